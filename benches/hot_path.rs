@@ -0,0 +1,151 @@
+use std::{
+    hint::black_box, net::UdpSocket as StdUdpSocket, path::PathBuf, sync::Arc, time::Duration,
+};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use mini_dns_relay::{
+    bench::{self, Compression, Interner, Message, RData, ResourceRecord},
+    Config,
+};
+use tokio::{net::UdpSocket, runtime::Runtime};
+
+fn free_addr() -> String {
+    let sock = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.local_addr().unwrap().to_string()
+}
+
+fn hosts_file(contents: &str) -> PathBuf {
+    let path =
+        std::env::temp_dir().join(format!("mini-dns-relay-bench-{}.txt", std::process::id()));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in name.split('.') {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf.extend_from_slice(&qtype.to_be_bytes());
+    buf.extend_from_slice(&1u16.to_be_bytes());
+    buf
+}
+
+fn parsing(c: &mut Criterion) {
+    let name = "some.fairly.long.name.example.lan";
+    let mut buf = query(0x1234, name, 1);
+    let len = buf.len();
+    let msg = Message::new(&mut buf, len);
+
+    c.bench_function("question_entries", |b| {
+        b.iter(|| msg.question.entries(black_box(1)))
+    });
+
+    let interner = Interner::new(1024);
+    c.bench_function("question_entries_interned", |b| {
+        b.iter(|| msg.question.interned_entries(black_box(1), &interner))
+    });
+
+    // the qname alone: a String, as qnames were before they were shared, an
+    // Arc<str>, and one shared through the interner
+    let mut group = c.benchmark_group("qname");
+    group.bench_function("string", |b| b.iter(|| String::from(black_box(name))));
+    group.bench_function("arc", |b| b.iter(|| Arc::<str>::from(black_box(name))));
+    group.bench_function("interned", |b| b.iter(|| interner.intern(black_box(name))));
+    group.finish();
+
+    let queries = msg.question.entries(1).unwrap();
+    c.bench_function("name_compressed", |b| {
+        b.iter(|| bench::name_compressed(black_box(&queries[0])))
+    });
+}
+
+fn answering(c: &mut Criterion) {
+    let query = query(0x1234, "host.example.lan", 1);
+
+    c.bench_function("add_entries", |b| {
+        b.iter(|| {
+            let mut buf = [0u8; 512];
+            buf[..query.len()].copy_from_slice(&query);
+            let mut msg = Message::new(&mut buf, query.len());
+            let records = (0..4)
+                .map(|i| ResourceRecord {
+                    name: 0xc00c,
+                    rtype: 1,
+                    rclass: 1,
+                    ttl: 600,
+                    rdlength: 4,
+                    rdata: RData::V4([10, 0, 0, i]),
+                })
+                .collect();
+            msg.answer
+                .add_entries(black_box(records), Compression::Owners)
+        })
+    });
+
+    let contents: String = (0..1000)
+        .map(|i| format!("10.0.{}.{} host{}.example.lan\n", i / 256, i % 256, i))
+        .collect();
+    let path = hosts_file(&contents);
+    let hosts = bench::load_hosts(path.to_str().unwrap(), true).unwrap();
+    std::fs::remove_file(path).unwrap();
+
+    let mut buf = query.clone();
+    let len = buf.len();
+    let queries = Message::new(&mut buf, len).question.entries(1).unwrap();
+    c.bench_function("process", |b| {
+        b.iter(|| bench::process(black_box(&queries[0]), &hosts))
+    });
+}
+
+/// Queries forwarded through a running relay to an upstream that answers by
+/// echoing them, one at a time.
+fn forwarding(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let upstream = rt.block_on(UdpSocket::bind("127.0.0.1:0")).unwrap();
+    let local_addr = free_addr();
+    let config = Config {
+        local_addr: local_addr.clone(),
+        remote_addr: "127.0.0.1:0".to_owned(),
+        upstream_addr: upstream.local_addr().unwrap().to_string(),
+        hosts_path: hosts_file("").to_string_lossy().into_owned(),
+        ..Config::default()
+    };
+
+    rt.spawn(async move {
+        let mut buf = [0u8; 512];
+        loop {
+            let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+            buf[2] |= 0b1000_0000;
+            upstream.send_to(&buf[..len], from).await.unwrap();
+        }
+    });
+    rt.spawn(mini_dns_relay::run(config));
+    std::thread::sleep(Duration::from_millis(100));
+
+    let client = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    client.connect(&local_addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+
+    let mut group = c.benchmark_group("forward");
+    group.throughput(Throughput::Elements(1));
+    let mut id = 0u16;
+    let mut buf = [0u8; 512];
+    group.bench_function("round_trip", |b| {
+        b.iter(|| {
+            id = id.wrapping_add(1);
+            client.send(&query(id, "remote.example", 1)).unwrap();
+            client.recv(&mut buf).unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parsing, answering, forwarding);
+criterion_main!(benches);
@@ -0,0 +1,52 @@
+//! Names that may be resolved at all when the relay runs in allowlist mode.
+
+use std::{collections::HashSet, fs};
+
+use tracing::info;
+
+/// One name per line; `*.example.com` allows every name under example.com,
+/// but not example.com itself. Empty lines and `#` comments are skipped.
+#[derive(Debug, Default)]
+pub struct Allowlist {
+    names: HashSet<String>,
+    // with a leading dot, so that a plain ends_with matches whole labels
+    suffixes: Vec<String>,
+}
+
+impl Allowlist {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read allowlist {}: {}", path, e))?;
+
+        let mut allowlist = Self::default();
+        for line in contents.lines() {
+            let entry = line.split('#').next().unwrap_or_default().trim();
+            if entry.is_empty() {
+                continue;
+            }
+            let entry = entry.trim_end_matches('.').to_ascii_lowercase();
+            match entry.strip_prefix('*') {
+                Some(suffix) if suffix.starts_with('.') => {
+                    allowlist.suffixes.push(suffix.to_owned())
+                }
+                Some(_) => anyhow::bail!("invalid allowlist entry: {}", line.trim()),
+                None => {
+                    allowlist.names.insert(entry);
+                }
+            }
+        }
+
+        info!(
+            "allowing {} names and {} suffixes from {}",
+            allowlist.names.len(),
+            allowlist.suffixes.len(),
+            path
+        );
+        Ok(allowlist)
+    }
+
+    pub fn allows(&self, qname: &str) -> bool {
+        let qname = qname.trim_end_matches('.').to_ascii_lowercase();
+        self.names.contains(&qname) || self.suffixes.iter().any(|suffix| qname.ends_with(suffix))
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use serde::Serialize;
+use tracing::{error, info};
+
+use crate::{
+    hosts::{self, load_hosts, Refresher, Remote, SharedHosts, DNAME},
+    packet::RData,
+    Hosts,
+};
+
+/// Where locally answered names come from.
+pub trait Backend: Send + Sync {
+    /// The address `name` maps to, like an `<ip> <name>` hosts file line.
+    fn addr(&self, name: &str) -> Option<IpAddr>;
+    /// The name `addr` maps back to, for PTR queries.
+    fn name(&self, addr: IpAddr) -> Option<String>;
+    /// Typed records of `name`, with their TTLs.
+    fn records(&self, name: &str, rtype: u16) -> Vec<(u32, RData)>;
+    /// Whether anything at all is defined for `name`.
+    fn contains(&self, name: &str) -> bool;
+    /// Number of entries, for reporting.
+    fn entries(&self) -> usize;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    File,
+    Sqlite,
+}
+
+impl FromStr for BackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "file" => Ok(BackendKind::File),
+            "sqlite" => Ok(BackendKind::Sqlite),
+            _ => Err(anyhow::anyhow!("unknown hosts backend: {}", s)),
+        }
+    }
+}
+
+/// Opens `path`, which for the file backend may also be an `http(s)://` URL.
+/// Hosts from a URL come with what it takes to refresh them. The records of
+/// `json_path` are added to those of a local hosts file. With `dedup`,
+/// records repeating one already loaded are left out.
+pub async fn open(
+    kind: BackendKind,
+    path: &str,
+    cache_path: Option<&str>,
+    json_path: Option<&str>,
+    dedup: bool,
+) -> anyhow::Result<(Arc<dyn Backend>, Option<Refresher>)> {
+    anyhow::ensure!(
+        json_path.is_none() || (kind == BackendKind::File && !hosts::is_url(path)),
+        "json records can only be added to a local hosts file"
+    );
+    match kind {
+        BackendKind::File if hosts::is_url(path) => {
+            let mut remote = Remote::new(path, cache_path, dedup)?;
+            let hosts: SharedHosts = Arc::new(RwLock::new(remote.load().await?));
+            tracing::debug!("hosts: {:?}", hosts);
+            Ok((Arc::new(hosts.clone()), Some(Refresher { remote, hosts })))
+        }
+        BackendKind::File => {
+            let mut hosts = load_hosts(path, dedup)?;
+            if let Some(json_path) = json_path {
+                hosts.load_json(json_path, None)?;
+            }
+            tracing::debug!("hosts: {:?}", hosts);
+            Ok((Arc::new(hosts), None))
+        }
+        BackendKind::Sqlite => {
+            anyhow::ensure!(
+                !hosts::is_url(path),
+                "a sqlite database cannot be fetched from a url"
+            );
+            Ok((Arc::new(SqliteBackend::open(path)?), None))
+        }
+    }
+}
+
+impl Backend for Hosts {
+    fn addr(&self, name: &str) -> Option<IpAddr> {
+        Hosts::addr(self, name)
+    }
+
+    fn name(&self, addr: IpAddr) -> Option<String> {
+        Hosts::name(self, addr).map(str::to_owned)
+    }
+
+    fn records(&self, name: &str, rtype: u16) -> Vec<(u32, RData)> {
+        Hosts::records(self, name, rtype)
+            .map(|(ttl, rdata)| (ttl, rdata.clone()))
+            .collect()
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        Hosts::contains(self, name)
+    }
+
+    fn entries(&self) -> usize {
+        Hosts::entries(self)
+    }
+}
+
+impl Backend for SharedHosts {
+    fn addr(&self, name: &str) -> Option<IpAddr> {
+        self.read().unwrap().addr(name)
+    }
+
+    fn name(&self, addr: IpAddr) -> Option<String> {
+        Backend::name(&*self.read().unwrap(), addr)
+    }
+
+    fn records(&self, name: &str, rtype: u16) -> Vec<(u32, RData)> {
+        Backend::records(&*self.read().unwrap(), name, rtype)
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.read().unwrap().contains(name)
+    }
+
+    fn entries(&self) -> usize {
+        self.read().unwrap().entries()
+    }
+}
+
+/// Records kept in a SQLite table, looked up on every query so that changes
+/// made by other tools are served without a restart:
+///
+/// ```sql
+/// CREATE TABLE records (name TEXT, type INTEGER, rdata BLOB, ttl INTEGER);
+/// ```
+///
+/// A and AAAA rows hold the 4 or 16 address bytes; an A row of 0.0.0.0 blocks
+/// the name, as in the hosts file.
+pub struct SqliteBackend {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteBackend {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        // fail on startup rather than on the first query
+        conn.prepare("SELECT name, type, rdata, ttl FROM records")?;
+        info!("answering local names from sqlite database {}", path);
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn query(&self, name: &str, rtype: u16) -> rusqlite::Result<Vec<(u32, Vec<u8>)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(
+            "SELECT ttl, rdata FROM records WHERE name = ?1 COLLATE NOCASE AND type = ?2",
+        )?;
+        let rows = stmt.query_map((name, rtype), |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    fn exists(&self, name: &str) -> rusqlite::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare_cached("SELECT 1 FROM records WHERE name = ?1 COLLATE NOCASE LIMIT 1")?;
+        stmt.exists([name])
+    }
+
+    fn reverse(&self, addr: IpAddr) -> rusqlite::Result<Option<String>> {
+        let (rtype, rdata) = match addr {
+            IpAddr::V4(v4) => (1, v4.octets().to_vec()),
+            IpAddr::V6(v6) => (28, v6.octets().to_vec()),
+        };
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare_cached("SELECT name FROM records WHERE type = ?1 AND rdata = ?2 LIMIT 1")?;
+        stmt.query_row((rtype, rdata), |row| row.get(0)).optional()
+    }
+
+    fn count(&self) -> rusqlite::Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT COUNT(*) FROM records", [], |row| row.get(0))
+    }
+
+    fn rows(&self, name: &str, rtype: u16) -> Vec<(u32, Vec<u8>)> {
+        self.query(name, rtype).unwrap_or_else(|e| {
+            error!("sqlite lookup of {} type {} failed: {}", name, rtype, e);
+            Vec::new()
+        })
+    }
+}
+
+impl Backend for SqliteBackend {
+    fn addr(&self, name: &str) -> Option<IpAddr> {
+        let v4 = self.rows(name, 1).into_iter().find_map(|(_, rdata)| {
+            <[u8; 4]>::try_from(rdata.as_slice())
+                .ok()
+                .map(|octets| IpAddr::V4(Ipv4Addr::from(octets)))
+        });
+
+        v4.or_else(|| {
+            self.rows(name, 28).into_iter().find_map(|(_, rdata)| {
+                <[u8; 16]>::try_from(rdata.as_slice())
+                    .ok()
+                    .map(|octets| IpAddr::V6(Ipv6Addr::from(octets)))
+            })
+        })
+    }
+
+    fn name(&self, addr: IpAddr) -> Option<String> {
+        self.reverse(addr).unwrap_or_else(|e| {
+            error!("sqlite reverse lookup of {} failed: {}", addr, e);
+            None
+        })
+    }
+
+    fn records(&self, name: &str, rtype: u16) -> Vec<(u32, RData)> {
+        self.rows(name, rtype)
+            .into_iter()
+            .filter_map(|(ttl, rdata)| {
+                let rdata = match rtype {
+                    1 => RData::V4(rdata.try_into().ok()?),
+                    28 => RData::V6(rdata.try_into().ok()?),
+                    DNAME => RData::Dname(rdata),
+                    _ if rdata.len() <= u16::MAX as usize => RData::Opaque(rdata),
+                    _ => return None,
+                };
+                Some((ttl, rdata))
+            })
+            .collect()
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.exists(name).unwrap_or_else(|e| {
+            error!("sqlite lookup of {} failed: {}", name, e);
+            false
+        })
+    }
+
+    fn entries(&self) -> usize {
+        self.count().unwrap_or_else(|e| {
+            error!("sqlite count of records failed: {}", e);
+            0
+        })
+    }
+}
@@ -0,0 +1,25 @@
+//! Internals reached by the benchmarks under `benches/`. Not part of the API.
+
+use crate::{control::Overrides, views::Views, Hosts};
+
+pub use crate::{
+    hosts::load_hosts,
+    intern::Interner,
+    packet::{Compression, Message, QuestionEntry, RData, ResourceRecord},
+};
+
+pub fn process(qe: &QuestionEntry, hosts: &Hosts) -> anyhow::Result<Vec<ResourceRecord>> {
+    Ok(crate::process(
+        qe,
+        None,
+        hosts,
+        &Overrides::default(),
+        &Views::default(),
+        &[],
+        &[],
+    )?)
+}
+
+pub fn name_compressed(qe: &QuestionEntry) -> u16 {
+    crate::name_compressed(qe)
+}
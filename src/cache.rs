@@ -0,0 +1,398 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::packet::{self, QuestionEntry, OPT};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CacheKey {
+    pub qname: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+impl From<&QuestionEntry> for CacheKey {
+    fn from(qe: &QuestionEntry) -> Self {
+        Self {
+            qname: qe.qname.to_ascii_lowercase(),
+            qtype: qe.qtype,
+            qclass: qe.qclass,
+        }
+    }
+}
+
+// absolute times, so that entries stay meaningful in a snapshot
+#[derive(Serialize, Deserialize)]
+pub struct CachedResponse {
+    bytes: Vec<u8>,
+    inserted: SystemTime,
+    expires: SystemTime,
+}
+
+/// Upstream responses keyed by their question, kept for the smallest TTL
+/// found among their records. Past `capacity` entries, the least recently
+/// used one is evicted.
+pub struct Cache {
+    capacity: usize,
+    entries: Mutex<Lru>,
+    evictions: AtomicU64,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: Mutex::default(),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of entries, expired ones not yet dropped included.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().slots.len()
+    }
+
+    /// Bytes taken by the cached responses and the names they are cached
+    /// under, a rough measure of the memory used.
+    pub fn bytes(&self) -> usize {
+        self.entries.lock().unwrap().bytes
+    }
+
+    /// Number of entries evicted to make room for new ones.
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    /// Returns a copy of the cached response with its TTLs counted down by
+    /// the time spent in the cache. The id is left for the caller to fix.
+    pub fn get(&self, key: &CacheKey) -> Option<Vec<u8>> {
+        let now = SystemTime::now();
+        let (mut bytes, elapsed) = {
+            let mut entries = self.entries.lock().unwrap();
+            let entry = entries.get(key)?;
+            if entry.expires <= now {
+                entries.remove(key);
+                return None;
+            }
+            let elapsed = now.duration_since(entry.inserted).unwrap_or_default();
+            (entry.bytes.clone(), elapsed.as_secs() as u32)
+        };
+
+        for rr in packet::records(&bytes)?.iter().filter(|rr| rr.rtype != OPT) {
+            let i = rr.ttl_offset();
+            bytes[i..i + 4].copy_from_slice(&rr.ttl.saturating_sub(elapsed).to_be_bytes());
+        }
+
+        Some(bytes)
+    }
+
+    /// Stores a response, returning the TTL it is cached for, or `None` if it
+    /// cannot be cached (malformed or without any TTL to go by).
+    pub fn insert(&self, key: CacheKey, bytes: &[u8]) -> Option<u32> {
+        let ttl = packet::records(bytes)?
+            .iter()
+            .filter(|rr| rr.rtype != OPT)
+            .map(|rr| rr.ttl)
+            .min()
+            .filter(|ttl| *ttl > 0)?;
+
+        let now = SystemTime::now();
+        self.store(
+            &mut self.entries.lock().unwrap(),
+            key,
+            CachedResponse {
+                bytes: bytes.to_vec(),
+                inserted: now,
+                expires: now + Duration::from_secs(ttl as u64),
+            },
+        );
+
+        Some(ttl)
+    }
+
+    fn store(&self, entries: &mut Lru, key: CacheKey, entry: CachedResponse) {
+        if entries.insert(key, entry, self.capacity) {
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Writes all live entries to `path`, returning how many were written.
+    pub fn save(&self, path: &str) -> anyhow::Result<usize> {
+        let now = SystemTime::now();
+        let entries = self.entries.lock().unwrap();
+        let live: Vec<(&CacheKey, &CachedResponse)> = entries
+            .slots
+            .iter()
+            .filter(|slot| slot.entry.expires > now)
+            .map(|slot| (&slot.key, &slot.entry))
+            .collect();
+
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        bincode::serialize_into(file, &live)?;
+
+        Ok(live.len())
+    }
+
+    /// Loads the entries of a snapshot written by [`Cache::save`], skipping
+    /// those that expired in the meantime. Returns how many were loaded.
+    pub fn load(&self, path: &str) -> anyhow::Result<usize> {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let snapshot: Vec<(CacheKey, CachedResponse)> = bincode::deserialize_from(file)?;
+
+        let now = SystemTime::now();
+        let mut entries = self.entries.lock().unwrap();
+        let mut loaded = 0;
+        for (key, entry) in snapshot {
+            if entry.expires > now {
+                self.store(&mut entries, key, entry);
+                loaded += 1;
+            }
+        }
+
+        Ok(loaded)
+    }
+}
+
+/// The entries in a list from the most to the least recently used, linked
+/// through their indices in `slots` so that moving one to the front and
+/// evicting the last are both O(1).
+#[derive(Default)]
+struct Lru {
+    index: HashMap<CacheKey, usize>,
+    slots: Vec<Slot>,
+    head: Option<usize>,
+    tail: Option<usize>,
+    // sum of the sizes of the slots
+    bytes: usize,
+}
+
+struct Slot {
+    key: CacheKey,
+    entry: CachedResponse,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+impl Slot {
+    fn size(&self) -> usize {
+        self.key.qname.len() + self.entry.bytes.len()
+    }
+}
+
+impl Lru {
+    fn get(&mut self, key: &CacheKey) -> Option<&CachedResponse> {
+        let i = *self.index.get(key)?;
+        self.unlink(i);
+        self.push_front(i);
+        Some(&self.slots[i].entry)
+    }
+
+    /// Returns whether the least recently used entry was evicted for it.
+    fn insert(&mut self, key: CacheKey, entry: CachedResponse, capacity: usize) -> bool {
+        if let Some(&i) = self.index.get(&key) {
+            self.bytes = self.bytes - self.slots[i].entry.bytes.len() + entry.bytes.len();
+            self.slots[i].entry = entry;
+            self.unlink(i);
+            self.push_front(i);
+            return false;
+        }
+
+        let evicted = match self.tail {
+            Some(last) if self.slots.len() >= capacity => {
+                self.remove_at(last);
+                true
+            }
+            _ => false,
+        };
+        let i = self.slots.len();
+        self.index.insert(key.clone(), i);
+        let slot = Slot {
+            key,
+            entry,
+            prev: None,
+            next: None,
+        };
+        self.bytes += slot.size();
+        self.slots.push(slot);
+        self.push_front(i);
+        evicted
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(&i) = self.index.get(key) {
+            self.remove_at(i);
+        }
+    }
+
+    fn remove_at(&mut self, i: usize) {
+        self.unlink(i);
+        let slot = self.slots.swap_remove(i);
+        self.index.remove(&slot.key);
+        self.bytes -= slot.size();
+
+        // the last slot took the place of the removed one
+        if i < self.slots.len() {
+            let (prev, next) = (self.slots[i].prev, self.slots[i].next);
+            match prev {
+                Some(prev) => self.slots[prev].next = Some(i),
+                None => self.head = Some(i),
+            }
+            match next {
+                Some(next) => self.slots[next].prev = Some(i),
+                None => self.tail = Some(i),
+            }
+            if let Some(index) = self.index.get_mut(&self.slots[i].key) {
+                *index = i;
+            }
+        }
+    }
+
+    fn unlink(&mut self, i: usize) {
+        let (prev, next) = (self.slots[i].prev, self.slots[i].next);
+        match prev {
+            Some(prev) => self.slots[prev].next = next,
+            None => self.head = next,
+        }
+        match next {
+            Some(next) => self.slots[next].prev = prev,
+            None => self.tail = prev,
+        }
+    }
+
+    fn push_front(&mut self, i: usize) {
+        self.slots[i].prev = None;
+        self.slots[i].next = self.head;
+        match self.head {
+            Some(head) => self.slots[head].prev = Some(i),
+            None => self.tail = Some(i),
+        }
+        self.head = Some(i);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> CacheKey {
+        CacheKey {
+            qname: name.to_owned(),
+            qtype: 1,
+            qclass: 1,
+        }
+    }
+
+    fn entry(len: usize) -> CachedResponse {
+        let now = SystemTime::now();
+        CachedResponse {
+            bytes: vec![0; len],
+            inserted: now,
+            expires: now + Duration::from_secs(60),
+        }
+    }
+
+    /// The names from the most to the least recently used, checking that the
+    /// links, the index and the byte count agree with each other.
+    fn order(lru: &Lru) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut prev = None;
+        let mut at = lru.head;
+        while let Some(i) = at {
+            let slot = &lru.slots[i];
+            assert_eq!(slot.prev, prev, "back link of {}", slot.key.qname);
+            assert_eq!(lru.index[&slot.key], i, "index of {}", slot.key.qname);
+            names.push(slot.key.qname.clone());
+            prev = at;
+            at = slot.next;
+        }
+        assert_eq!(lru.tail, prev);
+        assert_eq!(names.len(), lru.slots.len());
+        assert_eq!(lru.index.len(), lru.slots.len());
+        assert_eq!(lru.bytes, lru.slots.iter().map(Slot::size).sum::<usize>());
+        names
+    }
+
+    fn filled(names: &[&str]) -> Lru {
+        let mut lru = Lru::default();
+        for name in names.iter().rev() {
+            assert!(!lru.insert(key(name), entry(10), usize::MAX));
+        }
+        lru
+    }
+
+    #[test]
+    fn the_least_recently_used_is_evicted_at_capacity() {
+        let mut lru = Lru::default();
+        assert!(!lru.insert(key("a"), entry(10), 2));
+        assert!(!lru.insert(key("b"), entry(10), 2));
+        assert!(lru.insert(key("c"), entry(10), 2));
+
+        assert_eq!(order(&lru), ["c", "b"]);
+        assert!(lru.get(&key("a")).is_none());
+    }
+
+    #[test]
+    fn get_makes_an_entry_the_most_recently_used() {
+        let mut lru = filled(&["c", "b", "a"]);
+
+        assert!(lru.get(&key("a")).is_some());
+        assert_eq!(order(&lru), ["a", "c", "b"]);
+        assert!(lru.get(&key("b")).is_some());
+        assert_eq!(order(&lru), ["b", "a", "c"]);
+
+        assert!(lru.insert(key("d"), entry(10), 3));
+        assert_eq!(order(&lru), ["d", "b", "a"]);
+    }
+
+    #[test]
+    fn reinserting_a_key_replaces_its_entry() {
+        let mut lru = filled(&["b", "a"]);
+
+        assert!(!lru.insert(key("a"), entry(50), 2));
+        assert_eq!(order(&lru), ["a", "b"]);
+        assert_eq!(lru.bytes, 1 + 50 + 1 + 10);
+        assert!(!lru.insert(key("a"), entry(5), 2));
+        assert_eq!(lru.bytes, 1 + 5 + 1 + 10);
+        assert_eq!(lru.get(&key("a")).unwrap().bytes.len(), 5);
+    }
+
+    #[test]
+    fn removing_keeps_the_list_linked() {
+        for (removed, left) in [
+            ("a", ["b", "c", "d"]),
+            ("d", ["a", "b", "c"]),
+            ("b", ["a", "c", "d"]),
+            ("c", ["a", "b", "d"]),
+        ] {
+            let mut lru = filled(&["a", "b", "c", "d"]);
+            lru.remove(&key(removed));
+            assert_eq!(order(&lru), left, "after removing {}", removed);
+            assert!(lru.get(&key(removed)).is_none());
+        }
+
+        let mut lru = filled(&["a"]);
+        lru.remove(&key("a"));
+        assert!(order(&lru).is_empty());
+        assert_eq!((lru.head, lru.tail), (None, None));
+        lru.remove(&key("a"));
+    }
+
+    #[test]
+    fn evictions_are_counted() {
+        let cache = Cache::new(2);
+        for name in ["a", "b", "a", "c", "d"] {
+            cache.store(&mut cache.entries.lock().unwrap(), key(name), entry(10));
+        }
+
+        assert_eq!(cache.evictions(), 2);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.bytes(), 2 * 11);
+    }
+}
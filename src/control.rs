@@ -0,0 +1,167 @@
+//! Line-based control socket for changing the relay at runtime.
+//!
+//! Commands, one per line, each answered by `ok` or `error: <reason>` after
+//! any output lines:
+//!
+//! - `override <name> <ip>` answers `name` with `ip`, ahead of the hosts
+//! - `unoverride <name>` removes that override
+//! - `overrides` lists the overrides as `<name> <ip>` lines
+//! - `disable <upstream>` / `enable <upstream>` take an upstream out of and
+//!   back into service
+//! - `upstreams` lists the upstreams as `<addr> enabled|disabled healthy|unhealthy`
+//!   lines
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info, warn};
+
+use crate::health::Health;
+
+/// Addresses set through the control socket, by lowercased name.
+pub type Overrides = Arc<RwLock<HashMap<String, IpAddr>>>;
+
+/// The runtime state the control socket can change or show.
+#[derive(Clone)]
+pub struct Control {
+    pub overrides: Overrides,
+    // upstream address => enabled
+    upstreams: Arc<RwLock<HashMap<Arc<str>, bool>>>,
+    pub health: Arc<Health>,
+}
+
+pub async fn serve(addr: &str, control: &Control) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("control socket is listening on {}", addr);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        debug!("control connection from {}", peer);
+
+        let control = control.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &control).await {
+                warn!("control connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle(stream: TcpStream, control: &Control) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let reply = match control.execute(&line) {
+            Ok(output) => output.into_iter().chain(["ok".to_owned()]).collect(),
+            Err(e) => vec![format!("error: {}", e)],
+        };
+        for line in reply {
+            writer.write_all(format!("{}\n", line).as_bytes()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+impl Control {
+    pub fn new(upstreams: &[Arc<str>], disabled: &[String]) -> Self {
+        Self {
+            overrides: Overrides::default(),
+            health: Arc::default(),
+            upstreams: Arc::new(RwLock::new(
+                upstreams
+                    .iter()
+                    .map(|addr| (addr.clone(), !disabled.iter().any(|d| **d == **addr)))
+                    .collect(),
+            )),
+        }
+    }
+
+    pub fn is_enabled(&self, upstream: &str) -> bool {
+        self.upstreams
+            .read()
+            .unwrap()
+            .get(upstream)
+            .copied()
+            .unwrap_or(true)
+    }
+
+    fn set_enabled(&self, upstream: &str, enabled: bool) -> anyhow::Result<()> {
+        let mut upstreams = self.upstreams.write().unwrap();
+        let state = upstreams
+            .get_mut(upstream)
+            .ok_or(anyhow::anyhow!("unknown upstream: {}", upstream))?;
+        *state = enabled;
+        info!(
+            "upstream {} {}",
+            upstream,
+            if enabled { "enabled" } else { "disabled" }
+        );
+        Ok(())
+    }
+
+    fn execute(&self, line: &str) -> anyhow::Result<Vec<String>> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+
+        match parts.as_slice() {
+            ["override", name, ip] => {
+                let ip: IpAddr = ip.parse()?;
+                info!("overriding {} with {}", name, ip);
+                self.overrides
+                    .write()
+                    .unwrap()
+                    .insert(name.to_ascii_lowercase(), ip);
+                Ok(Vec::new())
+            }
+            ["unoverride", name] => {
+                self.overrides
+                    .write()
+                    .unwrap()
+                    .remove(&name.to_ascii_lowercase())
+                    .ok_or(anyhow::anyhow!("{} is not overridden", name))?;
+                info!("override of {} removed", name);
+                Ok(Vec::new())
+            }
+            ["overrides"] => {
+                let overrides = self.overrides.read().unwrap();
+                let mut list: Vec<String> = overrides
+                    .iter()
+                    .map(|(name, ip)| format!("{} {}", name, ip))
+                    .collect();
+                list.sort();
+                Ok(list)
+            }
+            ["disable", upstream] => self.set_enabled(upstream, false).map(|_| Vec::new()),
+            ["enable", upstream] => self.set_enabled(upstream, true).map(|_| Vec::new()),
+            ["upstreams"] => {
+                let upstreams = self.upstreams.read().unwrap();
+                let mut list: Vec<String> = upstreams
+                    .iter()
+                    .map(|(addr, enabled)| {
+                        format!(
+                            "{} {} {}",
+                            addr,
+                            if *enabled { "enabled" } else { "disabled" },
+                            if self.health.is_healthy(addr) {
+                                "healthy"
+                            } else {
+                                "unhealthy"
+                            }
+                        )
+                    })
+                    .collect();
+                list.sort();
+                Ok(list)
+            }
+            _ => Err(anyhow::anyhow!("unknown command: {}", line.trim())),
+        }
+    }
+}
@@ -0,0 +1,365 @@
+//! DNS cookies (RFC 7873) on the relay-to-upstream leg.
+//!
+//! Every forwarded query carries the relay's client cookie, plus the server
+//! cookie once the upstream it goes to has handed one out. The client cookie
+//! is derived from a random secret and the upstream's address (RFC 7873
+//! 4.1), so that upstreams cannot tell they serve the same relay. A response
+//! echoing a different client cookie was not sent in reply to the relay and
+//! is dropped. The relay's cookie is taken out of responses before they are
+//! relayed, as it is not the client's, along with the OPT record carrying it
+//! if the client sent none.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::packet::{self, OPT};
+
+pub const COOKIE: u16 = 10;
+// UDP payload size advertised in the OPT record added to queries without one
+const UDP_PAYLOAD_SIZE: u16 = 512;
+
+pub enum Check {
+    Valid,
+    /// no cookie, although the upstream gave one before
+    Missing,
+    /// a cookie that is not ours
+    Mismatch,
+    /// no cookie, and none expected
+    Unsupported,
+}
+
+pub struct Cookies {
+    // the client cookie sent to an upstream is keyed with it
+    secret: [u8; 32],
+    // by upstream address, each upstream handing out its own
+    servers: Mutex<HashMap<Arc<str>, Vec<u8>>>,
+}
+
+impl Cookies {
+    pub fn new() -> Self {
+        Self {
+            secret: rand::random(),
+            servers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The client cookie for `upstream`: the first 8 bytes of
+    /// HMAC-SHA256(secret, address).
+    fn client(&self, upstream: &str) -> [u8; 8] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("any key size");
+        mac.update(upstream.as_bytes());
+        let mut client = [0; 8];
+        client.copy_from_slice(&mac.finalize().into_bytes()[..8]);
+        client
+    }
+
+    fn option(&self, upstream: &str) -> Vec<u8> {
+        let servers = self.servers.lock().unwrap();
+        let server = servers.get(upstream).map_or(&[][..], Vec::as_slice);
+        let mut option = Vec::with_capacity(4 + 8 + server.len());
+        option.extend_from_slice(&COOKIE.to_be_bytes());
+        option.extend_from_slice(&((8 + server.len()) as u16).to_be_bytes());
+        option.extend_from_slice(&self.client(upstream));
+        option.extend_from_slice(server);
+        option
+    }
+
+    /// Puts the relay's cookie for `upstream` into the query in `buf[..len]`,
+    /// replacing any the client sent, and returns the new length. The query
+    /// is left as it is if it is malformed or the cookie does not fit.
+    pub fn add_to_query(&self, upstream: &str, buf: &mut [u8], len: usize) -> usize {
+        match with_cookie(&buf[..len], Some(&self.option(upstream))) {
+            Some(query) => replace(buf, len, query),
+            None => len,
+        }
+    }
+
+    /// Checks the cookie of a response from `upstream`, remembering the
+    /// server cookie of a valid one.
+    pub fn check_response(&self, upstream: &Arc<str>, buf: &[u8]) -> Check {
+        let cookie = packet::opt_options(buf)
+            .unwrap_or_default()
+            .into_iter()
+            .find(|(code, _)| *code == COOKIE)
+            .map(|(_, data)| &buf[data]);
+
+        let mut servers = self.servers.lock().unwrap();
+        match cookie {
+            Some(cookie) if cookie.len() >= 16 && cookie[..8] == self.client(upstream) => {
+                servers.insert(upstream.clone(), cookie[8..].to_vec());
+                Check::Valid
+            }
+            Some(_) => Check::Mismatch,
+            None if servers.contains_key(upstream) => Check::Missing,
+            None => Check::Unsupported,
+        }
+    }
+}
+
+/// Takes the relay's cookie out of the response in `buf[..len]` and returns
+/// the new length, so that a client sending a cookie of its own does not
+/// find the relay's instead and discard the response (RFC 7873 5.3). Unless
+/// the client sent an OPT record, `edns`, the whole OPT record goes, as a
+/// response may only carry one then (RFC 6891 7).
+pub fn remove(buf: &mut [u8], len: usize, edns: bool) -> usize {
+    let response = match edns {
+        true => with_cookie(&buf[..len], None),
+        false => without_opt(&buf[..len]),
+    };
+    match response {
+        Some(response) => replace(buf, len, response),
+        None => len,
+    }
+}
+
+/// The message without its OPT record, `None` if it is malformed or has
+/// none.
+fn without_opt(buf: &[u8]) -> Option<Vec<u8>> {
+    let opt = packet::records(buf)?
+        .into_iter()
+        .find(|rr| rr.rtype == OPT)?;
+    // the owner of an OPT record is the root, a single byte
+    let start = opt.rdata.checked_sub(11).filter(|&start| buf[start] == 0)?;
+
+    let mut message = buf[..start].to_vec();
+    message.extend_from_slice(&buf[opt.end..]);
+    let arcount = u16::from_be_bytes([message[10], message[11]]) - 1;
+    message[10..12].copy_from_slice(&arcount.to_be_bytes());
+    Some(message)
+}
+
+/// The message with its COOKIE options replaced by `cookie`, an OPT record
+/// added for it if there is none. `None` if the message is malformed, or
+/// there is nothing to take out.
+fn with_cookie(buf: &[u8], cookie: Option<&[u8]>) -> Option<Vec<u8>> {
+    let records = packet::records(buf)?;
+
+    match records.iter().find(|rr| rr.rtype == OPT) {
+        Some(opt) => {
+            let options = packet::opt_options(buf)?;
+            if cookie.is_none() && options.iter().all(|(code, _)| *code != COOKIE) {
+                return None;
+            }
+            let mut rdata: Vec<u8> = options
+                .into_iter()
+                .filter(|(code, _)| *code != COOKIE)
+                .flat_map(|(_, data)| buf[data.start - 4..data.end].to_vec())
+                .collect();
+            rdata.extend_from_slice(cookie.unwrap_or_default());
+
+            let mut message = buf[..opt.rdata - 2].to_vec();
+            message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            message.extend_from_slice(&rdata);
+            message.extend_from_slice(&buf[opt.end..]);
+            Some(message)
+        }
+        None => {
+            let cookie = cookie?;
+            let mut message = buf.to_vec();
+            let arcount = u16::from_be_bytes([message[10], message[11]]) + 1;
+            message[10..12].copy_from_slice(&arcount.to_be_bytes());
+            // root name, type, payload size, extended rcode and flags
+            message.push(0);
+            message.extend_from_slice(&OPT.to_be_bytes());
+            message.extend_from_slice(&UDP_PAYLOAD_SIZE.to_be_bytes());
+            message.extend_from_slice(&[0, 0, 0, 0]);
+            message.extend_from_slice(&(cookie.len() as u16).to_be_bytes());
+            message.extend_from_slice(cookie);
+            Some(message)
+        }
+    }
+}
+
+/// Copies `message` over `buf[..len]` if it fits, returning the length of
+/// whichever is left in `buf`.
+fn replace(buf: &mut [u8], len: usize, mut message: Vec<u8>) -> usize {
+    if message.len() > buf.len() {
+        return len;
+    }
+    let new_len = message.len();
+    buf[..new_len].swap_with_slice(&mut message);
+    new_len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPSTREAM: &str = "10.0.0.1:53";
+
+    fn query(opt: Option<&[u8]>) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(&packet::encode_name("cookie.test").unwrap());
+        query.extend_from_slice(&[0, 1, 0, 1]);
+        if let Some(options) = opt {
+            query[11] = 1;
+            query.extend_from_slice(&packet::opt_record(1232));
+            let rdlength = query.len() - 2;
+            query[rdlength..].copy_from_slice(&(options.len() as u16).to_be_bytes());
+            query.extend_from_slice(options);
+        }
+        query
+    }
+
+    fn cookie_option(cookie: &[u8]) -> Vec<u8> {
+        let mut option = COOKIE.to_be_bytes().to_vec();
+        option.extend_from_slice(&(cookie.len() as u16).to_be_bytes());
+        option.extend_from_slice(cookie);
+        option
+    }
+
+    fn cookie_of(buf: &[u8]) -> Option<Vec<u8>> {
+        packet::opt_options(buf)?
+            .into_iter()
+            .find(|(code, _)| *code == COOKIE)
+            .map(|(_, data)| buf[data].to_vec())
+    }
+
+    fn added(cookies: &Cookies, upstream: &str, query: &[u8]) -> Vec<u8> {
+        let mut buf = [0u8; 512];
+        buf[..query.len()].copy_from_slice(query);
+        let len = cookies.add_to_query(upstream, &mut buf, query.len());
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn add_to_query_without_opt() {
+        let cookies = Cookies::new();
+        let plain = query(None);
+
+        let buf = added(&cookies, UPSTREAM, &plain);
+        assert_eq!(&buf[10..12], &[0, 1]);
+        assert_eq!(&buf[12..plain.len()], &plain[12..]);
+        assert_eq!(cookie_of(&buf), Some(cookies.client(UPSTREAM).to_vec()));
+    }
+
+    #[test]
+    fn add_to_query_replaces_the_clients_cookie() {
+        let cookies = Cookies::new();
+        // an EDE option first, kept as it is
+        let mut options = vec![0, 15, 0, 2, 0, 18];
+        options.extend_from_slice(&cookie_option(&[7; 8]));
+        let with_opt = query(Some(&options));
+
+        let buf = added(&cookies, UPSTREAM, &with_opt);
+        assert_eq!(&buf[10..12], &[0, 1]);
+        assert_eq!(cookie_of(&buf), Some(cookies.client(UPSTREAM).to_vec()));
+        let codes: Vec<u16> = packet::opt_options(&buf)
+            .unwrap()
+            .into_iter()
+            .map(|(code, _)| code)
+            .collect();
+        assert_eq!(codes, [15, COOKIE]);
+    }
+
+    #[test]
+    fn server_cookies_are_kept_per_upstream() {
+        let cookies = Cookies::new();
+        let upstream: Arc<str> = UPSTREAM.into();
+        let mut cookie = cookies.client(UPSTREAM).to_vec();
+        cookie.extend_from_slice(&[9; 8]);
+
+        let response = query(Some(&cookie_option(&cookie)));
+        assert!(matches!(
+            cookies.check_response(&upstream, &response),
+            Check::Valid
+        ));
+        assert_eq!(
+            cookie_of(&added(&cookies, UPSTREAM, &query(None))),
+            Some(cookie)
+        );
+        assert_eq!(
+            cookie_of(&added(&cookies, "10.0.0.2:53", &query(None))),
+            Some(cookies.client("10.0.0.2:53").to_vec())
+        );
+    }
+
+    #[test]
+    fn client_cookies_differ_per_upstream() {
+        let cookies = Cookies::new();
+
+        assert_eq!(cookies.client(UPSTREAM), cookies.client(UPSTREAM));
+        assert_ne!(cookies.client(UPSTREAM), cookies.client("10.0.0.2:53"));
+        assert_ne!(cookies.client(UPSTREAM), Cookies::new().client(UPSTREAM));
+    }
+
+    #[test]
+    fn check_response() {
+        let cookies = Cookies::new();
+        let upstream: Arc<str> = UPSTREAM.into();
+        let other: Arc<str> = "10.0.0.2:53".into();
+        let mut cookie = cookies.client(UPSTREAM).to_vec();
+        cookie.extend_from_slice(&[9; 8]);
+
+        assert!(matches!(
+            cookies.check_response(&upstream, &query(None)),
+            Check::Unsupported
+        ));
+        assert!(matches!(
+            cookies.check_response(&upstream, &query(Some(&cookie_option(&cookie)))),
+            Check::Valid
+        ));
+        assert!(matches!(
+            cookies.check_response(&upstream, &query(None)),
+            Check::Missing
+        ));
+        assert!(matches!(
+            cookies.check_response(&other, &query(None)),
+            Check::Unsupported
+        ));
+        let mut wrong = [1; 16];
+        wrong[..8].copy_from_slice(&[!cookies.client(UPSTREAM)[0]; 8]);
+        assert!(matches!(
+            cookies.check_response(&upstream, &query(Some(&cookie_option(&wrong)))),
+            Check::Mismatch
+        ));
+        // the cookie sent to another upstream
+        let mut theirs = cookies.client("10.0.0.2:53").to_vec();
+        theirs.extend_from_slice(&[9; 8]);
+        assert!(matches!(
+            cookies.check_response(&upstream, &query(Some(&cookie_option(&theirs)))),
+            Check::Mismatch
+        ));
+    }
+
+    #[test]
+    fn remove_takes_out_only_the_cookie() {
+        let mut options = cookie_option(&[7; 16]);
+        options.extend_from_slice(&[0, 15, 0, 2, 0, 18]);
+        let mut buf = query(Some(&options));
+        let len = buf.len();
+
+        let len = remove(&mut buf, len, true);
+        assert_eq!(cookie_of(&buf[..len]), None);
+        assert_eq!(packet::opt_options(&buf[..len]).unwrap().len(), 1);
+        assert_eq!(len, query(Some(&[0, 15, 0, 2, 0, 18])).len());
+
+        let mut plain = query(None);
+        let len = plain.len();
+        assert_eq!(remove(&mut plain, len, true), len);
+        assert_eq!(remove(&mut plain, len, false), len);
+    }
+
+    #[test]
+    fn remove_takes_out_the_opt_the_client_did_not_send() {
+        let mut buf = query(Some(&cookie_option(&[7; 16])));
+        // an answer after the question, the OPT record after it
+        let question_end = packet::question_end(&buf).unwrap();
+        let answer = [0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 1];
+        buf.splice(question_end..question_end, answer);
+        buf[7] = 1;
+        let len = buf.len();
+
+        let len = remove(&mut buf, len, false);
+        let mut expected = query(None);
+        expected.extend_from_slice(&answer);
+        expected[7] = 1;
+        assert_eq!(&buf[..len], expected.as_slice());
+        assert!(!packet::has_opt(&buf[..len]));
+    }
+}
@@ -0,0 +1,146 @@
+//! Policies for devices rather than addresses, which DHCP may hand out to
+//! another device tomorrow, e.g. a blocklist for a child's tablet.
+//!
+//! Clients are told apart by the MAC address the kernel's ARP table has for
+//! their IP, so this only works on Linux and for IPv4 clients on the same
+//! subnet as the relay; queries from any other client are not filtered. The
+//! table is read at most once every `ARP_TTL`, so a device that just joined
+//! may get through unfiltered for that long.
+
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tracing::{debug, info};
+
+use crate::allowlist::Allowlist;
+
+const ARP_TABLE: &str = "/proc/net/arp";
+const ARP_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceListKind {
+    /// the names on the list are blocked
+    Block,
+    /// only the names on the list resolve
+    Allow,
+}
+
+/// A `mac=block:path` or `mac=allow:path` policy from the config, the list
+/// in the allowlist format.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DevicePolicy {
+    pub mac: String,
+    pub kind: DeviceListKind,
+    pub path: String,
+}
+
+impl FromStr for DevicePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("expected mac=block:path or mac=allow:path, got {}", s);
+        // the mac has colons of its own
+        let (mac, list) = s.split_once('=').ok_or_else(invalid)?;
+        let (kind, path) = list.split_once(':').ok_or_else(invalid)?;
+        let kind = match kind {
+            "block" => DeviceListKind::Block,
+            "allow" => DeviceListKind::Allow,
+            _ => return Err(invalid()),
+        };
+        let mac = normalize(mac);
+        anyhow::ensure!(
+            mac.len() == 17 && mac.split(':').all(|octet| octet.len() == 2),
+            "invalid mac address in {}",
+            s
+        );
+
+        Ok(Self {
+            mac,
+            kind,
+            path: path.to_owned(),
+        })
+    }
+}
+
+pub struct Devices {
+    lists: HashMap<String, (DeviceListKind, Allowlist)>,
+    // ip => mac, and when the table was read
+    arp: Mutex<(HashMap<IpAddr, String>, Option<Instant>)>,
+}
+
+impl Devices {
+    pub fn load(policies: &[DevicePolicy]) -> anyhow::Result<Self> {
+        let mut lists = HashMap::new();
+        for policy in policies {
+            lists.insert(
+                policy.mac.clone(),
+                (policy.kind, Allowlist::load(&policy.path)?),
+            );
+        }
+        info!("filtering queries of {} device(s) by mac", lists.len());
+
+        Ok(Self {
+            lists,
+            arp: Mutex::new((HashMap::new(), None)),
+        })
+    }
+
+    /// The MAC address of the device at `client` if its policy keeps it from
+    /// resolving `qname`.
+    pub fn denies(&self, client: IpAddr, qname: &str) -> Option<String> {
+        let mac = self.mac(client)?;
+        let (kind, list) = self.lists.get(&mac)?;
+        let listed = list.allows(qname);
+        match kind {
+            DeviceListKind::Block => listed,
+            DeviceListKind::Allow => !listed,
+        }
+        .then_some(mac)
+    }
+
+    fn mac(&self, client: IpAddr) -> Option<String> {
+        let mut arp = self.arp.lock().unwrap();
+        if arp.1.is_none_or(|read| read.elapsed() >= ARP_TTL) {
+            arp.0 = match fs::read_to_string(ARP_TABLE) {
+                Ok(table) => parse_arp(&table),
+                Err(e) => {
+                    debug!("failed to read {}: {}", ARP_TABLE, e);
+                    HashMap::new()
+                }
+            };
+            arp.1 = Some(Instant::now());
+        }
+        arp.0.get(&client).cloned()
+    }
+}
+
+/// The complete entries of the ARP table, under a header line:
+/// `IP address  HW type  Flags  HW address  Mask  Device`.
+fn parse_arp(table: &str) -> HashMap<IpAddr, String> {
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let ip = fields.first()?.parse().ok()?;
+            // ATF_COM, the address is resolved
+            let flags = u32::from_str_radix(fields.get(2)?.trim_start_matches("0x"), 16).ok()?;
+            if flags & 0x2 == 0 {
+                return None;
+            }
+            Some((ip, normalize(fields.get(3)?)))
+        })
+        .collect()
+}
+
+fn normalize(mac: &str) -> String {
+    mac.trim().to_ascii_lowercase().replace('-', ":")
+}
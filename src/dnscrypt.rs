@@ -0,0 +1,509 @@
+//! DNSCrypt v2 client for the relay-to-upstream leg.
+//!
+//! See <https://dnscrypt.info/protocol> for the wire format. Only the UDP
+//! transport is implemented. The provider certificate is fetched on startup
+//! and again whenever the one in use expires.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::Engine;
+use crypto_box::{
+    aead::{generic_array::GenericArray, Aead, OsRng},
+    ChaChaBox, PublicKey, SalsaBox, SecretKey,
+};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use tokio::{net::UdpSocket, time::timeout};
+use tracing::{debug, info, warn};
+
+use crate::{packet, upstream::Resolver};
+
+const CERT_MAGIC: &[u8] = b"DNSC";
+const RESOLVER_MAGIC: &[u8] = b"r6fnvWj8";
+const MIN_QUERY_LEN: usize = 256;
+const PADDING_BLOCK: usize = 64;
+const CERT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+// client nonces of queries not answered yet, the oldest forgotten first
+const MAX_NONCES: usize = 4096;
+
+/// The parts of an `sdns://` DNSCrypt stamp the relay needs.
+#[derive(Debug, Clone)]
+pub struct Stamp {
+    pub addr: String,
+    pub provider_pk: [u8; 32],
+    pub provider_name: String,
+}
+
+impl Stamp {
+    pub fn parse(stamp: &str) -> anyhow::Result<Self> {
+        let encoded = stamp
+            .strip_prefix("sdns://")
+            .ok_or(anyhow::anyhow!("stamp must start with sdns://"))?;
+        let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(encoded)?;
+
+        anyhow::ensure!(bytes.first() == Some(&0x01), "not a DNSCrypt stamp");
+        // protocol (1) and props (8)
+        let mut rest = bytes.get(9..).ok_or(anyhow::anyhow!("stamp too short"))?;
+        let mut next = || -> anyhow::Result<&[u8]> {
+            let len = *rest.first().ok_or(anyhow::anyhow!("stamp too short"))? as usize;
+            let field = rest
+                .get(1..=len)
+                .ok_or(anyhow::anyhow!("stamp too short"))?;
+            rest = &rest[len + 1..];
+            Ok(field)
+        };
+
+        let addr = std::str::from_utf8(next()?)?.to_owned();
+        let provider_pk = next()?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("provider public key must be 32 bytes"))?;
+        let provider_name = std::str::from_utf8(next()?)?.to_owned();
+
+        // the port is optional and defaults to 443
+        let addr = if addr.ends_with(']') || !addr.contains(':') {
+            format!("{}:443", addr)
+        } else {
+            addr
+        };
+
+        Ok(Self {
+            addr,
+            provider_pk,
+            provider_name,
+        })
+    }
+}
+
+enum Cipher {
+    XSalsa20(SalsaBox),
+    XChaCha20(ChaChaBox),
+}
+
+/// Keys agreed with the resolver for one certificate.
+struct Session {
+    cipher: Cipher,
+    client_magic: [u8; 8],
+    expires: u32,
+}
+
+pub struct DnsCryptUpstream {
+    sock: UdpSocket,
+    stamp: Stamp,
+    addr: Arc<str>,
+    secret: SecretKey,
+    public: PublicKey,
+    session: Mutex<Arc<Session>>,
+    nonces: Mutex<VecDeque<[u8; 12]>>,
+}
+
+impl DnsCryptUpstream {
+    pub async fn connect(local: &str, stamp: &str) -> anyhow::Result<Self> {
+        let stamp = Stamp::parse(stamp)?;
+        let sock = UdpSocket::bind(local).await?;
+        info!("remote socket is listening on {}", local);
+
+        let secret = SecretKey::generate(&mut OsRng);
+        let public = secret.public_key();
+        let session = fetch_session(&stamp, &secret).await?;
+
+        Ok(Self {
+            sock,
+            addr: stamp.addr.as_str().into(),
+            stamp,
+            secret,
+            public,
+            session: Mutex::new(Arc::new(session)),
+            nonces: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    pub fn addr(&self) -> Arc<str> {
+        self.addr.clone()
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.sock
+    }
+
+    async fn current_session(&self) -> anyhow::Result<Arc<Session>> {
+        let session = self.session.lock().unwrap().clone();
+        if session.expires > unix_now() {
+            return Ok(session);
+        }
+
+        info!(
+            "certificate of {} expired, fetching a new one",
+            self.stamp.provider_name
+        );
+        let session = Arc::new(fetch_session(&self.stamp, &self.secret).await?);
+        *self.session.lock().unwrap() = session.clone();
+        Ok(session)
+    }
+}
+
+impl Resolver for DnsCryptUpstream {
+    async fn send(&self, buf: &[u8]) -> anyhow::Result<()> {
+        let session = self.current_session().await?;
+
+        let client_nonce: [u8; 12] = rand::random();
+        let mut nonce = [0u8; 24];
+        nonce[..12].copy_from_slice(&client_nonce);
+        let sealed = session.cipher.seal(&nonce, &pad(buf))?;
+        {
+            let mut nonces = self.nonces.lock().unwrap();
+            if nonces.len() == MAX_NONCES {
+                nonces.pop_front();
+            }
+            nonces.push_back(client_nonce);
+        }
+
+        let mut packet = Vec::with_capacity(52 + sealed.len());
+        packet.extend_from_slice(&session.client_magic);
+        packet.extend_from_slice(self.public.as_bytes());
+        packet.extend_from_slice(&client_nonce);
+        packet.extend_from_slice(&sealed);
+
+        self.sock.send_to(&packet, &*self.addr).await?;
+        Ok(())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let mut packet = [0u8; 4096];
+        loop {
+            let (len, _) = self.sock.recv_from(&mut packet).await?;
+            let packet = &packet[..len];
+
+            if len < 32 || &packet[..8] != RESOLVER_MAGIC {
+                debug!("dropping non-dnscrypt packet from upstream");
+                continue;
+            }
+            let nonce: [u8; 24] = packet[8..32].try_into().unwrap();
+            // the first half is ours, echoed back
+            let client_nonce: [u8; 12] = nonce[..12].try_into().unwrap();
+            if !self.nonces.lock().unwrap().contains(&client_nonce) {
+                warn!("dropping a response from {} to no query sent", self.addr);
+                continue;
+            }
+
+            let session = self.session.lock().unwrap().clone();
+            let Some(mut opened) = session.cipher.open(&nonce, &packet[32..]) else {
+                warn!("failed to decrypt a response from {}", self.addr);
+                continue;
+            };
+            let Some(end) = unpadded_len(&opened) else {
+                warn!("bad padding in a response from {}", self.addr);
+                continue;
+            };
+            // forgotten only now, so that a forged response cannot use it up
+            self.nonces
+                .lock()
+                .unwrap()
+                .retain(|sent| *sent != client_nonce);
+
+            if end > buf.len() {
+                debug!(
+                    "response of {} bytes from {} exceeds {}, truncating",
+                    end,
+                    self.addr,
+                    buf.len()
+                );
+            }
+            let len = packet::truncate(&mut opened[..end], buf.len());
+            buf[..len].copy_from_slice(&opened[..len]);
+            return Ok(len);
+        }
+    }
+}
+
+/// `buf` padded with 0x80 and zeros to a multiple of [`PADDING_BLOCK`],
+/// and to at least [`MIN_QUERY_LEN`] bytes.
+fn pad(buf: &[u8]) -> Vec<u8> {
+    let mut padded = buf.to_vec();
+    padded.push(0x80);
+    let target = padded
+        .len()
+        .next_multiple_of(PADDING_BLOCK)
+        .max(MIN_QUERY_LEN);
+    padded.resize(target, 0);
+    padded
+}
+
+/// The length of the message in `padded`, which must end with 0x80 and
+/// nothing but zeros after it.
+fn unpadded_len(padded: &[u8]) -> Option<usize> {
+    let end = padded.iter().rposition(|b| *b != 0)?;
+    (padded[end] == 0x80).then_some(end)
+}
+
+impl Cipher {
+    fn seal(&self, nonce: &[u8; 24], plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let nonce = GenericArray::from_slice(nonce);
+        match self {
+            Cipher::XSalsa20(b) => b.encrypt(nonce, plaintext),
+            Cipher::XChaCha20(b) => b.encrypt(nonce, plaintext),
+        }
+        .map_err(|_| anyhow::anyhow!("encryption failed"))
+    }
+
+    fn open(&self, nonce: &[u8; 24], ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let nonce = GenericArray::from_slice(nonce);
+        match self {
+            Cipher::XSalsa20(b) => b.decrypt(nonce, ciphertext),
+            Cipher::XChaCha20(b) => b.decrypt(nonce, ciphertext),
+        }
+        .ok()
+    }
+}
+
+/// Queries the provider's TXT certificates over plain DNS and sets up a
+/// session with the newest valid one.
+///
+/// A socket of its own is used so that the reply loop, which may be reading
+/// the upstream socket at the same time, cannot swallow the certificates.
+async fn fetch_session(stamp: &Stamp, secret: &SecretKey) -> anyhow::Result<Session> {
+    let sock = UdpSocket::bind(if stamp.addr.starts_with('[') {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    })
+    .await?;
+
+    let id = rand::random::<u16>();
+    sock.send_to(
+        &packet::build_query(id, &stamp.provider_name, 16),
+        &stamp.addr,
+    )
+    .await?;
+
+    let mut buf = [0u8; 4096];
+    let len = loop {
+        let (len, _) = timeout(CERT_TIMEOUT, sock.recv_from(&mut buf))
+            .await
+            .map_err(|_| anyhow::anyhow!("no certificate from {}", stamp.addr))??;
+        if len >= 2 && u16::from_be_bytes([buf[0], buf[1]]) == id {
+            break len;
+        }
+    };
+
+    let verifying_key = VerifyingKey::from_bytes(&stamp.provider_pk)?;
+    let now = unix_now();
+    let (serial, session) = best_cert(&buf[..len], &verifying_key, secret, now)?.ok_or(
+        anyhow::anyhow!("no usable certificate for {}", stamp.provider_name),
+    )?;
+    info!(
+        "using certificate #{} of {}, valid for {}s",
+        serial,
+        stamp.provider_name,
+        session.expires.saturating_sub(now)
+    );
+
+    Ok(session)
+}
+
+/// The valid certificate with the highest serial among the TXT records of
+/// `buf`, and the session set up with it.
+fn best_cert(
+    buf: &[u8],
+    verifying_key: &VerifyingKey,
+    secret: &SecretKey,
+    now: u32,
+) -> anyhow::Result<Option<(u32, Session)>> {
+    let mut best: Option<(u32, Session)> = None;
+
+    for rr in packet::records(buf)
+        .ok_or(anyhow::anyhow!("malformed certificate response"))?
+        .iter()
+        .filter(|rr| rr.rtype == 16)
+    {
+        // the certificate may be split over several character-strings
+        let mut cert = Vec::new();
+        let mut i = rr.rdata;
+        while i < rr.end {
+            let len = buf[i] as usize;
+            cert.extend_from_slice(&buf[(i + 1).min(rr.end)..(i + 1 + len).min(rr.end)]);
+            i += len + 1;
+        }
+
+        match parse_cert(&cert, verifying_key, secret, now) {
+            Ok((serial, session)) => {
+                if best.as_ref().is_none_or(|(s, _)| serial > *s) {
+                    best = Some((serial, session));
+                }
+            }
+            Err(e) => debug!("skipping certificate: {}", e),
+        }
+    }
+
+    Ok(best)
+}
+
+fn parse_cert(
+    cert: &[u8],
+    verifying_key: &VerifyingKey,
+    secret: &SecretKey,
+    now: u32,
+) -> anyhow::Result<(u32, Session)> {
+    anyhow::ensure!(cert.len() >= 124, "certificate too short");
+    anyhow::ensure!(&cert[..4] == CERT_MAGIC, "bad certificate magic");
+
+    let signature = Signature::from_bytes(cert[8..72].try_into().unwrap());
+    verifying_key
+        .verify(&cert[72..], &signature)
+        .map_err(|_| anyhow::anyhow!("bad certificate signature"))?;
+
+    let resolver_pk = PublicKey::from(<[u8; 32]>::try_from(&cert[72..104]).unwrap());
+    let serial = u32::from_be_bytes(cert[112..116].try_into().unwrap());
+    let starts = u32::from_be_bytes(cert[116..120].try_into().unwrap());
+    let expires = u32::from_be_bytes(cert[120..124].try_into().unwrap());
+    anyhow::ensure!(starts <= now && now < expires, "certificate not valid now");
+
+    let cipher = match u16::from_be_bytes([cert[4], cert[5]]) {
+        1 => Cipher::XSalsa20(SalsaBox::new(&resolver_pk, secret)),
+        2 => Cipher::XChaCha20(ChaChaBox::new(&resolver_pk, secret)),
+        v => anyhow::bail!("unsupported es-version {}", v),
+    };
+
+    Ok((
+        serial,
+        Session {
+            cipher,
+            client_magic: cert[104..112].try_into().unwrap(),
+            expires,
+        },
+    ))
+}
+
+fn unix_now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use super::*;
+
+    const NOW: u32 = 1_700_000_000;
+
+    fn stamp(addr: &str, pk: &[u8], name: &str) -> String {
+        let mut bytes = vec![0x01, 0, 0, 0, 0, 0, 0, 0, 0];
+        for field in [addr.as_bytes(), pk, name.as_bytes()] {
+            bytes.push(field.len() as u8);
+            bytes.extend_from_slice(field);
+        }
+        format!(
+            "sdns://{}",
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+        )
+    }
+
+    #[test]
+    fn stamps() {
+        let pk = [7u8; 32];
+
+        let parsed = Stamp::parse(&stamp("10.0.0.1:5443", &pk, "2.dnscrypt-cert.test")).unwrap();
+        assert_eq!(parsed.addr, "10.0.0.1:5443");
+        assert_eq!(parsed.provider_pk, pk);
+        assert_eq!(parsed.provider_name, "2.dnscrypt-cert.test");
+
+        // the port defaults to 443, for IPv6 addresses too
+        let parsed = Stamp::parse(&stamp("10.0.0.1", &pk, "p")).unwrap();
+        assert_eq!(parsed.addr, "10.0.0.1:443");
+        let parsed = Stamp::parse(&stamp("[::1]", &pk, "p")).unwrap();
+        assert_eq!(parsed.addr, "[::1]:443");
+
+        assert!(Stamp::parse("https://example.com").is_err());
+        assert!(Stamp::parse(&stamp("10.0.0.1", &pk[..16], "p")).is_err());
+        let doh = stamp("10.0.0.1", &pk, "p").replacen("sdns://AQ", "sdns://Ag", 1);
+        assert!(Stamp::parse(&doh).is_err());
+        assert!(Stamp::parse("sdns://AQAAAAAAAAAA").is_err());
+    }
+
+    fn cert(key: &SigningKey, es_version: u16, serial: u32, starts: u32, expires: u32) -> Vec<u8> {
+        // resolver public key, client magic, serial and validity period
+        let mut signed = vec![9u8; 32];
+        signed.extend_from_slice(b"magic!!!");
+        signed.extend_from_slice(&serial.to_be_bytes());
+        signed.extend_from_slice(&starts.to_be_bytes());
+        signed.extend_from_slice(&expires.to_be_bytes());
+
+        let mut cert = CERT_MAGIC.to_vec();
+        cert.extend_from_slice(&es_version.to_be_bytes());
+        cert.extend_from_slice(&[0, 0]);
+        cert.extend_from_slice(&key.sign(&signed).to_bytes());
+        cert.extend_from_slice(&signed);
+        cert
+    }
+
+    /// A response with a TXT record per certificate, each split over
+    /// character-strings of at most 100 bytes.
+    fn response(certs: &[Vec<u8>]) -> Vec<u8> {
+        let mut buf = packet::build_query(1, "2.dnscrypt-cert.test", 16);
+        buf[2] |= 0b1000_0000;
+        buf[6..8].copy_from_slice(&(certs.len() as u16).to_be_bytes());
+        for cert in certs {
+            let rdata: Vec<u8> = cert
+                .chunks(100)
+                .flat_map(|chunk| std::iter::once(chunk.len() as u8).chain(chunk.iter().copied()))
+                .collect();
+            buf.extend_from_slice(&[0xc0, 0x0c, 0, 16, 0, 1, 0, 0, 0, 60]);
+            buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&rdata);
+        }
+        buf
+    }
+
+    #[test]
+    fn the_newest_valid_certificate_is_used() {
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let other = SigningKey::from_bytes(&[4; 32]);
+        let secret = SecretKey::from([5; 32]);
+        let certs = [
+            cert(&key, 1, 1, NOW - 10, NOW + 10),
+            cert(&key, 2, 2, NOW - 10, NOW + 20),
+            // newer, but expired, not yet valid, unsigned or of an unknown version
+            cert(&key, 2, 3, NOW - 20, NOW - 10),
+            cert(&key, 2, 4, NOW + 10, NOW + 20),
+            cert(&other, 2, 5, NOW - 10, NOW + 10),
+            cert(&key, 3, 6, NOW - 10, NOW + 10),
+        ];
+
+        let (serial, session) = best_cert(&response(&certs), &key.verifying_key(), &secret, NOW)
+            .unwrap()
+            .unwrap();
+        assert_eq!(serial, 2);
+        assert_eq!(session.expires, NOW + 20);
+        assert_eq!(&session.client_magic, b"magic!!!");
+        assert!(matches!(session.cipher, Cipher::XChaCha20(_)));
+
+        assert!(
+            best_cert(&response(&certs[2..]), &key.verifying_key(), &secret, NOW)
+                .unwrap()
+                .is_none()
+        );
+        assert!(best_cert(&[0; 5], &key.verifying_key(), &secret, NOW).is_err());
+    }
+
+    #[test]
+    fn padding() {
+        for len in [0, 1, 100, 255, 256, 300] {
+            let msg = vec![0xab; len];
+            let padded = pad(&msg);
+            assert_eq!(padded.len() % PADDING_BLOCK, 0);
+            assert!(padded.len() >= MIN_QUERY_LEN && padded.len() > len);
+            assert_eq!(unpadded_len(&padded), Some(len));
+        }
+
+        // the marker is the last byte that is not zero
+        assert_eq!(unpadded_len(&[1, 0x80, 0x80, 0, 0]), Some(2));
+        assert_eq!(unpadded_len(&[1, 0x80, 2, 0, 0]), None);
+        assert_eq!(unpadded_len(&[1, 2, 0, 0]), None);
+        assert_eq!(unpadded_len(&[0, 0]), None);
+    }
+}
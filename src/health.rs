@@ -0,0 +1,172 @@
+//! Active health checking of the upstream.
+//!
+//! A canary query is sent every interval through the regular forwarding
+//! path, so it exercises the same socket or connections client queries use.
+//! After enough consecutive failures the upstream is considered down and
+//! `forward` stops sending it client queries until a canary succeeds again.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::oneshot;
+use tracing::{debug, info, warn};
+
+use crate::{
+    allocate_id, packet,
+    upstream::{Resolver, Upstream},
+    Config, MsgMap, Pending, CANARY_NAME, CANARY_TIMEOUT,
+};
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct State {
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+}
+
+/// Health of each upstream by address. Upstreams never checked are healthy.
+#[derive(Default)]
+pub struct Health {
+    states: RwLock<HashMap<Arc<str>, State>>,
+}
+
+impl Health {
+    pub fn is_healthy(&self, upstream: &str) -> bool {
+        self.states
+            .read()
+            .unwrap()
+            .get(upstream)
+            .is_none_or(|state| state.healthy)
+    }
+
+    /// Whether some upstream is healthy, or none was checked yet.
+    pub fn any_healthy(&self) -> bool {
+        let states = self.states.read().unwrap();
+        states.is_empty() || states.values().any(|state| state.healthy)
+    }
+
+    pub fn states(&self) -> Vec<(Arc<str>, State)> {
+        let mut states: Vec<_> = self
+            .states
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(addr, state)| (addr.clone(), *state))
+            .collect();
+        states.sort_by(|a, b| a.0.cmp(&b.0));
+        states
+    }
+
+    fn record(&self, upstream: &Arc<str>, ok: bool, threshold: u32) {
+        let mut states = self.states.write().unwrap();
+        let state = states.entry(upstream.clone()).or_insert(State {
+            healthy: true,
+            consecutive_failures: 0,
+        });
+
+        if ok {
+            if !state.healthy {
+                info!(
+                    "upstream {} passed its health check, back in service",
+                    upstream
+                );
+            }
+            *state = State {
+                healthy: true,
+                consecutive_failures: 0,
+            };
+        } else {
+            state.consecutive_failures += 1;
+            if state.healthy && state.consecutive_failures >= threshold {
+                warn!(
+                    "upstream {} failed {} health checks in a row, taking it out of service",
+                    upstream, state.consecutive_failures
+                );
+                state.healthy = false;
+            }
+        }
+    }
+}
+
+pub async fn check_loop(
+    upstream: &Upstream,
+    health: &Health,
+    msg_map: MsgMap,
+    config: &Config,
+    interval: Duration,
+) -> anyhow::Result<()> {
+    let addr = upstream.addr();
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        let result = probe(upstream, &msg_map, config, interval.min(CANARY_TIMEOUT)).await;
+        match &result {
+            Ok(latency) => debug!("health check of {} took {:?}", addr, latency),
+            Err(e) => debug!("health check of {} failed: {}", addr, e),
+        }
+        health.record(&addr, result.is_ok(), config.health_check_failures);
+    }
+}
+
+/// Sends a canary query and waits for the reply loop to hand over its
+/// response.
+async fn probe(
+    upstream: &Upstream,
+    msg_map: &MsgMap,
+    config: &Config,
+    timeout: Duration,
+) -> anyhow::Result<Duration> {
+    let (tx, rx) = oneshot::channel();
+    let sent = Instant::now();
+
+    let id = {
+        let mut map = msg_map.lock().unwrap();
+        let id = allocate_id(&mut map, &config.id_generator);
+        map.insert(
+            id,
+            Pending {
+                id,
+                addr: ([0, 0, 0, 0], 0).into(),
+                upstream: upstream.addr(),
+                sent,
+                key: None,
+                waiters: Vec::new(),
+                max_size: 0,
+                probe: Some(tx),
+                split: None,
+                query: None,
+                fallback: false,
+                bad_cookie: false,
+                tried: Vec::new(),
+                question: None,
+                asked: Some(packet::build_query(id, CANARY_NAME, 1)[12..].to_vec()),
+                edns: false,
+                trace_id: 0,
+            },
+        );
+        id
+    };
+
+    let result = async {
+        upstream
+            .send(&packet::build_query(id, CANARY_NAME, 1))
+            .await?;
+        tokio::time::timeout(timeout, rx)
+            .await
+            .map_err(|_| anyhow::anyhow!("no answer within {:?}", timeout))?
+            .map_err(|_| anyhow::anyhow!("probe dropped"))
+    }
+    .await;
+
+    if result.is_err() {
+        let mut map = msg_map.lock().unwrap();
+        if map.get(id).is_some_and(|pending| pending.probe.is_some()) {
+            map.remove(id);
+        }
+    }
+    result.map(|_| sent.elapsed())
+}
@@ -0,0 +1,137 @@
+//! Callbacks for embedders, run on every query received and response sent,
+//! and a stream of the queries answered for live monitoring.
+
+use std::{fmt, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::sync::broadcast;
+
+use crate::packet::QuestionEntry;
+
+/// A query from a client. The name and type are those of its first question,
+/// empty and 0 when there is none that can be parsed.
+#[derive(Debug)]
+pub struct QueryEvent<'a> {
+    pub client: SocketAddr,
+    pub qname: &'a str,
+    pub qtype: u16,
+}
+
+/// A response sent to a client, for the query it answers.
+#[derive(Debug)]
+pub struct ResponseEvent<'a> {
+    pub client: SocketAddr,
+    pub qname: &'a str,
+    pub qtype: u16,
+    pub origin: Origin,
+    pub rcode: u8,
+    /// since the query was received, or for forwarded ones since it was
+    /// sent to the upstream that answered
+    pub latency: Duration,
+}
+
+/// An owned [`ResponseEvent`], as broadcast to monitoring subscribers.
+#[derive(Debug, Clone)]
+pub struct MonitorEvent {
+    pub client: SocketAddr,
+    pub qname: String,
+    pub qtype: u16,
+    pub origin: Origin,
+    pub rcode: u8,
+    pub latency: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// answered by the relay itself, e.g. by the `hosts` stage
+    Local(&'static str),
+    /// relayed from the upstream
+    Upstream,
+}
+
+type QueryHook = Arc<dyn Fn(&QueryEvent) + Send + Sync>;
+type ResponseHook = Arc<dyn Fn(&ResponseEvent) + Send + Sync>;
+
+/// The callbacks to run, none by default. They run on the query path, so
+/// they should return quickly.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    on_query: Option<QueryHook>,
+    on_response: Option<ResponseHook>,
+    events: Option<broadcast::Sender<MonitorEvent>>,
+}
+
+impl Hooks {
+    pub fn on_query(mut self, f: impl Fn(&QueryEvent) + Send + Sync + 'static) -> Self {
+        self.on_query = Some(Arc::new(f));
+        self
+    }
+
+    pub fn on_response(mut self, f: impl Fn(&ResponseEvent) + Send + Sync + 'static) -> Self {
+        self.on_response = Some(Arc::new(f));
+        self
+    }
+
+    /// Broadcasts a [`MonitorEvent`] for every response sent, to as many
+    /// receivers as `events` has. Receivers that fall behind miss the oldest
+    /// events rather than hold up the relay.
+    pub fn events(mut self, events: broadcast::Sender<MonitorEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub(crate) fn query(&self, client: SocketAddr, question: Option<&QuestionEntry>) {
+        if let Some(f) = &self.on_query {
+            f(&QueryEvent {
+                client,
+                qname: question.map_or("", |q| &q.qname),
+                qtype: question.map_or(0, |q| q.qtype),
+            });
+        }
+    }
+
+    pub(crate) fn response(
+        &self,
+        client: SocketAddr,
+        question: Option<&QuestionEntry>,
+        origin: Origin,
+        rcode: u8,
+        latency: Duration,
+    ) {
+        let event = ResponseEvent {
+            client,
+            qname: question.map_or("", |q| &q.qname),
+            qtype: question.map_or(0, |q| q.qtype),
+            origin,
+            rcode,
+            latency,
+        };
+        if let Some(f) = &self.on_response {
+            f(&event);
+        }
+        // an error only means nobody is subscribed right now
+        if let Some(events) = self
+            .events
+            .as_ref()
+            .filter(|events| events.receiver_count() > 0)
+        {
+            let _ = events.send(MonitorEvent {
+                client,
+                qname: event.qname.to_owned(),
+                qtype: event.qtype,
+                origin,
+                rcode,
+                latency,
+            });
+        }
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_query", &self.on_query.is_some())
+            .field("on_response", &self.on_response.is_some())
+            .field("events", &self.events.is_some())
+            .finish()
+    }
+}
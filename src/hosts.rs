@@ -0,0 +1,832 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs,
+    io::BufRead,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use base64::Engine;
+use reqwest::{
+    header::{HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    StatusCode,
+};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use crate::{
+    packet::{self, RData},
+    DEFAULT_TTL,
+};
+
+pub const DS: u16 = 43;
+pub const DNSKEY: u16 = 48;
+pub const NAPTR: u16 = 35;
+pub const DNAME: u16 = 39;
+pub const SVCB: u16 = 64;
+pub const HTTPS: u16 = 65;
+pub const SOA: u16 = 6;
+pub const CNAME: u16 = 5;
+pub const TLSA: u16 = 52;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Everything the hosts file defines.
+///
+/// Besides the classic `<ip> <name>...` lines, typed records can be given as
+/// `<TYPE> <name> <rdata>`, e.g. `DS example.lan <base64 rdata>`, or in the
+/// zone file presentation format for NAPTR:
+/// `NAPTR example.lan 100 10 "u" "E2U+sip" "!^.*$!sip:info@example.lan!" .`
+/// for DNAME: `DNAME old.lan new.lan`, for CNAME: `CNAME shop.lan shop.example.com`,
+/// for SVCB and HTTPS:
+/// `HTTPS example.lan 1 . alpn=h3,h2 port=443 ipv4hint=10.0.0.1`, and for SOA:
+/// `SOA example.lan ns.example.lan hostmaster.example.lan 1 3600 600 86400 300`,
+/// and for TLSA: `TLSA _443._tcp.web.lan 3 1 1 <hex>`.
+/// Records of any other type are given as `GENERIC <name> TYPE<n> <rdata>`,
+/// the rdata in hex (RFC 3597) or base64, e.g. `GENERIC example.lan TYPE44 0101ab`
+/// for an SSHFP record.
+#[derive(Debug, Default)]
+pub struct Hosts {
+    addrs: HashMap<String, IpAddr>,
+    // the first name of each address, for PTR queries
+    names: HashMap<IpAddr, String>,
+    // type, TTL and rdata
+    records: HashMap<String, Vec<(u16, u32, RData)>>,
+    // leave out records repeating one already loaded
+    dedup: bool,
+    // records left out for repeating one already loaded
+    duplicates: usize,
+}
+
+impl Hosts {
+    pub(crate) fn addr(&self, name: &str) -> Option<IpAddr> {
+        self.addrs.get(name).copied()
+    }
+
+    pub(crate) fn name(&self, addr: IpAddr) -> Option<&str> {
+        self.names.get(&addr).map(String::as_str)
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.addrs.contains_key(name) || self.records.contains_key(name)
+    }
+
+    pub(crate) fn records(&self, name: &str, rtype: u16) -> impl Iterator<Item = (u32, &RData)> {
+        self.records
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(move |(t, _, _)| *t == rtype)
+            .map(|(_, ttl, rdata)| (*ttl, rdata))
+    }
+
+    /// Number of names with an address plus typed records.
+    pub fn entries(&self) -> usize {
+        self.addrs.len() + self.records.values().map(Vec::len).sum::<usize>()
+    }
+
+    /// Number of records collapsed into an identical one, same name, type and
+    /// rdata, while loading.
+    pub fn duplicates(&self) -> usize {
+        self.duplicates
+    }
+}
+
+pub fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+pub fn load_hosts(path: &str, dedup: bool) -> anyhow::Result<Hosts> {
+    let file = fs::File::open(path)?;
+    let hosts = parse_hosts(std::io::BufReader::new(file), dedup)?;
+    info!(
+        "loaded {} hosts entries from file {}, {} duplicate(s) collapsed",
+        hosts.entries(),
+        path,
+        hosts.duplicates()
+    );
+    Ok(hosts)
+}
+
+/// A hosts file served over http(s). Every successful download is saved to
+/// `cache_path`, which is loaded instead when the first download fails.
+pub struct Remote {
+    url: String,
+    cache_path: Option<String>,
+    client: reqwest::Client,
+    // validators of the last download, for conditional requests
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    dedup: bool,
+}
+
+impl Remote {
+    pub fn new(url: &str, cache_path: Option<&str>, dedup: bool) -> anyhow::Result<Self> {
+        Ok(Self {
+            url: url.to_owned(),
+            cache_path: cache_path.map(str::to_owned),
+            dedup,
+            client: reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?,
+            etag: None,
+            last_modified: None,
+        })
+    }
+
+    pub async fn load(&mut self) -> anyhow::Result<Hosts> {
+        let fetched = self.fetch().await.and_then(|hosts| {
+            hosts.ok_or(anyhow::anyhow!("not modified, but nothing was requested"))
+        });
+        match fetched {
+            Ok(hosts) => Ok(hosts),
+            Err(e) => match &self.cache_path {
+                Some(cache_path) => {
+                    warn!(
+                        "failed to fetch hosts from {}, falling back to {}: {}",
+                        self.url, cache_path, e
+                    );
+                    load_hosts(cache_path, self.dedup)
+                }
+                None => Err(e.context(format!("failed to fetch hosts from {}", self.url))),
+            },
+        }
+    }
+
+    /// Downloads the hosts again, `None` if unchanged since the last time.
+    async fn fetch(&mut self) -> anyhow::Result<Option<Hosts>> {
+        let mut request = self.client.get(&self.url);
+        if let Some(etag) = &self.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &self.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        let response = response.error_for_status()?;
+        let etag = response.headers().get(ETAG).cloned();
+        let last_modified = response.headers().get(LAST_MODIFIED).cloned();
+        let text = response.text().await?;
+
+        // a long list takes a while to parse, which must not hold up queries
+        let dedup = self.dedup;
+        let (hosts, text) =
+            tokio::task::spawn_blocking(move || (parse_hosts(text.as_bytes(), dedup), text))
+                .await?;
+        let hosts = hosts?;
+        info!(
+            "loaded {} hosts entries from url {}, {} duplicate(s) collapsed",
+            hosts.entries(),
+            self.url,
+            hosts.duplicates()
+        );
+        // only once the list is known to be good
+        self.etag = etag;
+        self.last_modified = last_modified;
+
+        if let Some(cache_path) = &self.cache_path {
+            // written aside and renamed, so a crash never leaves half a file
+            let tmp = format!("{}.tmp", cache_path);
+            if let Err(e) = fs::write(&tmp, &text).and_then(|_| fs::rename(&tmp, cache_path)) {
+                warn!(
+                    "failed to save hosts from {} to {}: {}",
+                    self.url, cache_path, e
+                );
+            }
+        }
+        Ok(Some(hosts))
+    }
+}
+
+/// Hosts that can be replaced while being served.
+pub type SharedHosts = Arc<RwLock<Hosts>>;
+
+/// Keeps hosts loaded from a url up to date.
+pub struct Refresher {
+    pub remote: Remote,
+    pub hosts: SharedHosts,
+}
+
+impl Refresher {
+    /// Downloads the hosts every interval and swaps them in when they changed.
+    /// A failed download keeps the current hosts. Queries are only held up for
+    /// the swap itself, not the download, parsing or freeing the old hosts.
+    pub async fn run(mut self, interval: Duration) -> anyhow::Result<()> {
+        let start = tokio::time::Instant::now() + interval;
+        let mut ticker = tokio::time::interval_at(start, interval);
+
+        loop {
+            ticker.tick().await;
+
+            match self.remote.fetch().await {
+                Ok(Some(hosts)) => {
+                    let old = std::mem::replace(&mut *self.hosts.write().unwrap(), hosts);
+                    drop(old);
+                }
+                Ok(None) => debug!("hosts at {} not modified", self.remote.url),
+                Err(e) => warn!(
+                    "failed to refresh hosts from {}, keeping the current ones: {}",
+                    self.remote.url, e
+                ),
+            }
+        }
+    }
+}
+
+fn parse_hosts(reader: impl BufRead, dedup: bool) -> anyhow::Result<Hosts> {
+    parse(reader, dedup, None)
+}
+
+/// What checking a hosts file found wrong with it: lines and records that
+/// are invalid, and names defined more than once or in conflicting ways.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// Loads the hosts file at `path` without serving it, reporting every invalid
+/// line rather than failing at the first one.
+pub fn check_hosts(path: &str, dedup: bool) -> anyhow::Result<(Hosts, Report)> {
+    let file = fs::File::open(path)?;
+    let mut report = Report::default();
+    let hosts = parse(std::io::BufReader::new(file), dedup, Some(&mut report))?;
+    for line in report.errors.iter_mut().chain(&mut report.warnings) {
+        *line = format!("{}: {}", path, line);
+    }
+    Ok((hosts, report))
+}
+
+fn parse(
+    reader: impl BufRead,
+    dedup: bool,
+    mut report: Option<&mut Report>,
+) -> anyhow::Result<Hosts> {
+    let mut hosts = Hosts {
+        dedup,
+        ..Hosts::default()
+    };
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let mut warn = |warning: String| {
+            if let Some(report) = report.as_deref_mut() {
+                report.warnings.push(format!("line {}: {}", i + 1, warning));
+            }
+        };
+        let added = hosts.add_line(&line, &mut warn);
+        match (added, report.as_deref_mut()) {
+            (Ok(()), _) => {}
+            (Err(e), Some(report)) => report.errors.push(format!("line {}: {}", i + 1, e)),
+            (Err(e), None) => anyhow::bail!("line {}: {}", i + 1, e),
+        }
+    }
+
+    Ok(hosts)
+}
+
+impl Hosts {
+    fn add_line(&mut self, line: &str, warn: &mut dyn FnMut(String)) -> anyhow::Result<()> {
+        let mut parts = line.split_whitespace();
+        let first = parts.next().ok_or(anyhow::anyhow!("invalid hosts file"))?;
+
+        if let Ok(ip) = first.parse::<IpAddr>() {
+            for cname in parts {
+                self.add_addr(cname, ip, warn);
+            }
+            return Ok(());
+        }
+
+        if first.eq_ignore_ascii_case("GENERIC") {
+            let (name, rtype, rdata) = parse_generic(&parts.collect::<Vec<_>>())?;
+            self.add_record(name, rtype, DEFAULT_TTL as u32, rdata, warn);
+            return Ok(());
+        }
+        let rtype = record_type(first).ok_or(anyhow::anyhow!(
+            "invalid hosts file: unknown record type {}",
+            first
+        ))?;
+        let name = parts.next().ok_or(anyhow::anyhow!(
+            "invalid hosts file: {} without a name",
+            first
+        ))?;
+        let rdata = parse_rdata(rtype, name, &parts.collect::<Vec<_>>())?;
+
+        self.add_record(name, rtype, DEFAULT_TTL as u32, rdata, warn);
+        Ok(())
+    }
+
+    fn add_addr(&mut self, name: &str, ip: IpAddr, warn: &mut dyn FnMut(String)) {
+        match self.addrs.entry(name.to_owned()) {
+            Entry::Occupied(entry) if *entry.get() == ip => {
+                warn(format!("{} {} is repeated", ip, name));
+                self.duplicates += 1;
+            }
+            Entry::Occupied(entry) => warn(format!(
+                "{} is already {}, {} is ignored",
+                name,
+                entry.get(),
+                ip
+            )),
+            Entry::Vacant(entry) => {
+                entry.insert(ip);
+            }
+        }
+        if self.records(name, CNAME).next().is_some() {
+            warn(format!("{} has both a CNAME and an address", name));
+        }
+        // blocked names have no address to point back from
+        if !ip.is_unspecified() {
+            self.names.entry(ip).or_insert_with(|| name.to_owned());
+        }
+    }
+
+    fn add_record(
+        &mut self,
+        name: &str,
+        rtype: u16,
+        ttl: u32,
+        rdata: RData,
+        warn: &mut dyn FnMut(String),
+    ) {
+        let records = self.records.entry(name.to_owned()).or_default();
+        if records.iter().any(|(t, _, r)| *t == rtype && *r == rdata) {
+            warn(format!("type {} record of {} is repeated", rtype, name));
+            // the first one's TTL is kept
+            if self.dedup {
+                self.duplicates += 1;
+                return;
+            }
+        } else if (rtype == CNAME && !records.is_empty())
+            || records.iter().any(|(rtype, _, _)| *rtype == CNAME)
+        {
+            warn(format!("{} has both a CNAME and other records", name));
+        } else if rtype == CNAME && self.addrs.contains_key(name) {
+            warn(format!("{} has both a CNAME and an address", name));
+        }
+        records.push((rtype, ttl, rdata));
+    }
+}
+
+/// A record of a JSON record set, e.g.
+/// `{"name": "x.lan", "type": "A", "ttl": 300, "data": "1.2.3.4"}`. The data
+/// is the address for A and AAAA, the text for TXT, the hex or base64 rdata
+/// for `TYPE<n>`, and whatever follows the name in a hosts file line for the
+/// types the hosts file knows. Without a TTL, the hosts file's applies.
+///
+/// Addresses are handled like those of hosts file lines where it matters:
+/// `0.0.0.0` and `::` block the name, and a name with an address in the hosts
+/// file keeps it, the JSON one collapsed into it or ignored. Other names can
+/// have any number of A and AAAA records, each with its own TTL.
+#[derive(Debug, Deserialize)]
+struct JsonRecord {
+    name: String,
+    #[serde(rename = "type")]
+    rtype: String,
+    ttl: Option<u32>,
+    data: String,
+}
+
+impl Hosts {
+    /// Adds the records of the JSON record set at `path`, an array of
+    /// [`JsonRecord`]s, to those of the hosts file. With a `report`, invalid
+    /// records and warnings go into it rather than failing or being logged.
+    pub fn load_json(&mut self, path: &str, mut report: Option<&mut Report>) -> anyhow::Result<()> {
+        let json = fs::read_to_string(path).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+        let duplicates = self.duplicates;
+        let entries: Vec<serde_json::Value> = serde_json::from_str(&json)
+            .map_err(|e| anyhow::anyhow!("{}: expected an array of records: {}", path, e))?;
+        for (i, entry) in entries.iter().enumerate() {
+            let mut warn = |warning: String| match report.as_deref_mut() {
+                Some(report) => report
+                    .warnings
+                    .push(format!("{}: record {}: {}", path, i, warning)),
+                None => warn!("{}: record {}: {}", path, i, warning),
+            };
+            let added = self.add_json(entry, &mut warn);
+            match (added, report.as_deref_mut()) {
+                (Ok(()), _) => {}
+                (Err(e), Some(report)) => {
+                    report.errors.push(format!("{}: record {}: {}", path, i, e))
+                }
+                (Err(e), None) => anyhow::bail!("{}: record {}: {}", path, i, e),
+            }
+        }
+        info!(
+            "loaded {} records from json file {}, {} duplicate(s) collapsed",
+            entries.len(),
+            path,
+            self.duplicates - duplicates
+        );
+        Ok(())
+    }
+
+    fn add_json(
+        &mut self,
+        entry: &serde_json::Value,
+        warn: &mut dyn FnMut(String),
+    ) -> anyhow::Result<()> {
+        let record = JsonRecord::deserialize(entry)?;
+        let name = record.name.trim_end_matches('.');
+        anyhow::ensure!(!name.is_empty(), "empty name");
+        let fields: Vec<&str> = record.data.split_whitespace().collect();
+
+        let (rtype, rdata) = match record.rtype.to_ascii_uppercase().as_str() {
+            rtype @ ("A" | "AAAA") => {
+                let addr: IpAddr = match rtype {
+                    "A" => record.data.trim().parse::<Ipv4Addr>()?.into(),
+                    _ => record.data.trim().parse::<Ipv6Addr>()?.into(),
+                };
+                if addr.is_unspecified() || self.addrs.contains_key(name) {
+                    self.add_addr(name, addr, warn);
+                    return Ok(());
+                }
+                self.names.entry(addr).or_insert_with(|| name.to_owned());
+                match addr {
+                    IpAddr::V4(addr) => (1, RData::V4(addr.octets())),
+                    IpAddr::V6(addr) => (28, RData::V6(addr.octets())),
+                }
+            }
+            "TXT" => {
+                let rdata = packet::txt_rdata(&record.data);
+                anyhow::ensure!(rdata.len() <= u16::MAX as usize, "TXT data is too long");
+                (16, RData::Opaque(rdata))
+            }
+            rtype if rtype.starts_with("TYPE") => {
+                let fields: Vec<&str> = [name, rtype].into_iter().chain(fields).collect();
+                let (_, rtype, rdata) = parse_generic(&fields)?;
+                (rtype, rdata)
+            }
+            rtype => {
+                let rtype = record_type(rtype)
+                    .ok_or(anyhow::anyhow!("unknown record type {}", record.rtype))?;
+                (rtype, parse_rdata(rtype, name, &fields)?)
+            }
+        };
+
+        let ttl = record.ttl.unwrap_or(DEFAULT_TTL as u32);
+        self.add_record(name, rtype, ttl, rdata, warn);
+        Ok(())
+    }
+}
+
+/// The type of a typed hosts file line, e.g. `NAPTR`.
+fn record_type(name: &str) -> Option<u16> {
+    match name.to_ascii_uppercase().as_str() {
+        "DS" => Some(DS),
+        "DNSKEY" => Some(DNSKEY),
+        "NAPTR" => Some(NAPTR),
+        "DNAME" => Some(DNAME),
+        "SVCB" => Some(SVCB),
+        "HTTPS" => Some(HTTPS),
+        "SOA" => Some(SOA),
+        "CNAME" => Some(CNAME),
+        "TLSA" => Some(TLSA),
+        _ => None,
+    }
+}
+
+/// Parses the rdata of a typed hosts file line, the fields after the name.
+fn parse_rdata(rtype: u16, name: &str, fields: &[&str]) -> anyhow::Result<RData> {
+    Ok(match rtype {
+        NAPTR => parse_naptr(&fields.join(" "))?,
+        DNAME => match fields {
+            [target] => RData::Dname(packet::encode_name(target)?),
+            _ => anyhow::bail!("invalid hosts file: DNAME {} needs one target", name),
+        },
+        CNAME => match fields {
+            [target] => RData::Opaque(packet::encode_name(target)?),
+            _ => anyhow::bail!("invalid hosts file: CNAME {} needs one target", name),
+        },
+        SVCB | HTTPS => parse_svcb(fields)?,
+        SOA => parse_soa(fields)?,
+        TLSA => parse_tlsa(fields)?,
+        _ => parse_opaque(rtype, fields.concat().as_str())?,
+    })
+}
+
+/// Decodes base64 rdata (whitespace allowed, as in zone files), checking it is
+/// at least long enough for the fixed fields of its type.
+fn parse_opaque(rtype: u16, encoded: &str) -> anyhow::Result<RData> {
+    let data = base64::engine::general_purpose::STANDARD.decode(encoded)?;
+
+    // DS: key tag, algorithm, digest type; DNSKEY: flags, protocol, algorithm
+    let min_len = match rtype {
+        DS | DNSKEY => 5,
+        _ => 1,
+    };
+    anyhow::ensure!(
+        data.len() >= min_len && data.len() <= u16::MAX as usize,
+        "invalid hosts file: rdata of type {} is {} bytes long",
+        rtype,
+        data.len()
+    );
+
+    Ok(RData::Opaque(data))
+}
+
+/// Parses `<usage> <selector> <matching type> <hex>`, the hex as long as
+/// the matching type's digest if it has one.
+fn parse_tlsa(fields: &[&str]) -> anyhow::Result<RData> {
+    let invalid = || anyhow::anyhow!("invalid hosts file: bad TLSA record: {}", fields.join(" "));
+
+    let [usage, selector, matching_type, hex @ ..] = fields else {
+        return Err(invalid());
+    };
+    let [usage, selector, matching_type] =
+        [usage, selector, matching_type].map(|field| field.parse::<u8>().map_err(|_| invalid()));
+    let matching_type = matching_type?;
+    let data = decode_hex(&hex.concat())
+        .filter(|data| !data.is_empty())
+        .ok_or_else(invalid)?;
+
+    // 1 is SHA-256, 2 is SHA-512; 0 is the full certificate or key
+    let expected = match matching_type {
+        1 => Some(32),
+        2 => Some(64),
+        _ => None,
+    };
+    if let Some(expected) = expected {
+        anyhow::ensure!(
+            data.len() == expected,
+            "invalid hosts file: TLSA matching type {} needs {} bytes of data, not {}",
+            matching_type,
+            expected,
+            data.len()
+        );
+    }
+    anyhow::ensure!(
+        data.len() <= u16::MAX as usize - 3,
+        "invalid hosts file: TLSA data is {} bytes long",
+        data.len()
+    );
+
+    Ok(RData::Tlsa {
+        usage: usage?,
+        selector: selector?,
+        matching_type,
+        data,
+    })
+}
+
+/// Parses `<name> TYPE<n> <rdata>`, the rdata in hex or base64, whitespace
+/// allowed.
+fn parse_generic<'a>(fields: &[&'a str]) -> anyhow::Result<(&'a str, u16, RData)> {
+    let invalid = || {
+        anyhow::anyhow!(
+            "invalid hosts file: bad GENERIC record: {}",
+            fields.join(" ")
+        )
+    };
+
+    let [name, rtype, rdata @ ..] = fields else {
+        return Err(invalid());
+    };
+    let rtype: u16 = rtype
+        .get(..4)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("TYPE"))
+        .and_then(|_| rtype[4..].parse().ok())
+        .ok_or_else(invalid)?;
+    // OPT is a pseudo-record, it has no place in an answer
+    anyhow::ensure!(rtype != 0 && rtype != packet::OPT, invalid());
+
+    let encoded = rdata.concat();
+    let data = decode_hex(&encoded)
+        .or_else(|| {
+            base64::engine::general_purpose::STANDARD
+                .decode(&encoded)
+                .ok()
+        })
+        .ok_or_else(invalid)?;
+    anyhow::ensure!(
+        data.len() <= u16::MAX as usize,
+        "invalid hosts file: rdata of type {} is {} bytes long",
+        rtype,
+        data.len()
+    );
+
+    Ok((name, rtype, RData::Raw { rtype, data }))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Parses `<order> <preference> "<flags>" "<services>" "<regexp>" <replacement>`.
+fn parse_naptr(fields: &str) -> anyhow::Result<RData> {
+    let invalid = || anyhow::anyhow!("invalid hosts file: bad NAPTR record: {}", fields);
+
+    let mut tokens = Vec::new();
+    let mut rest = fields.trim_start();
+    while !rest.is_empty() {
+        let (token, after) = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').ok_or_else(invalid)?,
+            None => rest.split_once(' ').unwrap_or((rest, "")),
+        };
+        tokens.push(token);
+        rest = after.trim_start();
+    }
+
+    let [order, preference, flags, services, regexp, replacement] = tokens[..] else {
+        return Err(invalid());
+    };
+    anyhow::ensure!(
+        [flags, services, regexp].iter().all(|s| s.len() <= 255),
+        "invalid hosts file: NAPTR string longer than 255 bytes"
+    );
+
+    Ok(RData::Naptr {
+        order: order.parse().map_err(|_| invalid())?,
+        preference: preference.parse().map_err(|_| invalid())?,
+        flags: flags.to_owned(),
+        services: services.to_owned(),
+        regexp: regexp.to_owned(),
+        replacement: packet::encode_name(replacement)?,
+    })
+}
+
+/// Parses `<mname> <rname> <serial> <refresh> <retry> <expire> <minimum>`.
+fn parse_soa(fields: &[&str]) -> anyhow::Result<RData> {
+    let invalid = || anyhow::anyhow!("invalid hosts file: bad SOA record: {}", fields.join(" "));
+
+    let [mname, rname, numbers @ ..] = fields else {
+        return Err(invalid());
+    };
+    anyhow::ensure!(numbers.len() == 5, invalid());
+
+    let mut data = packet::encode_name(mname)?;
+    data.extend(packet::encode_name(rname)?);
+    for number in numbers {
+        let number: u32 = number.parse().map_err(|_| invalid())?;
+        data.extend_from_slice(&number.to_be_bytes());
+    }
+
+    Ok(RData::Opaque(data))
+}
+
+/// Parses `<priority> <target> <key>=<value>...`, for the keys mandatory,
+/// alpn, no-default-alpn, port, ipv4hint, ech and ipv6hint.
+fn parse_svcb(fields: &[&str]) -> anyhow::Result<RData> {
+    let invalid = || anyhow::anyhow!("invalid hosts file: bad SVCB record: {}", fields.join(" "));
+
+    let [priority, target, params @ ..] = fields else {
+        return Err(invalid());
+    };
+
+    let mut parsed = Vec::new();
+    for param in params {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        let list = || value.split(',').filter(|item| !item.is_empty());
+        let (key, value) = match key {
+            "mandatory" => {
+                let keys = list()
+                    .map(|key| svc_param_key(key).ok_or_else(invalid))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                (0, keys.iter().flat_map(|key| key.to_be_bytes()).collect())
+            }
+            "alpn" => {
+                let mut ids = Vec::new();
+                for id in list() {
+                    anyhow::ensure!(id.len() <= 255, invalid());
+                    ids.push(id.len() as u8);
+                    ids.extend_from_slice(id.as_bytes());
+                }
+                (1, ids)
+            }
+            "no-default-alpn" if value.is_empty() => (2, Vec::new()),
+            "port" => (
+                3,
+                value
+                    .parse::<u16>()
+                    .map_err(|_| invalid())?
+                    .to_be_bytes()
+                    .to_vec(),
+            ),
+            "ipv4hint" => {
+                let mut addrs = Vec::new();
+                for addr in list() {
+                    let addr: std::net::Ipv4Addr = addr.parse().map_err(|_| invalid())?;
+                    addrs.extend_from_slice(&addr.octets());
+                }
+                (4, addrs)
+            }
+            "ech" => (5, base64::engine::general_purpose::STANDARD.decode(value)?),
+            "ipv6hint" => {
+                let mut addrs = Vec::new();
+                for addr in list() {
+                    let addr: std::net::Ipv6Addr = addr.parse().map_err(|_| invalid())?;
+                    addrs.extend_from_slice(&addr.octets());
+                }
+                (6, addrs)
+            }
+            _ => return Err(invalid()),
+        };
+        parsed.push((key, value));
+    }
+    parsed.sort_by_key(|(key, _)| *key);
+    anyhow::ensure!(
+        parsed.windows(2).all(|pair| pair[0].0 != pair[1].0),
+        "invalid hosts file: repeated SvcParam in {}",
+        fields.join(" ")
+    );
+
+    Ok(RData::Svcb {
+        priority: priority.parse().map_err(|_| invalid())?,
+        target: packet::encode_name(target)?,
+        params: parsed,
+    })
+}
+
+fn svc_param_key(name: &str) -> Option<u16> {
+    match name {
+        "alpn" => Some(1),
+        "no-default-alpn" => Some(2),
+        "port" => Some(3),
+        "ipv4hint" => Some(4),
+        "ech" => Some(5),
+        "ipv6hint" => Some(6),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str, contents: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("mini-dns-relay-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn load(name: &str, dedup: bool) -> Hosts {
+        let hosts = temp_file(
+            &format!("{}.txt", name),
+            "10.0.0.1 a.lan\nGENERIC a.lan TYPE44 0101ab\n",
+        );
+        let json = temp_file(
+            &format!("{}.json", name),
+            r#"[{"name": "a.lan", "type": "A", "data": "10.0.0.1"},
+                {"name": "a.lan", "type": "TYPE44", "data": "0101ab"}]"#,
+        );
+        let mut loaded = load_hosts(&hosts, dedup).unwrap();
+        loaded.load_json(&json, None).unwrap();
+        loaded
+    }
+
+    #[test]
+    fn duplicates_are_collapsed_across_hosts_and_json() {
+        let hosts = load("dedup", true);
+
+        assert_eq!(hosts.duplicates(), 2);
+        assert_eq!(hosts.addr("a.lan"), Some(IpAddr::from([10, 0, 0, 1])));
+        assert_eq!(hosts.records("a.lan", 1).count(), 0);
+        assert_eq!(hosts.records("a.lan", 44).count(), 1);
+    }
+
+    #[test]
+    fn typed_duplicates_are_kept_without_dedup() {
+        let hosts = load("no-dedup", false);
+
+        // a name has one address either way
+        assert_eq!(hosts.duplicates(), 1);
+        assert_eq!(hosts.records("a.lan", 1).count(), 0);
+        assert_eq!(hosts.records("a.lan", 44).count(), 2);
+    }
+
+    #[test]
+    fn check_reports_every_invalid_line_and_record() {
+        let path = temp_file(
+            "check.txt",
+            "10.0.0.1 a.lan\nBOGUS b.lan\n10.0.0.2 a.lan\nCNAME c.lan\n",
+        );
+        let json = temp_file(
+            "check.json",
+            r#"[{"name": "d.lan", "type": "A", "data": "nope"},
+                {"name": "e.lan", "type": "A", "data": "10.0.0.5"},
+                {"name": "", "type": "A", "data": "10.0.0.6"}]"#,
+        );
+
+        let (mut hosts, mut report) = check_hosts(&path, true).unwrap();
+        hosts.load_json(&json, Some(&mut report)).unwrap();
+
+        assert_eq!(report.errors.len(), 4, "{:?}", report.errors);
+        assert!(report.errors[0].starts_with(&format!("{}: line 2: ", path)));
+        assert!(report.errors[1].starts_with(&format!("{}: line 4: ", path)));
+        assert!(report.errors[2].starts_with(&format!("{}: record 0: ", json)));
+        assert!(report.errors[3].starts_with(&format!("{}: record 2: ", json)));
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(hosts.addr("a.lan"), Some(IpAddr::from([10, 0, 0, 1])));
+        assert_eq!(hosts.records("e.lan", 1).count(), 1);
+    }
+}
@@ -0,0 +1,45 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+};
+
+/// Source of the ids given to queries forwarded upstream.
+#[derive(Clone)]
+pub struct IdGenerator(Arc<dyn Fn() -> u16 + Send + Sync>);
+
+impl IdGenerator {
+    pub fn new(f: impl Fn() -> u16 + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    /// Uniformly random ids, which is what should be used in production.
+    pub fn random() -> Self {
+        Self::new(rand::random::<u16>)
+    }
+
+    /// `start`, `start + 1`, ... wrapping around. Meant for tests, where the
+    /// rewritten id needs to be known in advance.
+    pub fn sequential(start: u16) -> Self {
+        let next = AtomicU16::new(start);
+        Self::new(move || next.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn next(&self) -> u16 {
+        (self.0)()
+    }
+}
+
+impl Default for IdGenerator {
+    fn default() -> Self {
+        Self::random()
+    }
+}
+
+impl fmt::Debug for IdGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("IdGenerator")
+    }
+}
@@ -1,277 +1,3096 @@
+mod allowlist;
+mod backend;
+#[doc(hidden)]
+pub mod bench;
+mod cache;
+mod control;
+mod cookie;
+mod device;
+mod dnscrypt;
+mod health;
+mod hooks;
+mod hosts;
+mod id;
+mod intern;
+mod lookup;
+mod metrics;
+mod mirror;
 mod packet;
+mod pattern;
+mod pipeline;
+mod profile;
+mod querylog;
+mod rebind;
+mod responses;
+mod rrl;
+mod schedule;
+mod script;
+mod split;
+mod status;
+mod upstream;
+mod views;
 
 use std::{
+    borrow::Cow,
     collections::HashMap,
-    env,
-    io::BufRead,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::{Arc, Mutex},
+    env, fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
 };
 
+use allowlist::Allowlist;
+use backend::Backend;
+use cache::{Cache, CacheKey};
+use control::{Control, Overrides};
+use cookie::{Check, Cookies};
+use device::Devices;
+use dnscrypt::DnsCryptUpstream;
+use futures_util::future::{join_all, try_join_all};
+use intern::Interner;
+use metrics::{Metrics, Source};
+use mirror::Mirror;
 use packet::{QuestionEntry, RData, ResourceRecord};
-use tokio::net::UdpSocket;
-use tracing::{debug, error, info, trace};
+use pipeline::{BlockReasons, Pipeline, Response};
+use querylog::QueryLog;
+use rand::Rng;
+use rrl::Rrl;
+use script::Script;
+use serde::Serialize;
+use split::Split;
+use status::Status;
+use tokio::{
+    net::UdpSocket,
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
+use upstream::{Backoff, Resolver, TcpUpstream, UdpUpstream, Upstream};
+use views::Views;
+
+pub use backend::BackendKind;
+pub use device::{DeviceListKind, DevicePolicy};
+pub use hooks::{Hooks, MonitorEvent, Origin, QueryEvent, ResponseEvent};
+pub use hosts::{Hosts, Report};
+pub use id::IdGenerator;
+pub use lookup::{RecordType, Relay};
+pub use pattern::{NamePattern, PatternAction};
+pub use pipeline::StageKind;
+pub use profile::Profile;
+pub use responses::StaticResponse;
+pub use rrl::{RrlAction, RrlClient};
+pub use schedule::Schedule;
+pub use upstream::Transport;
+
+pub type MsgMap = Arc<Mutex<PendingMap>>;
+/// Rewritten id of the query in flight for each question, for coalescing.
+pub type InFlight = Arc<Mutex<HashMap<CoalesceKey, u16>>>;
+
+/// What queries must share to be answered by the same upstream response: the
+/// question, the DO and CD bits, which change what upstream answers, and
+/// whether they have an OPT record, which the response may only have then.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CoalesceKey {
+    question: CacheKey,
+    edns: bool,
+    dnssec_ok: bool,
+    checking_disabled: bool,
+}
+
+/// A client waiting for the response to a query in flight.
+#[derive(Debug)]
+pub struct Client {
+    pub id: u16,
+    pub addr: SocketAddr,
+    // its question section as sent, put back into the response it gets when
+    // that echoes another casing
+    pub(crate) question: Option<Vec<u8>>,
+}
+
+/// A query forwarded upstream and waiting for its response.
+#[derive(Debug)]
+pub struct Pending {
+    pub id: u16,
+    pub addr: SocketAddr,
+    pub upstream: Arc<str>,
+    pub sent: Instant,
+    // question of a coalescable query and the (id, addr) of clients that
+    // asked the same while it was in flight
+    pub key: Option<CoalesceKey>,
+    pub waiters: Vec<Client>,
+    // largest response all of the clients above can take over UDP
+    pub max_size: usize,
+    // set for health check canaries, which have no client to answer
+    pub probe: Option<tokio::sync::oneshot::Sender<()>>,
+    // local answers to merge with the response, for a query split in two
+    pub(crate) split: Option<Split>,
+    // the query as forwarded, kept while it may be sent again, to a fallback
+    // or after a BADCOOKIE
+    pub(crate) query: Option<Vec<u8>>,
+    // whether an answer with one of retry_rcodes is retried on the fallbacks,
+    // which tagged queries are not
+    pub(crate) fallback: bool,
+    // set once the query was sent again with the server cookie of a BADCOOKIE
+    pub(crate) bad_cookie: bool,
+    // upstreams the query was sent to so far, by index
+    pub tried: Vec<usize>,
+    // the question section as the client sent it, when lowercased for upstream
+    pub(crate) question: Option<Vec<u8>>,
+    // the question section as sent upstream, for the response to echo
+    pub(crate) asked: Option<Vec<u8>>,
+    // whether the client sent an OPT record, which the response keeps only then
+    pub edns: bool,
+    // ties the query's spans to its metrics exemplar
+    pub trace_id: u64,
+}
+
+/// In-flight queries by rewritten id, also indexed by the (id, addr) of every
+/// client waiting on them so that retransmits can be recognized.
+#[derive(Debug, Default)]
+pub struct PendingMap {
+    by_id: HashMap<u16, Pending>,
+    by_client: HashMap<(u16, SocketAddr), u16>,
+}
+
+impl PendingMap {
+    pub fn contains(&self, id: u16) -> bool {
+        self.by_id.contains_key(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_id.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_id.is_empty()
+    }
+
+    pub fn get(&self, id: u16) -> Option<&Pending> {
+        self.by_id.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: u16) -> Option<&mut Pending> {
+        self.by_id.get_mut(&id)
+    }
+
+    /// Rewritten id of the query the client sent with `id`, if still in flight.
+    pub fn by_client(&self, id: u16, addr: SocketAddr) -> Option<u16> {
+        self.by_client.get(&(id, addr)).copied()
+    }
+
+    pub fn insert(&mut self, new_id: u16, pending: Pending) {
+        if pending.probe.is_none() {
+            self.by_client.insert((pending.id, pending.addr), new_id);
+        }
+        self.by_id.insert(new_id, pending);
+    }
+
+    /// Makes the client wait for the response to `new_id` as well.
+    pub fn add_waiter(&mut self, new_id: u16, client: Client, max_size: usize) {
+        if let Some(pending) = self.by_id.get_mut(&new_id) {
+            self.by_client.insert((client.id, client.addr), new_id);
+            pending.waiters.push(client);
+            pending.max_size = pending.max_size.min(max_size);
+        }
+    }
 
-pub type MsgMap = Arc<Mutex<HashMap<u16, (u16, SocketAddr)>>>;
-pub type Hosts = HashMap<String, IpAddr>;
+    pub fn remove(&mut self, id: u16) -> Option<Pending> {
+        let pending = self.by_id.remove(&id)?;
+        if pending.probe.is_none() {
+            let waiters = pending
+                .waiters
+                .iter()
+                .map(|client| (client.id, client.addr));
+            for client in std::iter::once((pending.id, pending.addr)).chain(waiters) {
+                if self.by_client.get(&client) == Some(&id) {
+                    self.by_client.remove(&client);
+                }
+            }
+        }
+        Some(pending)
+    }
+
+    fn oldest(&self) -> Option<u16> {
+        self.by_id
+            .iter()
+            .min_by_key(|(_, p)| p.sent)
+            .map(|(id, _)| *id)
+    }
+}
 
 const BUF_SIZE: usize = 512;
+// largest upstream response relayed; clients get at most what they advertise
+const MAX_UDP_SIZE: usize = 4096;
+const MAX_ID_ATTEMPTS: usize = 32;
 const DEFAULT_TTL: usize = 600;
+const CANARY_NAME: &str = "a.root-servers.net";
+const CANARY_TIMEOUT: Duration = Duration::from_secs(3);
+// answered with the decoded query when echo_query is on
+const ECHO_NAME: &str = "echo.relay.invalid";
+/// CHAOS names answered with the relay's version under `UnknownClassPolicy::Chaos`.
+const VERSION_NAMES: [&str; 2] = ["version.bind", "version.server"];
+// label prefix naming the upstream a query goes to, see named_upstreams
+const UPSTREAM_TAG: &str = "via-";
+// most local CNAMEs followed from a queried name
+const MAX_ALIAS_CHAIN: usize = 8;
+
+/// Loads the configured hosts file and JSON records without starting the
+/// relay, returning them with every invalid line and record, and warnings
+/// about names defined more than once or in conflicting ways.
+pub fn check_hosts(config: &Config) -> anyhow::Result<(Hosts, Report)> {
+    anyhow::ensure!(
+        config.hosts_backend == BackendKind::File && !hosts::is_url(&config.hosts_path),
+        "only a local hosts file can be checked"
+    );
+    let (mut hosts, mut report) = hosts::check_hosts(&config.hosts_path, config.dedup_records)
+        .map_err(|e| anyhow::anyhow!("{}: {}", config.hosts_path, e))?;
+    if let Some(path) = &config.json_records_path {
+        hosts.load_json(path, Some(&mut report))?;
+    }
+    Ok((hosts, report))
+}
 
+/// Runs the relay, and a relay of its own for each profile.
 pub async fn run(config: Config) -> anyhow::Result<()> {
-    let local_sock = UdpSocket::bind(&config.local_addr).await?;
+    let profiles: Vec<_> = config
+        .profiles
+        .iter()
+        .map(|profile| {
+            info!("profile {} listens on {}", profile.name, profile.local_addr);
+            serve(profile.config(&config)).instrument(info_span!("profile", name = %profile.name))
+        })
+        .collect();
+    tokio::try_join!(serve(config), try_join_all(profiles))?;
+    Ok(())
+}
+
+async fn serve(config: Config) -> anyhow::Result<()> {
+    let local_sock = Arc::new(UdpSocket::bind(&config.local_addr).await?);
     info!("local socket is listening on {}", &config.local_addr);
 
-    let remote_sock = UdpSocket::bind(&config.remote_addr).await?;
-    info!("remote socket is listening on {}", &config.remote_addr);
+    anyhow::ensure!(
+        config.upstream_transport != Transport::DnsCrypt
+            || (config.fallback_upstreams.is_empty()
+                && config.named_upstreams.is_empty()
+                && config.upstream_weights.is_empty()),
+        "fallback, named and weighted upstreams are not supported with dnscrypt"
+    );
+    anyhow::ensure!(
+        !config.lazy_upstream
+            || (config.dscp.is_none()
+                && config.udp_recv_buffer.is_none()
+                && config.udp_send_buffer.is_none()),
+        "dscp and udp buffer sizes cannot be applied to lazily bound upstream sockets"
+    );
+    let mut upstreams = vec![connect_upstream(&config, &config.upstream_addr).await?];
+    if !config.fallback_upstreams.is_empty() {
+        for addr in &config.fallback_upstreams {
+            upstreams.push(connect_upstream(&config, addr).await?);
+        }
+        info!(
+            "falling back to {} on rcodes {:?}",
+            config.fallback_upstreams.join(", "),
+            config.retry_rcodes
+        );
+    }
+    for (name, addr) in &config.named_upstreams {
+        upstreams.push(connect_upstream(&config, addr).await?);
+        info!("queries for *.{}{} go to {}", UPSTREAM_TAG, name, addr);
+    }
+    // upstreams sharing untagged queries by index, with their weights
+    let mut pool = Vec::new();
+    if !config.upstream_weights.is_empty() {
+        let weight = |addr: &str| {
+            config
+                .upstream_weights
+                .iter()
+                .find(|(weighted, _)| weighted == addr)
+                .map_or(1, |(_, weight)| *weight)
+        };
+        pool.push((0, weight(&config.upstream_addr)));
+        for (addr, weight) in &config.upstream_weights {
+            if *addr != config.upstream_addr {
+                pool.push((upstreams.len(), *weight));
+                upstreams.push(connect_upstream(&config, addr).await?);
+            }
+        }
+        info!(
+            "spreading queries across {}",
+            pool.iter()
+                .map(|(i, weight)| format!("{} (weight {})", upstreams[*i].addr(), weight))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    let upstream = &upstreams[0];
+    // upstreams that untagged queries go to, checked and switched on and off
+    let served: Vec<&Upstream> = match pool.as_slice() {
+        [] => vec![upstream],
+        pool => pool.iter().map(|(i, _)| &upstreams[*i]).collect(),
+    };
 
-    let hosts = load_hosts(&config.hosts_path)?;
-    debug!("hosts: {:?}", hosts);
+    if let Some(dscp) = config.dscp {
+        set_dscp(&local_sock, dscp)?;
+        for upstream in &upstreams {
+            match upstream.socket() {
+                Some(sock) => set_dscp(sock, dscp)?,
+                None => warn!("dscp is not applied to tcp upstream connections"),
+            }
+        }
+        info!("outgoing packets are marked with dscp {}", dscp);
+    }
 
-    let msg_map: MsgMap = Arc::new(Mutex::new(HashMap::new()));
+    if config.udp_recv_buffer.is_some() || config.udp_send_buffer.is_some() {
+        set_buffer_sizes(&local_sock, "local", &config)?;
+        for upstream in &upstreams {
+            match upstream.socket() {
+                Some(sock) => set_buffer_sizes(sock, "upstream", &config)?,
+                None => warn!("udp buffer sizes are not applied to tcp upstream connections"),
+            }
+        }
+    }
 
-    tokio::try_join!(
-        forward(
-            &local_sock,
-            &remote_sock,
-            &hosts,
-            msg_map.clone(),
-            &config.upstream_addr
-        ),
-        reply(&local_sock, &remote_sock, msg_map.clone())
-    )?;
+    let default = Config::default();
+    if config.upstream_transport != Transport::Tcp
+        && (config.retry_base_ms, config.retry_cap_ms)
+            != (default.retry_base_ms, default.retry_cap_ms)
+    {
+        warn!("retry backoff is only applied to tcp upstream connections");
+    }
+
+    if config.offline {
+        info!("offline: answering from the hosts and the cache only");
+    } else if config.startup_check {
+        let results = join_all(
+            upstreams
+                .iter()
+                .map(|upstream| check_upstream(upstream, &config.id_generator)),
+        )
+        .await;
+        let mut reachable = 0;
+        for (upstream, result) in upstreams.iter().zip(results) {
+            match result {
+                Ok(latency) => {
+                    info!("upstream {} answered in {:?}", upstream.addr(), latency);
+                    reachable += 1;
+                }
+                Err(e) => warn!("upstream {} is unreachable: {}", upstream.addr(), e),
+            }
+        }
+        anyhow::ensure!(reachable > 0, "none of the upstreams is reachable");
+    }
+
+    let (hosts, refresher) = backend::open(
+        config.hosts_backend,
+        &config.hosts_path,
+        config.hosts_cache_path.as_deref(),
+        config.json_records_path.as_deref(),
+        config.dedup_records,
+    )
+    .await?;
+
+    let control = Control::new(
+        &served
+            .iter()
+            .map(|upstream| upstream.addr())
+            .collect::<Vec<_>>(),
+        &config.disabled_upstreams,
+    );
+    let cache = config
+        .cache
+        .then(|| Arc::new(Cache::new(config.cache_size)));
+    let responses = responses::load(&config.static_responses)?;
+    let views = match &config.views_path {
+        Some(path) => Arc::new(Views::load(path)?),
+        None => Arc::default(),
+    };
+    let script = config
+        .script_path
+        .as_deref()
+        .map(|path| {
+            Script::load(path, Duration::from_millis(config.script_timeout_ms)).map(Arc::new)
+        })
+        .transpose()?;
+    let pipeline = Pipeline::new(
+        &config.pipeline,
+        responses,
+        &config.blackhole_qtypes,
+        hosts.clone(),
+        control.overrides.clone(),
+        views,
+        &config.schedules,
+        &config.name_patterns,
+        BlockReasons {
+            hosts: config.hosts_blocked_reason.as_deref().map(Arc::from),
+            patterns: config.pattern_blocked_reason.as_deref().map(Arc::from),
+        },
+        &[
+            config.authoritative_zones.clone(),
+            config.reverse_zones.clone(),
+        ]
+        .concat(),
+        config.soa_zones,
+        config.split_questions,
+        cache.clone(),
+        script,
+    );
+    info!(
+        "pipeline: {} -> upstream",
+        pipeline.stages().collect::<Vec<_>>().join(" -> ")
+    );
+
+    if let (Some(cache), Some(path)) = (&cache, &config.cache_snapshot_path) {
+        match cache.load(path) {
+            Ok(n) => info!("loaded {} cache entries from {}", n, path),
+            Err(e) => warn!("ignoring cache snapshot {}: {}", path, e),
+        }
+    }
+
+    let cookies = config.upstream_cookies.then(Cookies::new);
+    let metrics = Arc::new(Metrics::new(config.metrics_exemplars));
+
+    let allowlist = match (&config.allowlist_path, config.allowlist_mode) {
+        (Some(path), true) => Some(Allowlist::load(path)?),
+        (None, true) => anyhow::bail!("ALLOWLIST_MODE needs an ALLOWLIST_PATH"),
+        (_, false) => None,
+    };
+    let devices = match config.device_policies.as_slice() {
+        [] => None,
+        policies => Some(Devices::load(policies)?),
+    };
+    let rrl = config
+        .rrl_rate
+        .map(|rate| {
+            Rrl::new(
+                rate,
+                config.rrl_window,
+                config.rrl_ipv4_prefix,
+                config.rrl_ipv6_prefix,
+                config.rrl_action,
+                &config.rrl_clients,
+            )
+        })
+        .transpose()?;
+    let interner = (config.intern_capacity > 0).then(|| Interner::new(config.intern_capacity));
+    let mirror = match &config.mirror_addr {
+        Some(addr) => Some(Mirror::new(addr).await?),
+        None => None,
+    };
+
+    let msg_map: MsgMap = Arc::default();
+    let status = Arc::new(Status::new(&config, hosts, cache.clone(), msg_map.clone())?);
+    let in_flight: InFlight = Arc::new(Mutex::new(HashMap::new()));
+    let (stop, stopped) = watch::channel(false);
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    let relay = async {
+        tokio::try_join!(
+            forward(
+                &local_sock,
+                &upstreams,
+                &pool,
+                &pipeline,
+                &control,
+                allowlist.as_ref(),
+                devices.as_ref(),
+                rrl.as_ref(),
+                interner.as_ref(),
+                mirror.as_ref(),
+                cookies.as_ref(),
+                &metrics,
+                msg_map.clone(),
+                in_flight.clone(),
+                &config,
+                stopped
+            ),
+            try_join_all((0..upstreams.len()).map(|index| {
+                reply(
+                    &local_sock,
+                    &upstreams,
+                    index,
+                    cache.as_deref(),
+                    rrl.as_ref(),
+                    cookies.as_ref(),
+                    &metrics,
+                    msg_map.clone(),
+                    in_flight.clone(),
+                    &config,
+                )
+            })),
+            async {
+                match &config.control_addr {
+                    Some(addr) => control::serve(addr, &control).await,
+                    None => std::future::pending().await,
+                }
+            },
+            async {
+                match config.health_check_interval.filter(|_| !config.offline) {
+                    Some(secs) => {
+                        try_join_all(served.iter().map(|upstream| {
+                            health::check_loop(
+                                upstream,
+                                &control.health,
+                                msg_map.clone(),
+                                &config,
+                                Duration::from_secs(secs),
+                            )
+                        }))
+                        .await?;
+                        Ok(())
+                    }
+                    None => std::future::pending().await,
+                }
+            },
+            async {
+                match (refresher, config.hosts_refresh_interval) {
+                    (Some(refresher), Some(secs)) => refresher.run(Duration::from_secs(secs)).await,
+                    _ => std::future::pending().await,
+                }
+            },
+            async {
+                match &config.metrics_addr {
+                    Some(addr) => {
+                        metrics::serve(
+                            addr,
+                            metrics.clone(),
+                            control.health.clone(),
+                            status.clone(),
+                            cache.clone(),
+                        )
+                        .await
+                    }
+                    None => std::future::pending().await,
+                }
+            }
+        )
+    };
+    tokio::pin!(relay);
+
+    let signal = tokio::select! {
+        res = &mut relay => {
+            res?;
+            None
+        }
+        _ = tokio::signal::ctrl_c() => Some("interrupted"),
+        _ = terminate.recv() => Some("terminated"),
+    };
+    if let Some(signal) = signal {
+        info!("{}, shutting down", signal);
+        // upstream responses are still relayed while draining
+        let _ = stop.send(true);
+        tokio::select! {
+            res = &mut relay => {
+                res?;
+            }
+            _ = drain(&msg_map, Duration::from_secs(config.drain_timeout)) => {}
+        }
+    }
+
+    if let (Some(cache), Some(path)) = (&cache, &config.cache_snapshot_path) {
+        match cache.save(path) {
+            Ok(n) => info!("saved {} cache entries to {}", n, path),
+            Err(e) => error!("failed to save cache snapshot to {}: {}", path, e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Waits up to `timeout` for the queries in flight upstream to be answered.
+async fn drain(msg_map: &MsgMap, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    let mut last = None;
+
+    loop {
+        let pending = msg_map.lock().unwrap().len();
+        if pending == 0 {
+            info!("no queries left in flight");
+            return;
+        }
+        if Instant::now() >= deadline {
+            warn!(
+                "giving up on {} quer(ies) still in flight after {:?}",
+                pending, timeout
+            );
+            return;
+        }
+        if last != Some(pending) {
+            info!("waiting for {} quer(ies) in flight", pending);
+            last = Some(pending);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+async fn connect_upstream(config: &Config, addr: &str) -> anyhow::Result<Upstream> {
+    let bind_addr = config
+        .upstream_bind_addr
+        .as_deref()
+        .map(parse_bind_addr)
+        .transpose()?;
+    let local = bind_addr.map_or(config.remote_addr.clone(), |addr| addr.to_string());
+
+    let upstream = match config.upstream_transport {
+        Transport::Udp if config.lazy_upstream => {
+            info!(
+                "remote socket for {} is bound on the first query forwarded",
+                addr
+            );
+            Upstream::Udp(UdpUpstream::lazy(&local, addr))
+        }
+        Transport::Udp => Upstream::Udp(UdpUpstream::bind(&local, addr).await?),
+        Transport::Tcp => {
+            info!(
+                "forwarding over a pool of {} tcp connection(s)",
+                config.tcp_pool_size
+            );
+            Upstream::Tcp(TcpUpstream::new(
+                addr,
+                config.tcp_pool_size,
+                bind_addr,
+                Backoff {
+                    base: Duration::from_millis(config.retry_base_ms),
+                    cap: Duration::from_millis(config.retry_cap_ms),
+                },
+            ))
+        }
+        Transport::DnsCrypt => {
+            let stamp = config
+                .dnscrypt_stamp
+                .as_deref()
+                .ok_or(anyhow::anyhow!("DNSCRYPT_STAMP is required for dnscrypt"))?;
+            Upstream::DnsCrypt(DnsCryptUpstream::connect(&local, stamp).await?)
+        }
+    };
+
+    if let (Some(bind_addr), Ok(target)) = (bind_addr, upstream.addr().parse::<SocketAddr>()) {
+        anyhow::ensure!(
+            bind_addr.is_ipv4() == target.is_ipv4(),
+            "upstream bind address {} and upstream {} are of different address families",
+            bind_addr,
+            target
+        );
+    }
+
+    Ok(upstream)
+}
+
+/// Accepts an address with or without a port; without one, any port is used.
+fn parse_bind_addr(addr: &str) -> anyhow::Result<SocketAddr> {
+    addr.parse::<SocketAddr>()
+        .or_else(|_| addr.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 0)))
+        .map_err(|_| anyhow::anyhow!("invalid upstream bind address: {}", addr))
+}
+
+/// Sends a canary query upstream and waits for its answer, returning how long
+/// it took. Must run before the reply loop starts reading from the upstream.
+async fn check_upstream(upstream: &Upstream, ids: &IdGenerator) -> anyhow::Result<Duration> {
+    let id = ids.next();
+    let sent = Instant::now();
+    upstream
+        .send(&packet::build_query(id, CANARY_NAME, 1))
+        .await?;
+
+    let mut buf = [0u8; MAX_UDP_SIZE];
+    tokio::time::timeout(CANARY_TIMEOUT, async {
+        loop {
+            let len = upstream.recv(&mut buf).await?;
+            if len >= 12 && u16::from_be_bytes([buf[0], buf[1]]) == id {
+                return Ok(sent.elapsed());
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("no answer to {} within {:?}", CANARY_NAME, CANARY_TIMEOUT))?
+}
+
+/// Marks packets sent from `sock` with `dscp`, the upper six bits of the IPv4
+/// TOS / IPv6 traffic class byte.
+fn set_dscp(sock: &UdpSocket, dscp: u8) -> anyhow::Result<()> {
+    anyhow::ensure!(dscp < 64, "dscp must be in 0-63, got {}", dscp);
+
+    let tos = (dscp as u32) << 2;
+    let is_ipv6 = sock.local_addr()?.is_ipv6();
+    let sock = socket2::SockRef::from(sock);
+    if is_ipv6 {
+        sock.set_tclass_v6(tos)?;
+    } else {
+        sock.set_tos(tos)?;
+    }
+
+    Ok(())
+}
+
+/// Sets SO_RCVBUF / SO_SNDBUF as configured and logs what the OS granted,
+/// which may be clamped (and on Linux is doubled for bookkeeping).
+fn set_buffer_sizes(sock: &UdpSocket, name: &str, config: &Config) -> anyhow::Result<()> {
+    let sock = socket2::SockRef::from(sock);
+    if let Some(size) = config.udp_recv_buffer {
+        sock.set_recv_buffer_size(size)?;
+        info!(
+            "{} socket receive buffer: asked for {}, got {}",
+            name,
+            size,
+            sock.recv_buffer_size()?
+        );
+    }
+    if let Some(size) = config.udp_send_buffer {
+        sock.set_send_buffer_size(size)?;
+        info!(
+            "{} socket send buffer: asked for {}, got {}",
+            name,
+            size,
+            sock.send_buffer_size()?
+        );
+    }
 
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn forward(
-    local_sock: &UdpSocket,
-    remote_sock: &UdpSocket,
-    hosts: &Hosts,
+    local_sock: &Arc<UdpSocket>,
+    upstreams: &[Upstream],
+    pool: &[(usize, u32)],
+    pipeline: &Pipeline,
+    control: &Control,
+    allowlist: Option<&Allowlist>,
+    devices: Option<&Devices>,
+    rrl: Option<&Rrl>,
+    interner: Option<&Interner>,
+    mirror: Option<&Mirror>,
+    cookies: Option<&Cookies>,
+    metrics: &Metrics,
     msg_map: MsgMap,
-    upstream: &str,
+    in_flight: InFlight,
+    config: &Config,
+    mut stop: watch::Receiver<bool>,
 ) -> anyhow::Result<()> {
-    'outer: loop {
+    let query_log = config
+        .log_queries
+        .then(|| QueryLog::new(config.log_hashed_names));
+
+    loop {
         let mut buf = [0u8; BUF_SIZE];
 
-        let (len, addr) = local_sock.recv_from(&mut buf).await?;
+        let (len, addr) = tokio::select! {
+            res = local_sock.recv_from(&mut buf) => res?,
+            _ = stop.changed() => {
+                info!("no longer accepting queries");
+                return Ok(());
+            }
+        };
+        let received = Instant::now();
         trace!("buf: {:x?}", &buf[..len]);
+        if let Some(mirror) = mirror {
+            mirror.send(addr, &buf[..len]);
+        }
+
+        if len < 12 {
+            debug!("dropping a {} byte message from {}", len, addr);
+            continue;
+        }
+        // answering a response could bounce packets between two servers forever
+        if packet::Message::new(&mut buf, len).header.get_qr() == 1 {
+            let dropped = metrics.responses_dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(
+                "({:x?}) dropping a response received from {} ({} dropped so far)",
+                u16::from_be_bytes([buf[0], buf[1]]),
+                addr,
+                dropped
+            );
+            continue;
+        }
+        if config.log_client_subnet {
+            log_client_subnet(&buf[..len], addr);
+        }
+        let max_size = packet::udp_payload_size(&buf[..len]).min(MAX_UDP_SIZE);
+        let padding = config.response_padding.map(|block| (block, max_size));
+        let edns = packet::has_opt(&buf[..len]);
+        let edns_version = packet::edns_version(&buf[..len]);
+        let flags = u16::from_be_bytes([buf[2], buf[3]]);
 
         let mut msg = packet::Message::new(&mut buf, len);
         info!("({:x?}) query received from {}", msg.header.get_id(), addr);
 
-        let queries = msg.question.entries(msg.header.get_qdcount());
+        let id = msg.header.get_id();
+        let queries =
+            info_span!("parse", id = %format_args!("{:x}", id)).in_scope(|| match interner {
+                Some(interner) => msg
+                    .question
+                    .interned_entries(msg.header.get_qdcount(), interner),
+                None => msg.question.entries(msg.header.get_qdcount()),
+            });
+        let queries = match queries {
+            Ok(queries) => Some(queries),
+            Err(e) => {
+                debug!("({:x?}) malformed question from {}: {}", id, addr, e);
+                trace!("({:x?}) bytes at offset {}: {:02x?}", id, e.offset, e.bytes);
+                None
+            }
+        };
         debug!(
             "({:x?}) questions parsed: {:?}",
             msg.header.get_id(),
             queries
         );
+        config
+            .hooks
+            .query(addr, queries.as_ref().and_then(|queries| queries.first()));
+        if let (Some(query_log), Some(queries)) = (&query_log, &queries) {
+            query_log.log(addr, queries);
+        }
+
+        if let Some(version) = edns_version.filter(|version| *version > 0 && config.badvers) {
+            let len = msg.make_empty_response(0);
+            msg.header.set_ra(config.recursion_available as u8);
+            let len = packet::set_extended_rcode(&mut buf, len, packet::BADVERS, BUF_SIZE as u16)
+                .unwrap_or(len);
+
+            info!(
+                "({:x?}) EDNS version {} is not supported, sending BADVERS back to {}",
+                id, version, addr
+            );
+            send_response(local_sock, rrl, padding, &buf[..len], addr).await?;
+            config.hooks.response(
+                addr,
+                queries.as_ref().and_then(|queries| queries.first()),
+                Origin::Local("edns"),
+                packet::BADVERS as u8,
+                received.elapsed(),
+            );
+            continue;
+        }
+
+        let resolved = match &queries {
+            Some(queries) => info_span!("local", id = %format_args!("{:x}", id)).in_scope(|| {
+                config
+                    .echo_query
+                    .then(|| echo(queries, flags, id))
+                    .flatten()
+                    .map(|response| ("echo", response))
+                    .or_else(|| {
+                        let allowlist = allowlist?;
+                        let denied = queries.iter().find(|q| !allowlist.allows(&q.qname))?;
+                        debug!("{} is not on the allowlist", denied.qname);
+                        Some(("allowlist", Response::Rcode(0b0011)))
+                    })
+                    .or_else(|| {
+                        let devices = devices?;
+                        let (denied, mac) = queries.iter().find_map(|q| {
+                            devices.denies(addr.ip(), &q.qname).map(|mac| (q, mac))
+                        })?;
+                        debug!("{} is filtered for device {}", denied.qname, mac);
+                        let reason = config.device_blocked_reason.as_deref().map(Arc::from);
+                        Some(("device", Response::Blocked(reason)))
+                    })
+                    .or_else(|| Some(("class", unknown_class(queries, config)?)))
+                    .or_else(|| pipeline.resolve(queries, Some(addr.ip())))
+            }),
+            // in allowlist mode, what cannot be parsed cannot be checked either
+            None if config.parse_failure == ParseFailurePolicy::Forward && allowlist.is_none() => {
+                info!(
+                    "({:x?}) question section cannot be parsed, forwarding it as is",
+                    msg.header.get_id()
+                );
+                None
+            }
+            None => {
+                msg.header.set_qdcount(0);
+                info!(
+                    "({:x?}) question section cannot be parsed, sending a refused response back to {}",
+                    msg.header.get_id(),
+                    addr
+                );
+                respond_locally(
+                    local_sock,
+                    rrl,
+                    padding,
+                    &mut buf,
+                    len,
+                    addr,
+                    None,
+                    0b0101,
+                    Origin::Local("parse"),
+                    received,
+                    config,
+                )
+                .await?;
+                continue;
+            }
+        };
+        let queries = queries.unwrap_or_default();
 
-        let mut local_answers = Vec::new();
-        for query in queries {
-            match process(&query, hosts) {
-                Ok(Some(rr)) => {
-                    debug!("({:x?}) local rr created: {:x?}", msg.header.get_id(), rr);
-                    local_answers.push(rr);
+        match resolved {
+            Some((stage, response @ (Response::Records(_) | Response::NoData(_)))) => {
+                // NODATA has no answers, only the zone's SOA as authority
+                let (mut local_answers, nodata) = match response {
+                    Response::Records(rrs) => (rrs, false),
+                    Response::NoData(soa) => (vec![soa], true),
+                    _ => (Vec::new(), false),
+                };
+                if no_cache(&queries, config) {
+                    local_answers.iter_mut().for_each(|rr| rr.ttl = 0);
                 }
-                Ok(None) => {}
-                Err(e) => {
-                    msg.header.set_qr(0b1);
-                    msg.header.set_rcode(0b0011);
+                let capped = match config.max_answer_records {
+                    Some(max) if !nodata && local_answers.len() > max => {
+                        debug!(
+                            "({:x?}) keeping {} of {} local rr(s)",
+                            msg.header.get_id(),
+                            max,
+                            local_answers.len()
+                        );
+                        local_answers.truncate(max);
+                        true
+                    }
+                    _ => false,
+                };
+                let local_count = local_answers.len() as u16;
+                debug!(
+                    "({:x?}) constructed a total of {} local rr(s)",
+                    msg.header.get_id(),
+                    local_count
+                );
+
+                // answers go right after the question, not after what followed it
+                let question_len = msg.question.len();
+                let mut msg = packet::Message::new(&mut buf, 12 + question_len);
 
+                msg.header.set_qr(0b1);
+                msg.header.set_ra(config.recursion_available as u8);
+                msg.header.set_aa(authoritative(config, pipeline, &queries));
+                let set_count = |header: &mut packet::Header, count| match nodata {
+                    true => header.set_nscount(count),
+                    false => header.set_ancount(count),
+                };
+                msg.header.set_ancount(0);
+                msg.header.set_nscount(0);
+                set_count(&mut msg.header, local_count);
+                msg.header.set_arcount(0);
+                msg.header.set_tc(capped as u8);
+                let written = msg.answer.add_entries(local_answers, compression(config));
+                if written < local_count {
+                    debug!(
+                        "({:x?}) only {} local rr(s) fit, truncating",
+                        msg.header.get_id(),
+                        written
+                    );
+                    set_count(&mut msg.header, written);
+                    msg.header.set_tc(0b1);
+                }
+                metrics.observe_answers(Source::Local, if nodata { 0 } else { written });
+
+                info!(
+                    "({:x?}) query is processed by {}, sending response back to {}",
+                    msg.header.get_id(),
+                    stage,
+                    addr
+                );
+                let len = msg.len();
+                let len = if edns { add_opt(&mut buf, len) } else { len };
+
+                trace!("buf: {:x?}", &buf[..len]);
+                send_response(local_sock, rrl, padding, &buf[..len], addr).await?;
+                config.hooks.response(
+                    addr,
+                    queries.first(),
+                    Origin::Local(stage),
+                    0b0000,
+                    received.elapsed(),
+                );
+            }
+            Some((stage, response @ (Response::Rcode(_) | Response::Blocked(_)))) => {
+                let rcode = match response {
+                    Response::Rcode(rcode) => rcode,
+                    _ => 0b0011,
+                };
+                let len = msg.make_empty_response(rcode);
+                msg.header.set_ra(config.recursion_available as u8);
+                msg.header.set_aa(authoritative(config, pipeline, &queries));
+                metrics.observe_answers(Source::Local, 0);
+
+                info!(
+                    "({:x?}) query is answered by {} with rcode {}, sending response back to {}",
+                    msg.header.get_id(),
+                    stage,
+                    rcode,
+                    addr
+                );
+                let reason = match &response {
+                    Response::Blocked(reason) if !queries.is_empty() => {
+                        reason.as_deref().or(config.blocked_reason.as_deref())
+                    }
+                    _ => None,
+                };
+                let len = match reason {
+                    Some(reason) => add_reason(&mut buf, len, reason),
+                    None => len,
+                };
+                let len = if edns { add_opt(&mut buf, len) } else { len };
+
+                trace!("buf: {:x?}", &buf[..len]);
+                match config.blocked_delay {
+                    Some((min, max)) if matches!(response, Response::Blocked(_)) => {
+                        // sent later, so as not to hold up the queries behind it
+                        let delay = Duration::from_millis(rand::thread_rng().gen_range(min..=max));
+                        let local_sock = local_sock.clone();
+                        let resp = pad(&buf[..len], padding);
+                        if let Some(resp) = rate_limit(rrl, &resp, addr).map(Cow::into_owned) {
+                            tokio::spawn(async move {
+                                tokio::time::sleep(delay).await;
+                                if let Err(e) = local_sock.send_to(&resp, addr).await {
+                                    error!("failed to send a delayed response to {}: {}", addr, e);
+                                }
+                            });
+                        }
+                    }
+                    _ => {
+                        send_response(local_sock, rrl, padding, &buf[..len], addr).await?;
+                    }
+                }
+                config.hooks.response(
+                    addr,
+                    queries.first(),
+                    Origin::Local(stage),
+                    rcode,
+                    received.elapsed(),
+                );
+            }
+            Some((stage, Response::Message(mut resp))) => {
+                resp[0..2].copy_from_slice(&msg.header.get_id().to_be_bytes());
+                if no_cache(&queries, config) {
+                    packet::set_ttls(&mut resp, 0);
+                }
+                if stage == "cache" && config.mark_cached && edns {
+                    let option = [packet::CACHED.to_be_bytes(), [0, 0]].concat();
+                    packet::add_edns_option(&mut resp, &option, BUF_SIZE as u16);
+                }
+                if let Some(max) = config.max_answer_records {
+                    let len = resp.len();
+                    let len = packet::cap_answers(&mut resp, len, max).unwrap_or(len);
+                    resp.truncate(len);
+                }
+                let len = packet::truncate(&mut resp, max_size);
+                metrics.observe_answers(
+                    Source::Local,
+                    packet::Message::new(&mut resp, len).header.get_ancount(),
+                );
+
+                info!(
+                    "({:x?}) query is answered by {}, sending response back to {}",
+                    msg.header.get_id(),
+                    stage,
+                    addr
+                );
+
+                trace!("buf: {:x?}", &resp[..len]);
+                send_response(local_sock, rrl, padding, &resp[..len], addr).await?;
+                config.hooks.response(
+                    addr,
+                    queries.first(),
+                    Origin::Local(stage),
+                    resp[3] & 0b0000_1111,
+                    received.elapsed(),
+                );
+            }
+            resolved @ (None | Some((_, Response::Partial { .. } | Response::Chase { .. }))) => {
+                // the local answers wait for upstream to answer the rest
+                let partial = match resolved {
+                    Some((stage, Response::Partial { answers, forwarded })) => {
+                        info!(
+                            "({:x?}) {} of {} questions answered by {}, forwarding the rest",
+                            msg.header.get_id(),
+                            queries.len() - forwarded.len(),
+                            queries.len(),
+                            stage
+                        );
+                        Some(Response::Partial { answers, forwarded })
+                    }
+                    Some((stage, Response::Chase { answers, target })) => {
+                        info!(
+                            "({:x?}) query is answered by {} with an alias, forwarding its target",
+                            msg.header.get_id(),
+                            stage
+                        );
+                        Some(Response::Chase { answers, target })
+                    }
+                    _ => {
+                        info!(
+                            "({:x?}) query cannot be processed locally",
+                            msg.header.get_id()
+                        );
+                        None
+                    }
+                };
+                let tagged = match (&partial, queries.as_slice()) {
+                    (None, [q]) => tagged_upstream(q, config),
+                    _ => None,
+                };
+                let index = match tagged {
+                    Some((i, _)) => i,
+                    None => weighted_upstream(pool, upstreams, control).unwrap_or(0),
+                };
+                let upstream = &upstreams[index];
+                if tagged.is_some() {
                     info!(
-                        "({:x?}) query is {}, sending response back to {}",
+                        "({:x?}) query is tagged for upstream {}",
                         msg.header.get_id(),
-                        e,
+                        upstream.addr()
+                    );
+                }
+
+                if msg.header.get_rd() == 0 && config.non_recursive != NonRecursivePolicy::Forward {
+                    let rcode = match config.non_recursive {
+                        NonRecursivePolicy::Refuse => 0b0101,
+                        _ => 0b0000,
+                    };
+                    info!(
+                        "({:x?}) recursion not desired, sending {} response back to {}",
+                        msg.header.get_id(),
+                        if rcode == 0 { "an empty" } else { "a refused" },
                         addr
                     );
-                    let len = msg.len();
+                    respond_locally(
+                        local_sock,
+                        rrl,
+                        padding,
+                        &mut buf,
+                        len,
+                        addr,
+                        queries.first(),
+                        rcode,
+                        Origin::Local("non-recursive"),
+                        received,
+                        config,
+                    )
+                    .await?;
+                    continue;
+                }
+
+                if config.outside_zone == OutsideZonePolicy::Refuse {
+                    info!(
+                        "({:x?}) query is outside the authoritative zones, sending a refused response back to {}",
+                        msg.header.get_id(),
+                        addr
+                    );
+                    respond_locally(
+                        local_sock,
+                        rrl,
+                        padding,
+                        &mut buf,
+                        len,
+                        addr,
+                        queries.first(),
+                        0b0101,
+                        Origin::Local("outside-zone"),
+                        received,
+                        config,
+                    )
+                    .await?;
+                    continue;
+                }
+
+                if config.offline {
+                    info!(
+                        "({:x?}) query cannot be answered offline, sending a server failure response back to {}",
+                        msg.header.get_id(),
+                        addr
+                    );
+                    respond_locally(
+                        local_sock,
+                        rrl,
+                        padding,
+                        &mut buf,
+                        len,
+                        addr,
+                        queries.first(),
+                        0b0010,
+                        Origin::Local("offline"),
+                        received,
+                        config,
+                    )
+                    .await?;
+                    continue;
+                }
+
+                let state = if !control.is_enabled(&upstream.addr()) {
+                    Some("disabled")
+                } else if !control.health.is_healthy(&upstream.addr()) {
+                    Some("unhealthy")
+                } else if let Err(e) = upstream.ready().await {
+                    error!(
+                        "remote socket for {} cannot be bound: {}",
+                        upstream.addr(),
+                        e
+                    );
+                    Some("unbound")
+                } else {
+                    None
+                };
+                if let Some(state) = state {
+                    info!(
+                        "({:x?}) upstream {} is {}, sending rcode {} back to {}",
+                        msg.header.get_id(),
+                        upstream.addr(),
+                        state,
+                        config.disabled_rcode,
+                        addr
+                    );
+
+                    respond_locally(
+                        local_sock,
+                        rrl,
+                        padding,
+                        &mut buf,
+                        len,
+                        addr,
+                        queries.first(),
+                        config.disabled_rcode,
+                        Origin::Local(state),
+                        received,
+                        config,
+                    )
+                    .await?;
+                    continue;
+                }
+
+                let split = match partial {
+                    Some(Response::Partial { answers, forwarded }) => Split::new(
+                        &mut buf,
+                        len,
+                        &queries,
+                        &forwarded,
+                        answers,
+                        compression(config),
+                    ),
+                    Some(Response::Chase { answers, target }) => {
+                        Split::chase(&mut buf, len, answers, &target, compression(config))
+                    }
+                    _ => None,
+                };
+                let (len, split) = match split {
+                    Some((len, split)) => (len, Some(split)),
+                    None => (len, None),
+                };
+                // the tag is the last label of the only question
+                let len = match (tagged, queries.first()) {
+                    (Some((_, tag)), Some(q)) => {
+                        let start = q.offset + q.qname.trim_end_matches('.').len() - tag;
+                        buf.copy_within(start + 1 + tag..len, start);
+                        len - 1 - tag
+                    }
+                    _ => len,
+                };
+                // a split query gets the client's question back from the merge
+                let question = config
+                    .lowercase_qnames
+                    .then(|| packet::lowercase_question(&mut buf[..len]))
+                    .flatten()
+                    .filter(|_| split.is_none());
+                let asked = packet::question_end(&buf[..len]).map(|end| buf[12..end].to_vec());
+                let trace_id: u64 = rand::random();
+                let edns = packet::has_opt(&buf[..len]);
+                let dnssec_ok = packet::dnssec_ok(&buf[..len]);
+                let mut msg = packet::Message::new(&mut buf, len);
+                let key = match queries.as_slice() {
+                    [q] if config.coalesce => Some(CoalesceKey {
+                        question: CacheKey::from(q),
+                        edns,
+                        dnssec_ok,
+                        checking_disabled: msg.header.get_cd() == 1,
+                    }),
+                    _ => None,
+                };
+
+                {
+                    let mut map = msg_map.lock().unwrap();
+                    let mut in_flight = in_flight.lock().unwrap();
 
-                    trace!("buf: {:x?}", &buf[..len]);
-                    local_sock.send_to(&buf[..len], addr).await?;
+                    if let Some(upstream_id) = map.by_client(msg.header.get_id(), addr) {
+                        info!(
+                            "({:x?}) retransmit of the query in flight as {:x?}, ignoring it",
+                            msg.header.get_id(),
+                            upstream_id
+                        );
+                        continue;
+                    }
 
-                    continue 'outer;
+                    // the id may have been evicted and reused since
+                    if let Some(upstream_id) = key
+                        .as_ref()
+                        .and_then(|key| in_flight.get(key))
+                        .copied()
+                        .filter(|id| map.get(*id).is_some_and(|pending| pending.key == key))
+                    {
+                        let client = Client {
+                            id: msg.header.get_id(),
+                            addr,
+                            question: question.or(asked),
+                        };
+                        map.add_waiter(upstream_id, client, max_size);
+                        info!(
+                            "({:x?}) same question already in flight as {:x?}, waiting for its response",
+                            msg.header.get_id(),
+                            upstream_id
+                        );
+                        continue;
+                    }
+
+                    let new_id = allocate_id(&mut map, &config.id_generator);
+
+                    if let Some(key) = &key {
+                        in_flight.insert(key.clone(), new_id);
+                    }
+                    map.insert(
+                        new_id,
+                        Pending {
+                            id: msg.header.get_id(),
+                            addr,
+                            upstream: upstream.addr(),
+                            sent: Instant::now(),
+                            key,
+                            waiters: Vec::new(),
+                            max_size,
+                            probe: None,
+                            split,
+                            query: None,
+                            fallback: !config.fallback_upstreams.is_empty()
+                                && !config.retry_rcodes.is_empty()
+                                && tagged.is_none(),
+                            bad_cookie: false,
+                            tried: vec![index],
+                            question,
+                            asked,
+                            edns,
+                            trace_id,
+                        },
+                    );
+
+                    info!(
+                        "({:x?}) new id generated: {:x?}",
+                        msg.header.get_id(),
+                        new_id
+                    );
+                    msg.header.set_id(new_id);
+                    // mutex guard dropped here
+                }
+
+                if config.set_cd && msg.header.get_cd() == 0 {
+                    debug!("({:x?}) setting the CD bit", msg.header.get_id());
+                    msg.header.set_cd(1);
+                }
+
+                info!("({:x?}) query is sending to upstream", msg.header.get_id(),);
+
+                if let Some(pending) = msg_map
+                    .lock()
+                    .unwrap()
+                    .get_mut(msg.header.get_id())
+                    .filter(|pending| pending.fallback || cookies.is_some())
+                {
+                    pending.query = Some(buf[..len].to_vec());
                 }
+                let len = match cookies {
+                    Some(cookies) => cookies.add_to_query(&upstream.addr(), &mut buf, len),
+                    None => len,
+                };
+                trace!("buf: {:x?}", &buf[..len]);
+                metrics.count_upstream_query(upstream.addr());
+                upstream
+                    .send(&buf[..len])
+                    .instrument(info_span!(
+                        "forward",
+                        id = %format_args!("{:x}", u16::from_be_bytes([buf[0], buf[1]])),
+                        upstream = %upstream.addr(),
+                        trace_id = %format_args!("{:016x}", trace_id)
+                    ))
+                    .await?;
             }
         }
+    }
+}
 
-        let local_ancount = local_answers.len() as u16;
-        if local_ancount == msg.header.get_qdcount() {
-            debug!(
-                "({:x?}) constructed a total of {} local rr(s)",
-                msg.header.get_id(),
-                local_ancount
-            );
+/// The AA bit of a response built locally for `queries`.
+fn authoritative(config: &Config, pipeline: &Pipeline, queries: &[QuestionEntry]) -> u8 {
+    match config.aa_policy {
+        AaPolicy::Never => 0,
+        AaPolicy::Zones => {
+            (!queries.is_empty() && queries.iter().all(|q| pipeline.authoritative(&q.qname))) as u8
+        }
+        AaPolicy::Local => 1,
+    }
+}
 
-            msg.header.set_qr(0b1);
-            msg.header.set_ancount(local_ancount);
-            msg.header.set_nscount(0);
-            msg.header.set_arcount(0);
-            msg.answer.add_entries(local_answers);
+/// Appends an OPT record to the local response in `buf[..len]` if it fits,
+/// so that EDNS clients keep using EDNS. Returns the new length.
+fn add_opt(buf: &mut [u8], len: usize) -> usize {
+    add_additional(buf, len, &packet::opt_record(BUF_SIZE as u16))
+}
 
-            info!(
-                "({:x?}) query is processed locally, sending response back to {}",
-                msg.header.get_id(),
-                addr
-            );
-            let len = msg.len();
+/// Appends `record` to the additional section of the local response in
+/// `buf[..len]` if it fits. Returns the new length.
+fn add_additional(buf: &mut [u8], len: usize, record: &[u8]) -> usize {
+    let Some(dest) = buf.get_mut(len..len + record.len()) else {
+        return len;
+    };
+    dest.copy_from_slice(record);
 
-            trace!("buf: {:x?}", &buf[..len]);
-            local_sock.send_to(&buf[..len], addr).await?;
-        } else {
-            info!(
-                "({:x?}) query cannot be processed locally",
-                msg.header.get_id()
-            );
+    let arcount = u16::from_be_bytes([buf[10], buf[11]]) + 1;
+    buf[10..12].copy_from_slice(&arcount.to_be_bytes());
+    len + record.len()
+}
 
-            {
-                let mut map = msg_map.lock().unwrap();
+/// Appends a TXT record telling why the first question's name is blocked to
+/// the additional section of the response in `buf[..len]` if it fits, for
+/// diagnostic tools to show. Returns the new length.
+fn add_reason(buf: &mut [u8], len: usize, reason: &str) -> usize {
+    let rdata = packet::txt_rdata(reason);
+    let mut txt = Vec::with_capacity(12 + rdata.len());
+    txt.extend_from_slice(&0xc00cu16.to_be_bytes());
+    txt.extend_from_slice(&16u16.to_be_bytes());
+    txt.extend_from_slice(&1u16.to_be_bytes());
+    txt.extend_from_slice(&(DEFAULT_TTL as u32).to_be_bytes());
+    txt.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    txt.extend_from_slice(&rdata);
+    add_additional(buf, len, &txt)
+}
 
-                // try to generate a new id of 16 bits
-                let mut new_id = rand::random::<u16>();
-                while map.contains_key(&new_id) {
-                    new_id = rand::random::<u16>();
-                }
+fn compression(config: &Config) -> packet::Compression {
+    if config.disable_compression {
+        packet::Compression::None
+    } else if config.compress_names {
+        packet::Compression::All
+    } else {
+        packet::Compression::Owners
+    }
+}
 
-                map.insert(new_id, (msg.header.get_id(), addr));
+fn no_cache(questions: &[QuestionEntry], config: &Config) -> bool {
+    questions
+        .iter()
+        .any(|q| packet::in_domains(&q.qname, &config.no_cache_names))
+}
 
-                info!(
-                    "({:x?}) new id generated: {:x?}",
-                    msg.header.get_id(),
-                    new_id
-                );
-                msg.header.set_id(new_id);
-                // mutex guard dropped here
+/// Describes the query in a TXT record if it asks for [`ECHO_NAME`].
+fn echo(queries: &[QuestionEntry], flags: u16, id: u16) -> Option<Response> {
+    let [q] = queries else { return None };
+    if !q.qname.eq_ignore_ascii_case(ECHO_NAME) {
+        return None;
+    }
+
+    let text = format!(
+        "qname={} qtype={} qclass={} id={:#06x} flags={:#06x} opcode={} rd={} ad={} cd={}",
+        q.qname,
+        q.qtype,
+        q.qclass,
+        id,
+        flags,
+        (flags >> 11) & 0b1111,
+        (flags >> 8) & 1,
+        (flags >> 5) & 1,
+        (flags >> 4) & 1
+    );
+    let rdata = RData::Opaque(packet::txt_rdata(&text));
+    Some(Response::Records(vec![ResourceRecord {
+        name: name_compressed(q),
+        rtype: 16,
+        rclass: q.qclass,
+        ttl: 0,
+        rdlength: rdata.len() as u16,
+        rdata,
+    }]))
+}
+
+/// The response to queries with a class other than IN under the
+/// `unknown_class` policy, `None` to go on as with any other query.
+fn unknown_class(queries: &[QuestionEntry], config: &Config) -> Option<Response> {
+    let q = queries.iter().find(|q| q.qclass != 1)?;
+    match config.unknown_class {
+        UnknownClassPolicy::Forward => None,
+        UnknownClassPolicy::Refuse => Some(Response::Rcode(0b0101)),
+        UnknownClassPolicy::Chaos => {
+            let known = queries.len() == 1
+                && q.qclass == 3
+                && matches!(q.qtype, 16 | 255)
+                && VERSION_NAMES
+                    .iter()
+                    .any(|name| q.qname.eq_ignore_ascii_case(name));
+            if !known {
+                return Some(Response::Rcode(0b0101));
             }
+            let rdata = RData::Opaque(packet::txt_rdata(concat!(
+                "mini-dns-relay ",
+                env!("CARGO_PKG_VERSION")
+            )));
+            Some(Response::Records(vec![ResourceRecord {
+                name: name_compressed(q),
+                rtype: 16,
+                rclass: q.qclass,
+                ttl: 0,
+                rdlength: rdata.len() as u16,
+                rdata,
+            }]))
+        }
+    }
+}
 
-            info!("({:x?}) query is sending to upstream", msg.header.get_id(),);
+fn log_client_subnet(buf: &[u8], addr: SocketAddr) {
+    let id = u16::from_be_bytes([buf[0], buf[1]]);
+    for (_, data) in packet::opt_options(buf)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(code, _)| *code == packet::EDNS_CLIENT_SUBNET)
+    {
+        match packet::ClientSubnet::parse(&buf[data]) {
+            Some(ecs) => info!("({:x?}) client {} sent subnet {}", id, addr, ecs),
+            None => debug!("({:x?}) client {} sent a malformed subnet option", id, addr),
+        }
+    }
+}
 
-            trace!("buf: {:x?}", &buf[..len]);
-            remote_sock.send_to(&buf[..len], &upstream).await?;
+/// Picks an id not used by any in-flight query. When the id space is close to
+/// exhausted, the oldest entry (most likely a query upstream never answered)
+/// is evicted and its id reused instead of retrying forever.
+fn allocate_id(map: &mut PendingMap, ids: &IdGenerator) -> u16 {
+    for _ in 0..MAX_ID_ATTEMPTS {
+        let id = ids.next();
+        if !map.contains(id) {
+            return id;
         }
     }
+
+    match map.oldest() {
+        Some(id) => {
+            let evicted = map.remove(id).unwrap();
+            warn!(
+                "({:x?}) no free id after {} attempts, evicting the query from {} sent {:?} ago",
+                id,
+                MAX_ID_ATTEMPTS,
+                evicted.addr,
+                evicted.sent.elapsed()
+            );
+            id
+        }
+        None => ids.next(),
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn reply(
     local_sock: &UdpSocket,
-    remote_sock: &UdpSocket,
+    upstreams: &[Upstream],
+    index: usize,
+    cache: Option<&Cache>,
+    rrl: Option<&Rrl>,
+    cookies: Option<&Cookies>,
+    metrics: &Metrics,
     msg_map: MsgMap,
+    in_flight: InFlight,
+    config: &Config,
 ) -> anyhow::Result<()> {
     loop {
-        let mut buf = [0u8; BUF_SIZE];
+        let mut buf = [0u8; MAX_UDP_SIZE];
 
-        let (len, _) = remote_sock.recv_from(&mut buf).await?;
+        let len = upstreams[index].recv(&mut buf).await?;
         trace!("buf: {:x?}", &buf[..len]);
-
-        let mut msg = packet::Message::new(&mut buf, len);
-        info!(
-            "({:x?}) response received from upstream",
-            msg.header.get_id()
+        if len < 12 {
+            debug!(
+                "dropping a {} byte message from upstream {}",
+                len,
+                upstreams[index].addr()
+            );
+            continue;
+        }
+        let span = info_span!(
+            "reply",
+            id = %format_args!("{:x}", u16::from_be_bytes([buf[0], buf[1]])),
+            trace_id = tracing::field::Empty
         );
+        async {
+            let len = match cookies {
+                Some(cookies) => {
+                    let id = u16::from_be_bytes([buf[0], buf[1]]);
+                    let mut len = len;
+                    match cookies.check_response(&upstreams[index].addr(), &buf[..len]) {
+                        Check::Valid
+                            if packet::extended_rcode(&buf[..len]) == packet::BADCOOKIE =>
+                        {
+                            // sent again once with the new server cookie (RFC 7873 5.3)
+                            let query = msg_map
+                                .lock()
+                                .unwrap()
+                                .get_mut(id)
+                                .filter(|pending| !pending.bad_cookie)
+                                .and_then(|pending| {
+                                    pending.bad_cookie = true;
+                                    pending.sent = Instant::now();
+                                    pending.query.clone()
+                                });
+                            match query {
+                                Some(query) => {
+                                    info!(
+                                        "({:x?}) upstream answered BADCOOKIE, sending the query again with its new server cookie",
+                                        id
+                                    );
+                                    let upstream = &upstreams[index];
+                                    metrics.count_upstream_query(upstream.addr());
+                                    upstream
+                                        .send(&with_cookie(Some(cookies), upstream, query))
+                                        .await?;
+                                    return Ok(());
+                                }
+                                None => {
+                                    warn!(
+                                        "({:x?}) upstream answered BADCOOKIE again, answering SERVFAIL",
+                                        id
+                                    );
+                                    len = packet::set_extended_rcode(
+                                        &mut buf,
+                                        len,
+                                        0b0010,
+                                        BUF_SIZE as u16,
+                                    )
+                                    .unwrap_or(len);
+                                }
+                            }
+                        }
+                        Check::Valid | Check::Unsupported => {}
+                        Check::Missing => warn!(
+                            "({:x?}) response from upstream lacks the cookie it handed out",
+                            id
+                        ),
+                        Check::Mismatch => {
+                            warn!(
+                                "({:x?}) response from upstream has a wrong cookie, dropping it",
+                                id
+                            );
+                            return Ok(());
+                        }
+                    }
+                    let edns = msg_map.lock().unwrap().get(id).is_none_or(|pending| pending.edns);
+                    cookie::remove(&mut buf, len, edns)
+                }
+                None => len,
+            };
 
-        let origin = msg_map.lock().unwrap().remove(&msg.header.get_id());
-        match origin {
-            Some((id, addr)) => {
-                info!(
-                    "({:x?}) the original query id is {:x?}, changing back to it",
-                    msg.header.get_id(),
-                    id
+            if !answers_question(&msg_map, &buf[..len]) {
+                let dropped = metrics.question_mismatches.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!(
+                    "({:x?}) response from upstream {} is not for the question asked, dropping it ({} dropped so far)",
+                    u16::from_be_bytes([buf[0], buf[1]]),
+                    upstreams[index].addr(),
+                    dropped
                 );
+                return Ok(());
+            }
 
-                msg.header.set_id(id);
-
+            if let Some((query, next)) = retry(&msg_map, upstreams, &buf[..len], config) {
                 info!(
-                    "({:x?}) upstream response is sending back to {}",
-                    msg.header.get_id(),
-                    addr
+                    "({:x?}) upstream {} answered with rcode {}, trying {}",
+                    u16::from_be_bytes([query[0], query[1]]),
+                    upstreams[index].addr(),
+                    buf[3] & 0b0000_1111,
+                    next.addr()
                 );
-
-                let len = msg.len();
-                trace!("buf: {:x?}", &buf[..len]);
-                local_sock.send_to(&buf[..len], addr).await?;
+                metrics.count_upstream_query(next.addr());
+                next.send(&with_cookie(cookies, next, query)).await?;
+                return Ok(());
             }
-            None => {
-                error!("({:x?}) no corresponding query found", msg.header.get_id());
+
+            let private_addr = config
+                .rebind_protection
+                .then(|| rebind::private_addr(&buf[..len]))
+                .flatten();
+
+            let msg = packet::Message::new(&mut buf, len);
+
+            let origin = msg_map.lock().unwrap().remove(msg.header.get_id());
+            match origin {
+                Some(Pending {
+                    id,
+                    addr,
+                    upstream,
+                    sent,
+                    key,
+                    waiters,
+                    max_size,
+                    probe,
+                    split,
+                    question,
+                    trace_id,
+                    ..
+                }) => {
+                    if let Some(probe) = probe {
+                        debug!("({:x?}) health check answered", msg.header.get_id());
+                        let _ = probe.send(());
+                        return Ok(());
+                    }
+                    tracing::Span::current()
+                        .record("trace_id", tracing::field::display(format_args!("{:016x}", trace_id)));
+                    metrics.observe_latency(upstream.clone(), sent.elapsed(), trace_id);
+                    let padding = config.response_padding.map(|block| (block, max_size));
+
+                    if let Some(key) = key {
+                        let mut in_flight = in_flight.lock().unwrap();
+                        if in_flight.get(&key) == Some(&msg.header.get_id()) {
+                            in_flight.remove(&key);
+                        }
+                    }
+                    // the first client's question is put back below, before the
+                    // response is looked at
+                    let first = Client {
+                        id,
+                        addr,
+                        question: None,
+                    };
+                    let clients: Vec<Client> = std::iter::once(first).chain(waiters).collect();
+
+                    let upstream_id = msg.header.get_id();
+                    let len = match split {
+                        Some(split) => {
+                            let mut merged = split.merge(&buf[..len]).unwrap_or_else(|| {
+                                warn!(
+                                    "({:x?}) upstream response cannot be merged with the local answers",
+                                    upstream_id
+                                );
+                                split.failure(&buf[..12])
+                            });
+                            debug!("({:x?}) merged with the local answers", upstream_id);
+                            let len = packet::truncate(&mut merged, MAX_UDP_SIZE);
+                            buf[..len].copy_from_slice(&merged[..len]);
+                            len
+                        }
+                        None => len,
+                    };
+                    if let Some(question) = question.filter(|question| {
+                        buf.get(12..12 + question.len())
+                            .is_some_and(|asked| asked.eq_ignore_ascii_case(question))
+                    }) {
+                        buf[12..12 + question.len()].copy_from_slice(&question);
+                    }
+                    let mut msg = packet::Message::new(&mut buf, len);
+
+                    info!(
+                        "({:x?}) response received from upstream {} in {:?}",
+                        msg.header.get_id(),
+                        upstream,
+                        sent.elapsed()
+                    );
+                    info!(
+                        "({:x?}) the original query id is {:x?}, changing back to it",
+                        msg.header.get_id(),
+                        id
+                    );
+
+                    msg.header.set_id(id);
+                    if msg.header.get_ra() != config.recursion_available as u8 {
+                        debug!("({:x?}) overriding the RA bit from upstream", id);
+                        msg.header.set_ra(config.recursion_available as u8);
+                    }
+                    if config.clear_ad && msg.header.get_ad() == 1 {
+                        debug!("({:x?}) clearing the AD bit from upstream", id);
+                        msg.header.set_ad(0);
+                    }
+
+                    info!(
+                        "({:x?}) upstream response is sending back to {}",
+                        msg.header.get_id(),
+                        addr
+                    );
+
+                    // unparseable questions cannot be on the allowlist
+                    let questions = msg.question.entries(msg.header.get_qdcount()).ok();
+                    if let Some(ip) = private_addr.filter(|_| {
+                        !questions.as_ref().is_some_and(|questions| {
+                            questions
+                                .iter()
+                                .all(|q| packet::in_domains(&q.qname, &config.rebind_allowlist))
+                        })
+                    }) {
+                        let filtered = metrics.rebind_filtered.fetch_add(1, Ordering::Relaxed) + 1;
+                        warn!(
+                            "({:x?}) upstream answered with private address {}, replying NXDOMAIN ({} filtered so far)",
+                            id, ip, filtered
+                        );
+
+                        let len = msg.make_empty_response(0b0011);
+                        for client in &clients {
+                            metrics.observe_answers(Source::Forwarded, 0);
+                            config.hooks.response(
+                                client.addr,
+                                questions.as_deref().and_then(<[_]>::first),
+                                Origin::Upstream,
+                                0b0011,
+                                sent.elapsed(),
+                            );
+                        }
+                        send_to_clients(local_sock, rrl, padding, &mut buf[..len], &clients).await?;
+                        return Ok(());
+                    }
+
+                    let cache_key = match questions.as_deref().unwrap_or_default() {
+                        [q] if cache.is_some()
+                            && msg.header.get_tc() == 0
+                            && matches!(msg.header.get_rcode(), 0b0000 | 0b0011) =>
+                        {
+                            Some(CacheKey::from(q))
+                        }
+                        _ => None,
+                    };
+
+                    let len = msg.len();
+
+                    // a TTL of 0 also keeps it out of our own cache
+                    if no_cache(questions.as_deref().unwrap_or_default(), config) {
+                        debug!("({:x?}) rewriting the TTLs to 0", id);
+                        packet::set_ttls(&mut buf[..len], 0);
+                    }
+
+                    // the full response is cached, hits are capped and truncated for
+                    // their own client
+                    if let (Some(cache), Some(key)) = (cache, cache_key) {
+                        if let Some(ttl) = cache.insert(key, &buf[..len]) {
+                            debug!("({:x?}) response cached for {}s", id, ttl);
+                        }
+                    }
+                    let len = match config.max_answer_records {
+                        Some(max) => packet::cap_answers(&mut buf, len, max).unwrap_or(len),
+                        None => len,
+                    };
+
+                    if len > max_size {
+                        debug!(
+                            "({:x?}) response of {} bytes exceeds the client's {}, truncating",
+                            id, len, max_size
+                        );
+                    }
+                    let len = packet::truncate(&mut buf[..len], max_size);
+                    let ancount = packet::Message::new(&mut buf, len).header.get_ancount();
+                    for client in &clients {
+                        metrics.observe_answers(Source::Forwarded, ancount);
+                        config.hooks.response(
+                            client.addr,
+                            questions.as_deref().and_then(<[_]>::first),
+                            Origin::Upstream,
+                            buf[3] & 0b0000_1111,
+                            sent.elapsed(),
+                        );
+                    }
+                    send_to_clients(local_sock, rrl, padding, &mut buf[..len], &clients).await?;
+                }
+                None => {
+                    info!(
+                        "({:x?}) response received from upstream",
+                        msg.header.get_id()
+                    );
+                    error!("({:x?}) no corresponding query found", msg.header.get_id());
+                }
             }
+            Ok::<_, anyhow::Error>(())
         }
+        .instrument(span)
+        .await?;
     }
 }
 
-fn load_hosts(path: &str) -> anyhow::Result<Hosts> {
-    let mut hosts = HashMap::new();
+/// The upstream a name tagged `<name>.via-<upstream>` goes to, by index
+/// among all of them, and the length of the tag.
+fn tagged_upstream(qe: &QuestionEntry, config: &Config) -> Option<(usize, usize)> {
+    let (_, tag) = qe.qname.trim_end_matches('.').rsplit_once('.')?;
+    let name = tag
+        .get(..UPSTREAM_TAG.len())
+        .filter(|prefix| prefix.eq_ignore_ascii_case(UPSTREAM_TAG))
+        .map(|_| &tag[UPSTREAM_TAG.len()..])?;
+    let i = config
+        .named_upstreams
+        .iter()
+        .position(|(upstream, _)| upstream.eq_ignore_ascii_case(name))?;
+    Some((1 + config.fallback_upstreams.len() + i, tag.len()))
+}
 
-    let file = std::fs::File::open(path)?;
-    let reader = std::io::BufReader::new(file);
+/// An upstream of the weighted pool by index among all of them, picked at
+/// random in proportion to the weights of those enabled and healthy. `None`
+/// if there is no pool or none of it is available.
+fn weighted_upstream(
+    pool: &[(usize, u32)],
+    upstreams: &[Upstream],
+    control: &Control,
+) -> Option<usize> {
+    let available: Vec<_> = pool
+        .iter()
+        .filter(|(i, _)| {
+            let addr = upstreams[*i].addr();
+            control.is_enabled(&addr) && control.health.is_healthy(&addr)
+        })
+        .collect();
+    let total: u64 = available.iter().map(|(_, weight)| *weight as u64).sum();
+    if total == 0 {
+        return None;
+    }
 
-    for line in reader.lines() {
-        let line = line?;
-        let mut parts = line.split_whitespace();
-        let ip = parts.next().ok_or(anyhow::anyhow!("invalid hosts file"))?;
-        let ip = ip.parse::<IpAddr>()?;
-        for cname in parts {
-            hosts.entry(cname.to_owned()).or_insert(ip);
+    let mut pick = rand::thread_rng().gen_range(0..total);
+    for (i, weight) in available {
+        match pick.checked_sub(*weight as u64) {
+            Some(rest) => pick = rest,
+            None => return Some(*i),
         }
     }
+    None
+}
 
-    Ok(hosts)
+/// Whether `resp` echoes the question of the query in flight with its id, as
+/// a spoofed response might not. One without a question is let through if
+/// it reports an error, which is how some servers send FORMERR or NOTIMP.
+fn answers_question(msg_map: &MsgMap, resp: &[u8]) -> bool {
+    if resp.len() < 12 {
+        return false;
+    }
+    let map = msg_map.lock().unwrap();
+    let Some(asked) = map
+        .get(u16::from_be_bytes([resp[0], resp[1]]))
+        .and_then(|pending| pending.asked.as_deref())
+    else {
+        return true;
+    };
+    if resp[4..6] == [0, 0] && resp[3] & 0b0000_1111 != 0 {
+        return true;
+    }
+    packet::echoes_question(resp, asked)
 }
 
-fn process(qe: &QuestionEntry, hosts: &Hosts) -> anyhow::Result<Option<ResourceRecord>> {
-    match hosts.get(&qe.qname) {
-        Some(ip) => match ip {
-            IpAddr::V4(ip) if ip == &Ipv4Addr::UNSPECIFIED => Err(anyhow::anyhow!("blocked")),
-            IpAddr::V4(ip) => {
-                if qe.qtype != 1 {
-                    return Ok(None);
-                }
-                let rr = ResourceRecord {
-                    name: name_compressed(qe),
-                    rtype: qe.qtype,
-                    rclass: qe.qclass,
-                    ttl: DEFAULT_TTL as u32,
-                    rdlength: 4,
-                    rdata: RData::V4(ip.octets()),
-                };
-                Ok(Some(rr))
+/// `query` with the relay's cookie for `upstream`, if cookies are on.
+fn with_cookie(cookies: Option<&Cookies>, upstream: &Upstream, mut query: Vec<u8>) -> Vec<u8> {
+    if let Some(cookies) = cookies {
+        // room for the cookie, which is added in place
+        let len = query.len();
+        query.resize(BUF_SIZE.max(len), 0);
+        let len = cookies.add_to_query(&upstream.addr(), &mut query, len);
+        query.truncate(len);
+    }
+    query
+}
+
+/// The query to send to the next upstream instead of relaying `resp`, if its
+/// rcode is one to retry and upstreams are left to try. The query stays in
+/// flight, now waiting on that upstream.
+fn retry<'a>(
+    msg_map: &MsgMap,
+    upstreams: &'a [Upstream],
+    resp: &[u8],
+    config: &Config,
+) -> Option<(Vec<u8>, &'a Upstream)> {
+    if resp.len() < 12 || !config.retry_rcodes.contains(&(resp[3] & 0b0000_1111)) {
+        return None;
+    }
+
+    let mut map = msg_map.lock().unwrap();
+    let pending = map.get_mut(u16::from_be_bytes([resp[0], resp[1]]))?;
+    if !pending.fallback || pending.tried.len() >= config.max_upstream_attempts {
+        return None;
+    }
+    // upstream_addr then the fallbacks, skipping those already asked, even
+    // when the query first went to another upstream of the same address
+    let index = (0..=config.fallback_upstreams.len()).find(|&i| {
+        pending
+            .tried
+            .iter()
+            .all(|&tried| upstreams[tried].addr() != upstreams[i].addr())
+    })?;
+    let next = upstreams.get(index)?;
+    let query = pending.query.clone()?;
+    pending.tried.push(index);
+    pending.upstream = next.addr();
+    pending.sent = Instant::now();
+    Some((query, next))
+}
+
+/// The response to send to `addr`: `resp` itself, or what RRL replaces it
+/// with once the rate is exceeded, `None` if it is dropped instead.
+fn rate_limit<'a>(rrl: Option<&Rrl>, resp: &'a [u8], addr: SocketAddr) -> Option<Cow<'a, [u8]>> {
+    let rrl = match rrl {
+        Some(rrl) if !rrl.allows(addr.ip(), resp) => rrl,
+        _ => return Some(Cow::Borrowed(resp)),
+    };
+    debug!(
+        "({:x?}) response rate to {} exceeded, action: {:?}",
+        u16::from_be_bytes([resp[0], resp[1]]),
+        addr,
+        rrl.action()
+    );
+
+    match rrl.action() {
+        RrlAction::Truncate => Some(Cow::Owned(packet::truncated(resp))),
+        RrlAction::Drop => None,
+        RrlAction::Refuse => {
+            let mut refused = packet::truncated(resp);
+            refused[2] &= 0b1111_1101;
+            refused[3] = (refused[3] & 0b1111_0000) | 0b0101;
+            // the reason goes in an EDE, for clients that speak EDNS
+            if packet::has_opt(resp) {
+                let ede = packet::ede_option(packet::EDE_PROHIBITED, "rate limited");
+                let mut opt = packet::opt_record(BUF_SIZE as u16).to_vec();
+                opt[9..11].copy_from_slice(&(ede.len() as u16).to_be_bytes());
+                opt.extend_from_slice(&ede);
+                refused.extend_from_slice(&opt);
+                refused[10..12].copy_from_slice(&1u16.to_be_bytes());
             }
-            IpAddr::V6(ip) => {
-                if qe.qtype != 28 {
-                    return Ok(None);
-                }
+            Some(Cow::Owned(refused))
+        }
+    }
+}
+
+/// Sends the query in `buf[..len]` back to `addr` as an empty response with
+/// `rcode`, and reports it to the hooks as answered by `origin`.
+#[allow(clippy::too_many_arguments)]
+async fn respond_locally(
+    local_sock: &UdpSocket,
+    rrl: Option<&Rrl>,
+    padding: Option<(usize, usize)>,
+    buf: &mut [u8],
+    len: usize,
+    addr: SocketAddr,
+    query: Option<&QuestionEntry>,
+    rcode: u8,
+    origin: Origin,
+    received: Instant,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let mut msg = packet::Message::new(buf, len);
+    let len = msg.make_empty_response(rcode);
+    msg.header.set_ra(config.recursion_available as u8);
+
+    trace!("buf: {:x?}", &buf[..len]);
+    send_response(local_sock, rrl, padding, &buf[..len], addr).await?;
+    config
+        .hooks
+        .response(addr, query, origin, rcode, received.elapsed());
+    Ok(())
+}
+
+/// Sends `resp` to a client, subject to RRL.
+async fn send_response(
+    local_sock: &UdpSocket,
+    rrl: Option<&Rrl>,
+    padding: Option<(usize, usize)>,
+    resp: &[u8],
+    addr: SocketAddr,
+) -> anyhow::Result<()> {
+    let resp = pad(resp, padding);
+    if let Some(resp) = rate_limit(rrl, &resp, addr) {
+        local_sock.send_to(&resp, addr).await?;
+    }
+    Ok(())
+}
+
+/// `resp` padded to a multiple of the block size of `padding`, but not
+/// beyond the size limit of the client it is for.
+fn pad(resp: &[u8], padding: Option<(usize, usize)>) -> Cow<'_, [u8]> {
+    padding
+        .and_then(|(block, max_size)| packet::padded(resp, block, max_size))
+        .map_or(Cow::Borrowed(resp), Cow::Owned)
+}
+
+/// Sends the response to every client waiting for it, each with its own id
+/// and question.
+async fn send_to_clients(
+    local_sock: &UdpSocket,
+    rrl: Option<&Rrl>,
+    padding: Option<(usize, usize)>,
+    buf: &mut [u8],
+    clients: &[Client],
+) -> anyhow::Result<()> {
+    for Client { id, addr, question } in clients {
+        buf[0..2].copy_from_slice(&id.to_be_bytes());
+        if let Some(question) = question.as_deref().filter(|question| {
+            buf.get(12..12 + question.len())
+                .is_some_and(|asked| asked.eq_ignore_ascii_case(question))
+        }) {
+            buf[12..12 + question.len()].copy_from_slice(question);
+        }
+        trace!("buf: {:x?}", buf);
+        let resp = pad(buf, padding);
+        if let Some(resp) = rate_limit(rrl, &resp, *addr) {
+            local_sock.send_to(&resp, addr).await?;
+        }
+    }
+    if clients.len() > 1 {
+        debug!("response shared by {} clients", clients.len());
+    }
+
+    Ok(())
+}
+
+/// What blocked a name [`process`] has no records for.
+#[derive(Debug)]
+pub(crate) enum Blocked {
+    /// an address of `0.0.0.0` in the hosts, an override or a view
+    Hosts,
+    /// the name pattern that matched
+    Pattern(String),
+}
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Blocked::Hosts => write!(f, "blocked"),
+            Blocked::Pattern(pattern) => write!(f, "blocked by pattern {}", pattern),
+        }
+    }
+}
+
+impl std::error::Error for Blocked {}
+
+fn process(
+    qe: &QuestionEntry,
+    client: Option<IpAddr>,
+    hosts: &dyn Backend,
+    overrides: &Overrides,
+    views: &Views,
+    schedules: &[Schedule],
+    patterns: &[NamePattern],
+) -> Result<Vec<ResourceRecord>, Blocked> {
+    let qname = qe.qname.trim_end_matches('.').to_ascii_lowercase();
+    let scheduled = schedules
+        .iter()
+        .find(|schedule| schedule.qname == qname)
+        .map(|schedule| schedule.active(SystemTime::now()));
+    let overridden = overrides
+        .read()
+        .unwrap()
+        .get(&qe.qname.to_ascii_lowercase())
+        .copied();
+    // a scheduled answer lives until the next switch
+    let (overridden, ttl) = match (overridden, scheduled) {
+        (None, Some((ip, ttl))) => (Some(ip), ttl),
+        (overridden, _) => (
+            overridden.or_else(|| views.addr(&qe.qname, client?)),
+            DEFAULT_TTL as u32,
+        ),
+    };
+    let ip = overridden
+        .or_else(|| hosts.addr(&qe.qname))
+        .or_else(|| localhost(qe));
+    if ip == Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)) {
+        return Err(Blocked::Hosts);
+    }
+
+    // an override or view replaces whatever the hosts have for the name
+    if overridden.is_none() {
+        if let Some(records) = redirect(qe, hosts).or_else(|| alias(qe, hosts)) {
+            return Ok(records);
+        }
+
+        let records: Vec<ResourceRecord> = hosts
+            .records(&qe.qname, qe.qtype)
+            .into_iter()
+            .map(|(ttl, rdata)| ResourceRecord {
+                name: name_compressed(qe),
+                rtype: qe.qtype,
+                rclass: qe.qclass,
+                ttl,
+                rdlength: rdata.len() as u16,
+                rdata,
+            })
+            .collect();
+        if !records.is_empty() {
+            return Ok(records);
+        }
+
+        if qe.qtype == 12 {
+            // loopback addresses point back to localhost, unless the hosts say otherwise
+            let target = packet::reverse_addr(&qe.qname)
+                .and_then(|addr| {
+                    hosts
+                        .name(addr)
+                        .or_else(|| addr.is_loopback().then(|| "localhost".to_owned()))
+                })
+                .and_then(|name| packet::encode_name(&name).ok());
+            if let Some(target) = target {
                 let rr = ResourceRecord {
                     name: name_compressed(qe),
                     rtype: qe.qtype,
                     rclass: qe.qclass,
                     ttl: DEFAULT_TTL as u32,
-                    rdlength: 16,
-                    rdata: RData::V6(ip.octets()),
+                    rdlength: target.len() as u16,
+                    rdata: RData::Opaque(target),
                 };
-                Ok(Some(rr))
+                return Ok(vec![rr]);
             }
+        }
+    }
+
+    // patterns are slow, so tried last
+    let ip = match ip {
+        Some(ip) => ip,
+        None => match patterns.iter().find(|pattern| pattern.matches(&qname)) {
+            Some(NamePattern {
+                action: PatternAction::Redirect(ip),
+                ..
+            }) => *ip,
+            Some(pattern) => return Err(Blocked::Pattern(pattern.pattern.clone())),
+            None => return Ok(Vec::new()),
         },
-        None => Ok(None),
+    };
+
+    match ip {
+        IpAddr::V4(ip) => {
+            if qe.qtype != 1 {
+                return Ok(Vec::new());
+            }
+            let rr = ResourceRecord {
+                name: name_compressed(qe),
+                rtype: qe.qtype,
+                rclass: qe.qclass,
+                ttl,
+                rdlength: 4,
+                rdata: RData::V4(ip.octets()),
+            };
+            Ok(vec![rr])
+        }
+        IpAddr::V6(ip) => {
+            if qe.qtype != 28 {
+                return Ok(Vec::new());
+            }
+            let rr = ResourceRecord {
+                name: name_compressed(qe),
+                rtype: qe.qtype,
+                rclass: qe.qclass,
+                ttl,
+                rdlength: 16,
+                rdata: RData::V6(ip.octets()),
+            };
+            Ok(vec![rr])
+        }
+    }
+}
+
+/// Loopback address for `localhost` and names under it (RFC 6761 6.3), so
+/// they are never forwarded.
+fn localhost(qe: &QuestionEntry) -> Option<IpAddr> {
+    if !is_localhost(&qe.qname) {
+        return None;
     }
+
+    match qe.qtype {
+        28 => Some(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        _ => Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+    }
+}
+
+/// Whether `qname` is `localhost` or a name under it, which are answered
+/// locally for any type, NODATA for all but A and AAAA.
+fn is_localhost(qname: &str) -> bool {
+    let name = qname.trim_end_matches('.').to_ascii_lowercase();
+    name == "localhost" || name.ends_with(".localhost")
+}
+
+/// Answers a name under a DNAME owner with the DNAME and the CNAME it implies
+/// (RFC 6672). The CNAME is left out if the new name would be too long.
+fn redirect(qe: &QuestionEntry, hosts: &dyn Backend) -> Option<Vec<ResourceRecord>> {
+    let labels: Vec<&str> = qe.qname.split('.').collect();
+    // the owner itself is not redirected, only the names under it
+    let (skip, ttl, target) = (1..labels.len()).find_map(|skip| {
+        let owner = labels[skip..].join(".");
+        hosts
+            .records(&owner, hosts::DNAME)
+            .into_iter()
+            .find_map(|(ttl, rdata)| match rdata {
+                RData::Dname(target) => Some((skip, ttl, target)),
+                _ => None,
+            })
+    })?;
+
+    let prefix: usize = labels[..skip].iter().map(|label| label.len() + 1).sum();
+    let mut records = vec![ResourceRecord {
+        name: name_compressed(qe) + prefix as u16,
+        rtype: hosts::DNAME,
+        rclass: qe.qclass,
+        ttl,
+        rdlength: target.len() as u16,
+        rdata: RData::Dname(target.clone()),
+    }];
+
+    let mut cname: Vec<u8> = labels[..skip]
+        .iter()
+        .flat_map(|label| std::iter::once(label.len() as u8).chain(label.bytes()))
+        .collect();
+    cname.extend_from_slice(&target);
+    if cname.len() <= 255 {
+        records.push(ResourceRecord {
+            name: name_compressed(qe),
+            rtype: 5,
+            rclass: qe.qclass,
+            ttl,
+            rdlength: cname.len() as u16,
+            rdata: RData::Opaque(cname),
+        });
+    }
+    debug!("{} redirected by a DNAME", qe.qname);
+
+    Some(records)
+}
+
+/// The chain of local aliases from the queried name, in order, followed by
+/// the records of the name it ends at if they are local too. A loop ends the
+/// chain where it closes, and a chain longer than [`MAX_ALIAS_CHAIN`] is cut
+/// off there.
+fn alias(qe: &QuestionEntry, hosts: &dyn Backend) -> Option<Vec<ResourceRecord>> {
+    if qe.qtype == hosts::CNAME {
+        return None;
+    }
+    let mut records: Vec<ResourceRecord> = Vec::new();
+    let mut name = qe.qname.to_string();
+    let mut seen = Vec::new();
+    while records.len() < MAX_ALIAS_CHAIN && !seen.contains(&name.to_ascii_lowercase()) {
+        seen.push(name.to_ascii_lowercase());
+        let Some((ttl, rdata)) = hosts.records(&name, hosts::CNAME).into_iter().next() else {
+            break;
+        };
+        let RData::Opaque(target) = &rdata else {
+            break;
+        };
+        name = packet::decode_name(target)?;
+        records.push(ResourceRecord {
+            name: if records.is_empty() {
+                name_compressed(qe)
+            } else {
+                packet::PREVIOUS_TARGET
+            },
+            rtype: hosts::CNAME,
+            rclass: qe.qclass,
+            ttl,
+            rdlength: rdata.len() as u16,
+            rdata,
+        });
+    }
+    if records.is_empty() {
+        return None;
+    }
+    debug!(
+        "{} is an alias of {} through {} CNAME(s)",
+        qe.qname,
+        name,
+        records.len()
+    );
+
+    let addr = hosts
+        .addr(&name)
+        .filter(|addr| !addr.is_unspecified())
+        .map(|addr| match addr {
+            IpAddr::V4(addr) => (1, RData::V4(addr.octets())),
+            IpAddr::V6(addr) => (28, RData::V6(addr.octets())),
+        })
+        .filter(|(rtype, _)| *rtype == qe.qtype)
+        .map(|(_, rdata)| (DEFAULT_TTL as u32, rdata));
+    let target = hosts.records(&name, qe.qtype).into_iter().chain(addr);
+    records.extend(target.map(|(ttl, rdata)| ResourceRecord {
+        name: packet::PREVIOUS_TARGET,
+        rtype: qe.qtype,
+        rclass: qe.qclass,
+        ttl,
+        rdlength: rdata.len() as u16,
+        rdata,
+    }));
+
+    Some(records)
 }
 
 fn name_compressed(qe: &QuestionEntry) -> u16 {
     0b1100_0000_0000_0000 | (qe.offset as u16)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Config {
     pub local_addr: String,
     pub remote_addr: String,
     pub upstream_addr: String,
+    pub upstream_transport: Transport,
+    pub tcp_pool_size: usize,
+    // backoff between attempts to reconnect to a tcp upstream and resend, in
+    // ms; unanswered udp and dnscrypt queries are not resent
+    pub retry_base_ms: u64,
+    pub retry_cap_ms: u64,
+    // sdns:// stamp of the resolver, replacing upstream_addr for dnscrypt
+    pub dnscrypt_stamp: Option<String>,
+    // tried in order after upstream_addr when it answers with one of retry_rcodes
+    pub fallback_upstreams: Vec<String>,
+    // rcodes worth asking the next upstream about, e.g. 2 (SERVFAIL) and 5 (REFUSED)
+    pub retry_rcodes: Vec<u8>,
+    // most upstreams asked per query, the first one included
+    pub max_upstream_attempts: usize,
+    // upstreams by name, for queries tagged e.g. example.com.via-<name>, which
+    // go to that upstream with the tag stripped
+    pub named_upstreams: Vec<(String, String)>,
+    // <address>=<weight> pairs spreading untagged queries at random across
+    // upstreams in proportion to their weights, upstream_addr included with
+    // weight 1 unless listed; disabled and unhealthy ones get none
+    pub upstream_weights: Vec<(String, u32)>,
     pub hosts_path: String,
+    // what hosts_path is: a hosts file or a sqlite database
+    pub hosts_backend: BackendKind,
+    // last copy of hosts fetched from a url, loaded when fetching fails
+    pub hosts_cache_path: Option<String>,
+    // a JSON array of records added to those of the hosts file, see hosts.rs
+    pub json_records_path: Option<String>,
+    // leave out records repeating one already loaded, same name, type and data
+    pub dedup_records: bool,
+    // seconds between downloads of hosts from a url; never again when unset
+    pub hosts_refresh_interval: Option<u64>,
+    // set RA in every response; turn off for authoritative-only deployments
+    pub recursion_available: bool,
+    // answer queries with an EDNS version above 0 with BADVERS (RFC 6891
+    // 6.1.3) rather than forwarding them as they are
+    pub badvers: bool,
+    // what to do with RD=0 queries that cannot be answered locally
+    pub non_recursive: NonRecursivePolicy,
+    // when responses built locally have the AA bit set
+    pub aa_policy: AaPolicy,
+    // what to do with queries for a class other than IN, e.g. CHAOS
+    pub unknown_class: UnknownClassPolicy,
+    // qtypes always answered with NODATA, e.g. AAAA on networks with broken IPv6
+    pub blackhole_qtypes: Vec<u16>,
+    // cache upstream responses for their TTL
+    pub cache: bool,
+    // most responses cached, the least recently used evicted first
+    pub cache_size: usize,
+    // order of the local stages tried before going upstream
+    pub pipeline: Vec<StageKind>,
+    // seconds to wait on SIGINT/SIGTERM for queries in flight upstream
+    pub drain_timeout: u64,
+    // where the cache is saved on shutdown and restored from on startup
+    pub cache_snapshot_path: Option<String>,
+    // ids for forwarded queries, random unless a test needs them predictable
+    #[serde(skip)]
+    pub id_generator: IdGenerator,
+    // callbacks for embedders, set in code only
+    #[serde(skip)]
+    pub hooks: Hooks,
+    // NXDOMAIN upstream answers pointing at private addresses
+    pub rebind_protection: bool,
+    // internal domains still allowed to resolve to private addresses
+    pub rebind_allowlist: Vec<String>,
+    // log the EDNS client subnet of queries; this logs network-identifying data
+    pub log_client_subnet: bool,
+    // forward only one of several identical queries in flight at the same time
+    pub coalesce: bool,
+    // DSCP (0-63) to mark the relay's UDP traffic with
+    pub dscp: Option<u8>,
+    // SO_RCVBUF / SO_SNDBUF in bytes for the UDP sockets; OS defaults when unset
+    pub udp_recv_buffer: Option<usize>,
+    pub udp_send_buffer: Option<usize>,
+    // send a canary query to every upstream, logging how long each took to
+    // answer, and refuse to start unless at least one does
+    pub startup_check: bool,
+    // never contact upstream, queries not answered from the hosts or the
+    // cache get SERVFAIL, e.g. to replay clients against a cache snapshot
+    pub offline: bool,
+    // send DNS cookies upstream and drop responses echoing a wrong one
+    pub upstream_cookies: bool,
+    // where the control socket listens, e.g. 127.0.0.1:5380; off when unset
+    pub control_addr: Option<String>,
+    // upstreams out of service from the start; see the control socket
+    pub disabled_upstreams: Vec<String>,
+    // answer to queries when their upstream is disabled
+    pub disabled_rcode: u8,
+    // clear AD in upstream responses instead of vouching for data not validated here
+    pub clear_ad: bool,
+    // set CD in forwarded queries, so upstream validation failures are not SERVFAIL
+    pub set_cd: bool,
+    // lowercase the names of forwarded questions, for upstreams that mishandle
+    // mixed case; clients still get their question back as they cased it
+    pub lowercase_qnames: bool,
+    // answer queries for echo.relay.invalid with a TXT of the query as parsed, for debugging
+    pub echo_query: bool,
+    // compress the names in the rdata of local answers against the question and each other
+    pub compress_names: bool,
+    // spell out the owner names of local answers instead of pointing to the question,
+    // for clients that mishandle compression; overrides compress_names
+    pub disable_compression: bool,
+    // mark responses served from the cache, for clients that speak EDNS, with an
+    // empty EDNS option 65001 (from the local/experimental range)
+    pub mark_cached: bool,
+    // log every question received at info level, with target "query"
+    pub log_queries: bool,
+    // log a hash of each qname, keyed per process, instead of the name itself
+    pub log_hashed_names: bool,
+    // what to do with queries whose question section cannot be parsed
+    pub parse_failure: ParseFailurePolicy,
+    // answer the questions of a multi-question query that can be answered locally
+    // and forward only the others, instead of the whole query (see split.rs)
+    pub split_questions: bool,
+    // resolve only the names in allowlist_path, locally or upstream; the rest are NXDOMAIN
+    pub allowlist_mode: bool,
+    pub allowlist_path: Option<String>,
+    // per-device lists, mac=block:path or mac=allow:path, the device told by
+    // its IPv4 address in the ARP table; see device.rs
+    pub device_policies: Vec<DevicePolicy>,
+    // split-horizon rules, `<cidr> <name> <ip>` per line, ahead of the hosts
+    pub views_path: Option<String>,
+    // Rhai script deciding on the names nothing faster answers, see script.rs
+    pub script_path: Option<String>,
+    // ms a script may run per query before it is stopped and the query forwarded
+    pub script_timeout_ms: u64,
+    // qnames shared between queries for the most recently used names; 0 is
+    // off, as interning is slower than allocating, see intern.rs
+    pub intern_capacity: usize,
+    // UDP collector every received query is copied to, with its client address
+    pub mirror_addr: Option<String>,
+    // identical responses per second to one client network before they are
+    // sent truncated (RRL); no limit when unset
+    pub rrl_rate: Option<u32>,
+    // seconds the RRL rate is measured over
+    pub rrl_window: u64,
+    // client addresses sharing these prefixes count as one network for RRL
+    pub rrl_ipv4_prefix: u8,
+    pub rrl_ipv6_prefix: u8,
+    // what clients over the RRL rate get instead of the response
+    pub rrl_action: RrlAction,
+    // client networks with an RRL rate of their own, e.g. a NAT gateway
+    pub rrl_clients: Vec<RrlClient>,
+    // names, and the names under them, answered with TTL 0 so clients never cache them
+    pub no_cache_names: Vec<String>,
+    // source address of upstream traffic, replacing remote_addr, e.g. a VPN interface's
+    pub upstream_bind_addr: Option<String>,
+    // bind udp upstream sockets on the first query forwarded, not at startup,
+    // so a relay answering everything locally works without a network
+    pub lazy_upstream: bool,
+    // zones answered authoritatively: names in them missing locally are NXDOMAIN
+    pub authoritative_zones: Vec<String>,
+    // also answer authoritatively for every name with an SOA in the hosts
+    pub soa_zones: bool,
+    // reverse zones, e.g. 0.0.10.in-addr.arpa, answered authoritatively from
+    // the addresses in the hosts: PTR for mapped ones, NXDOMAIN for the rest
+    pub reverse_zones: Vec<String>,
+    // what to do with queries nothing local answers
+    pub outside_zone: OutsideZonePolicy,
+    // seconds between upstream health checks; off when unset
+    pub health_check_interval: Option<u64>,
+    // consecutive failed health checks before the upstream is taken out of service
+    pub health_check_failures: u32,
+    // where Prometheus metrics are served over HTTP; off when unset
+    pub metrics_addr: Option<String>,
+    // OpenMetrics, with a query's trace id per latency bucket, for scrapers asking for it
+    pub metrics_exemplars: bool,
+    // raw responses served as they are for these names and qtypes
+    pub static_responses: Vec<StaticResponse>,
+    // names whose address takes turns from a list, each for a period
+    pub schedules: Vec<Schedule>,
+    // regex=block|ip rules for names nothing else answers for, separated by
+    // whitespace as patterns may have commas
+    pub name_patterns: Vec<NamePattern>,
+    // random delay in ms, min and max, before answering blocked names, so that
+    // they take about as long as a real NXDOMAIN from upstream
+    pub blocked_delay: Option<(u64, u64)>,
+    // EDNS responses are padded to a multiple of this many bytes (RFC 7830),
+    // e.g. 468 (RFC 8467), but never beyond the size the client can receive
+    pub response_padding: Option<usize>,
+    // most answers in a response, the rest dropped and TC set
+    pub max_answer_records: Option<usize>,
+    // why names are blocked, sent in a TXT record in the additional section
+    // of their NXDOMAIN, e.g. "blocked by parental-controls list"
+    pub blocked_reason: Option<String>,
+    // the reasons for names blocked by the hosts, the name patterns and the
+    // device block lists, blocked_reason for those without one
+    pub hosts_blocked_reason: Option<String>,
+    pub pattern_blocked_reason: Option<String>,
+    pub device_blocked_reason: Option<String>,
+    // extra listeners, each with its own hosts and upstream, see profile.rs
+    pub profiles: Vec<Profile>,
 }
 
-impl Config {
-    pub fn from_env() -> Config {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NonRecursivePolicy {
+    /// NOERROR with no answers
+    Empty,
+    /// REFUSED
+    Refuse,
+    /// recurse anyway, like any other query
+    Forward,
+}
+
+impl std::str::FromStr for NonRecursivePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "empty" => Ok(NonRecursivePolicy::Empty),
+            "refuse" => Ok(NonRecursivePolicy::Refuse),
+            "forward" => Ok(NonRecursivePolicy::Forward),
+            _ => Err(anyhow::anyhow!("unknown non-recursive policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AaPolicy {
+    /// never, as a pure forwarder
+    Never,
+    /// for names in the authoritative zones, or with an SOA with soa_zones
+    Zones,
+    /// for every response built locally
+    Local,
+}
+
+impl std::str::FromStr for AaPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "never" => Ok(AaPolicy::Never),
+            "zones" => Ok(AaPolicy::Zones),
+            "local" => Ok(AaPolicy::Local),
+            _ => Err(anyhow::anyhow!("unknown aa policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownClassPolicy {
+    /// like any other query, the class copied into local answers
+    Forward,
+    /// REFUSED
+    Refuse,
+    /// the version for CHAOS TXT version.bind and version.server, REFUSED
+    /// for anything else
+    Chaos,
+}
+
+impl std::str::FromStr for UnknownClassPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "forward" => Ok(UnknownClassPolicy::Forward),
+            "refuse" => Ok(UnknownClassPolicy::Refuse),
+            "chaos" => Ok(UnknownClassPolicy::Chaos),
+            _ => Err(anyhow::anyhow!("unknown class policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutsideZonePolicy {
+    /// recurse through the upstream
+    Forward,
+    /// REFUSED, for authoritative-only deployments
+    Refuse,
+}
+
+impl std::str::FromStr for OutsideZonePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "forward" => Ok(OutsideZonePolicy::Forward),
+            "refuse" => Ok(OutsideZonePolicy::Refuse),
+            _ => Err(anyhow::anyhow!("unknown outside-zone policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParseFailurePolicy {
+    /// REFUSED with an empty question section
+    Refuse,
+    /// pass the raw query upstream and relay whatever it answers
+    Forward,
+}
+
+impl std::str::FromStr for ParseFailurePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "refuse" => Ok(ParseFailurePolicy::Refuse),
+            "forward" => Ok(ParseFailurePolicy::Forward),
+            _ => Err(anyhow::anyhow!("unknown parse failure policy: {}", s)),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
         Config {
-            local_addr: env::var("LOCAL_ADDR").unwrap_or("127.0.0.1:53".to_owned()),
-            remote_addr: env::var("REMOTE_ADDR").unwrap_or("0.0.0.0:10053".to_owned()),
-            upstream_addr: env::var("UPSTREAM_ADDR").unwrap_or("10.3.9.45:53".to_owned()),
-            hosts_path: env::var("HOSTS_PATH").unwrap_or("hosts.txt".to_owned()),
+            local_addr: "127.0.0.1:53".to_owned(),
+            remote_addr: "0.0.0.0:10053".to_owned(),
+            upstream_addr: "10.3.9.45:53".to_owned(),
+            upstream_transport: Transport::Udp,
+            tcp_pool_size: 2,
+            retry_base_ms: 100,
+            retry_cap_ms: 2000,
+            dnscrypt_stamp: None,
+            fallback_upstreams: Vec::new(),
+            retry_rcodes: Vec::new(),
+            max_upstream_attempts: 3,
+            named_upstreams: Vec::new(),
+            upstream_weights: Vec::new(),
+            hosts_path: "hosts.txt".to_owned(),
+            hosts_backend: BackendKind::File,
+            hosts_cache_path: None,
+            json_records_path: None,
+            dedup_records: true,
+            hosts_refresh_interval: None,
+            recursion_available: true,
+            badvers: true,
+            non_recursive: NonRecursivePolicy::Empty,
+            aa_policy: AaPolicy::Zones,
+            unknown_class: UnknownClassPolicy::Forward,
+            blackhole_qtypes: Vec::new(),
+            cache: false,
+            cache_size: 10_000,
+            pipeline: pipeline::DEFAULT_ORDER.to_vec(),
+            drain_timeout: 5,
+            cache_snapshot_path: None,
+            id_generator: IdGenerator::random(),
+            hooks: Hooks::default(),
+            rebind_protection: false,
+            rebind_allowlist: Vec::new(),
+            log_client_subnet: false,
+            coalesce: false,
+            dscp: None,
+            udp_recv_buffer: None,
+            udp_send_buffer: None,
+            startup_check: false,
+            offline: false,
+            upstream_cookies: false,
+            control_addr: None,
+            disabled_upstreams: Vec::new(),
+            disabled_rcode: 0b0010,
+            clear_ad: false,
+            set_cd: false,
+            lowercase_qnames: false,
+            echo_query: false,
+            log_queries: false,
+            log_hashed_names: false,
+            split_questions: false,
+            compress_names: false,
+            disable_compression: false,
+            mark_cached: false,
+            parse_failure: ParseFailurePolicy::Refuse,
+            allowlist_mode: false,
+            allowlist_path: None,
+            device_policies: Vec::new(),
+            views_path: None,
+            script_path: None,
+            script_timeout_ms: 10,
+            intern_capacity: 0,
+            mirror_addr: None,
+            rrl_rate: None,
+            rrl_window: 15,
+            rrl_ipv4_prefix: 24,
+            rrl_ipv6_prefix: 56,
+            rrl_action: RrlAction::Truncate,
+            rrl_clients: Vec::new(),
+            no_cache_names: Vec::new(),
+            upstream_bind_addr: None,
+            lazy_upstream: false,
+            authoritative_zones: Vec::new(),
+            soa_zones: false,
+            reverse_zones: Vec::new(),
+            outside_zone: OutsideZonePolicy::Forward,
+            health_check_interval: None,
+            health_check_failures: 3,
+            metrics_addr: None,
+            metrics_exemplars: false,
+            static_responses: Vec::new(),
+            schedules: Vec::new(),
+            name_patterns: Vec::new(),
+            blocked_delay: None,
+            response_padding: None,
+            max_answer_records: None,
+            blocked_reason: None,
+            hosts_blocked_reason: None,
+            pattern_blocked_reason: None,
+            device_blocked_reason: None,
+            profiles: Vec::new(),
         }
     }
 }
+
+impl Config {
+    pub fn from_env() -> anyhow::Result<Config> {
+        let default = Config::default();
+
+        Ok(Config {
+            local_addr: env::var("LOCAL_ADDR").unwrap_or(default.local_addr),
+            remote_addr: env::var("REMOTE_ADDR").unwrap_or(default.remote_addr),
+            upstream_addr: env::var("UPSTREAM_ADDR").unwrap_or(default.upstream_addr),
+            upstream_transport: env_parse("UPSTREAM_TRANSPORT", default.upstream_transport)?,
+            tcp_pool_size: env_parse("TCP_POOL_SIZE", default.tcp_pool_size)?,
+            retry_base_ms: env_parse("RETRY_BASE_MS", default.retry_base_ms)?,
+            retry_cap_ms: env_parse("RETRY_CAP_MS", default.retry_cap_ms)?,
+            dnscrypt_stamp: env::var("DNSCRYPT_STAMP").ok(),
+            fallback_upstreams: env_list("FALLBACK_UPSTREAMS", default.fallback_upstreams, |s| {
+                Ok(s.to_owned())
+            })?,
+            retry_rcodes: env_list("RETRY_RCODES", default.retry_rcodes, |s| Ok(s.parse()?))?,
+            max_upstream_attempts: env_parse(
+                "MAX_UPSTREAM_ATTEMPTS",
+                default.max_upstream_attempts,
+            )?,
+            named_upstreams: env_list("NAMED_UPSTREAMS", default.named_upstreams, |s| {
+                s.split_once('=')
+                    .map(|(name, addr)| (name.to_owned(), addr.to_owned()))
+                    .ok_or(anyhow::anyhow!("expected <name>=<address>: {}", s))
+            })?,
+            upstream_weights: env_list("UPSTREAM_WEIGHTS", default.upstream_weights, |s| {
+                let (addr, weight) = s
+                    .split_once('=')
+                    .ok_or(anyhow::anyhow!("expected <address>=<weight>: {}", s))?;
+                let weight: u32 = weight.parse()?;
+                anyhow::ensure!(weight > 0, "weights must be positive: {}", s);
+                Ok((addr.to_owned(), weight))
+            })?,
+            hosts_path: env::var("HOSTS_PATH").unwrap_or(default.hosts_path),
+            hosts_backend: env_parse("HOSTS_BACKEND", default.hosts_backend)?,
+            hosts_cache_path: env::var("HOSTS_CACHE_PATH").ok(),
+            json_records_path: env::var("JSON_RECORDS_PATH").ok(),
+            dedup_records: env_parse("DEDUP_RECORDS", default.dedup_records)?,
+            hosts_refresh_interval: env::var("HOSTS_REFRESH_INTERVAL")
+                .ok()
+                .map(|val| {
+                    val.parse()
+                        .ok()
+                        .filter(|secs| *secs > 0)
+                        .ok_or(anyhow::anyhow!(
+                            "invalid value for HOSTS_REFRESH_INTERVAL: {}",
+                            val
+                        ))
+                })
+                .transpose()?,
+            recursion_available: env_parse("RECURSION_AVAILABLE", default.recursion_available)?,
+            badvers: env_parse("BADVERS", default.badvers)?,
+            non_recursive: env_parse("NON_RECURSIVE_POLICY", default.non_recursive)?,
+            aa_policy: env_parse("AA_POLICY", default.aa_policy)?,
+            unknown_class: env_parse("UNKNOWN_CLASS_POLICY", default.unknown_class)?,
+            blackhole_qtypes: env_list("BLACKHOLE_QTYPES", default.blackhole_qtypes, parse_qtype)?,
+            cache: env_parse("CACHE", default.cache)?,
+            cache_size: env_parse("CACHE_SIZE", default.cache_size)?,
+            pipeline: env_list("PIPELINE", default.pipeline, str::parse)?,
+            drain_timeout: env_parse("DRAIN_TIMEOUT", default.drain_timeout)?,
+            cache_snapshot_path: env::var("CACHE_SNAPSHOT_PATH").ok(),
+            id_generator: default.id_generator,
+            hooks: default.hooks,
+            rebind_protection: env_parse("REBIND_PROTECTION", default.rebind_protection)?,
+            rebind_allowlist: env_list("REBIND_ALLOWLIST", default.rebind_allowlist, |s| {
+                Ok(s.to_owned())
+            })?,
+            log_client_subnet: env_parse("LOG_CLIENT_SUBNET", default.log_client_subnet)?,
+            coalesce: env_parse("COALESCE", default.coalesce)?,
+            dscp: match env::var("DSCP") {
+                Ok(val) => {
+                    let dscp = val
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|dscp| *dscp < 64)
+                        .ok_or(anyhow::anyhow!("invalid value for DSCP: {}", val))?;
+                    Some(dscp)
+                }
+                Err(_) => default.dscp,
+            },
+            udp_recv_buffer: env::var("UDP_RECV_BUFFER")
+                .ok()
+                .map(|val| {
+                    val.parse()
+                        .map_err(|e| anyhow::anyhow!("invalid value for UDP_RECV_BUFFER: {}", e))
+                })
+                .transpose()?,
+            udp_send_buffer: env::var("UDP_SEND_BUFFER")
+                .ok()
+                .map(|val| {
+                    val.parse()
+                        .map_err(|e| anyhow::anyhow!("invalid value for UDP_SEND_BUFFER: {}", e))
+                })
+                .transpose()?,
+            startup_check: env_parse("STARTUP_CHECK", default.startup_check)?,
+            offline: env_parse("OFFLINE", default.offline)?,
+            upstream_cookies: env_parse("UPSTREAM_COOKIES", default.upstream_cookies)?,
+            control_addr: env::var("CONTROL_ADDR").ok(),
+            disabled_upstreams: env_list("DISABLED_UPSTREAMS", default.disabled_upstreams, |s| {
+                Ok(s.to_owned())
+            })?,
+            disabled_rcode: env_parse("DISABLED_RCODE", default.disabled_rcode)?,
+            clear_ad: env_parse("CLEAR_AD", default.clear_ad)?,
+            set_cd: env_parse("SET_CD", default.set_cd)?,
+            lowercase_qnames: env_parse("LOWERCASE_QNAMES", default.lowercase_qnames)?,
+            echo_query: env_parse("ECHO_QUERY", default.echo_query)?,
+            log_queries: env_parse("LOG_QUERIES", default.log_queries)?,
+            log_hashed_names: env_parse("LOG_HASHED_NAMES", default.log_hashed_names)?,
+            split_questions: env_parse("SPLIT_QUESTIONS", default.split_questions)?,
+            compress_names: env_parse("COMPRESS_NAMES", default.compress_names)?,
+            disable_compression: env_parse("DISABLE_COMPRESSION", default.disable_compression)?,
+            mark_cached: env_parse("MARK_CACHED", default.mark_cached)?,
+            parse_failure: env_parse("PARSE_FAILURE_POLICY", default.parse_failure)?,
+            allowlist_mode: env_parse("ALLOWLIST_MODE", default.allowlist_mode)?,
+            allowlist_path: env::var("ALLOWLIST_PATH").ok(),
+            device_policies: env_list("DEVICE_POLICIES", default.device_policies, str::parse)?,
+            views_path: env::var("VIEWS_PATH").ok(),
+            script_path: env::var("SCRIPT_PATH").ok(),
+            script_timeout_ms: env_parse("SCRIPT_TIMEOUT_MS", default.script_timeout_ms)?,
+            intern_capacity: env_parse("INTERN_CAPACITY", default.intern_capacity)?,
+            mirror_addr: env::var("MIRROR_ADDR").ok(),
+            rrl_rate: env::var("RRL_RATE")
+                .ok()
+                .map(|val| {
+                    val.parse()
+                        .map_err(|e| anyhow::anyhow!("invalid value for RRL_RATE: {}", e))
+                })
+                .transpose()?,
+            rrl_window: env_parse("RRL_WINDOW", default.rrl_window)?,
+            rrl_ipv4_prefix: env_parse("RRL_IPV4_PREFIX", default.rrl_ipv4_prefix)?,
+            rrl_ipv6_prefix: env_parse("RRL_IPV6_PREFIX", default.rrl_ipv6_prefix)?,
+            rrl_action: env_parse("RRL_ACTION", default.rrl_action)?,
+            rrl_clients: env_list("RRL_CLIENTS", default.rrl_clients, str::parse)?,
+            no_cache_names: env_list("NO_CACHE_NAMES", default.no_cache_names, |s| {
+                Ok(s.to_owned())
+            })?,
+            upstream_bind_addr: env::var("UPSTREAM_BIND_ADDR").ok(),
+            lazy_upstream: env_parse("LAZY_UPSTREAM", default.lazy_upstream)?,
+            authoritative_zones: env_list(
+                "AUTHORITATIVE_ZONES",
+                default.authoritative_zones,
+                |s| Ok(s.to_owned()),
+            )?,
+            soa_zones: env_parse("SOA_ZONES", default.soa_zones)?,
+            reverse_zones: env_list("REVERSE_ZONES", default.reverse_zones, |s| Ok(s.to_owned()))?,
+            outside_zone: env_parse("OUTSIDE_ZONE_POLICY", default.outside_zone)?,
+            health_check_interval: env::var("HEALTH_CHECK_INTERVAL")
+                .ok()
+                .map(|val| {
+                    val.parse()
+                        .ok()
+                        .filter(|secs| *secs > 0)
+                        .ok_or(anyhow::anyhow!(
+                            "invalid value for HEALTH_CHECK_INTERVAL: {}",
+                            val
+                        ))
+                })
+                .transpose()?,
+            health_check_failures: env_parse(
+                "HEALTH_CHECK_FAILURES",
+                default.health_check_failures,
+            )?,
+            metrics_addr: env::var("METRICS_ADDR").ok(),
+            metrics_exemplars: env_parse("METRICS_EXEMPLARS", default.metrics_exemplars)?,
+            static_responses: env_list("STATIC_RESPONSES", default.static_responses, str::parse)?,
+            schedules: env_list("SCHEDULES", default.schedules, str::parse)?,
+            name_patterns: match env::var("NAME_PATTERNS") {
+                Ok(val) => val
+                    .split_whitespace()
+                    .map(|item| {
+                        item.parse()
+                            .map_err(|e| anyhow::anyhow!("invalid value for NAME_PATTERNS: {}", e))
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+                Err(_) => default.name_patterns,
+            },
+            blocked_delay: env::var("BLOCKED_DELAY_MS")
+                .ok()
+                .map(|val| {
+                    parse_delay_range(&val)
+                        .map_err(|e| anyhow::anyhow!("invalid value for BLOCKED_DELAY_MS: {}", e))
+                })
+                .transpose()?,
+            response_padding: env::var("RESPONSE_PADDING")
+                .ok()
+                .map(|val| {
+                    val.parse()
+                        .ok()
+                        .filter(|block| *block > 0)
+                        .ok_or(anyhow::anyhow!(
+                            "invalid value for RESPONSE_PADDING: {}",
+                            val
+                        ))
+                })
+                .transpose()?,
+            max_answer_records: env::var("MAX_ANSWER_RECORDS")
+                .ok()
+                .map(|val| {
+                    val.parse()
+                        .ok()
+                        .filter(|max| *max > 0)
+                        .ok_or(anyhow::anyhow!(
+                            "invalid value for MAX_ANSWER_RECORDS: {}",
+                            val
+                        ))
+                })
+                .transpose()?,
+            blocked_reason: env_reason("BLOCKED_REASON"),
+            hosts_blocked_reason: env_reason("HOSTS_BLOCKED_REASON"),
+            pattern_blocked_reason: env_reason("PATTERN_BLOCKED_REASON"),
+            device_blocked_reason: env_reason("DEVICE_BLOCKED_REASON"),
+            profiles: env_list("PROFILES", default.profiles, Profile::from_env)?,
+        })
+    }
+}
+
+/// The text of a TXT record telling why a name is blocked, if it has any.
+fn env_reason(key: &str) -> Option<String> {
+    env::var(key).ok().filter(|reason| !reason.is_empty())
+}
+
+fn env_list<T>(
+    key: &str,
+    default: Vec<T>,
+    parse: fn(&str) -> anyhow::Result<T>,
+) -> anyhow::Result<Vec<T>> {
+    match env::var(key) {
+        Ok(val) => val
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| {
+                parse(item).map_err(|e| anyhow::anyhow!("invalid value for {}: {}", key, e))
+            })
+            .collect(),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Parses a qtype given either by mnemonic (`AAAA`) or number (`28`).
+pub fn parse_qtype(s: &str) -> anyhow::Result<u16> {
+    let qtype = match s.to_ascii_uppercase().as_str() {
+        "A" => 1,
+        "NS" => 2,
+        "CNAME" => 5,
+        "SOA" => 6,
+        "PTR" => 12,
+        "MX" => 15,
+        "TXT" => 16,
+        "AAAA" => 28,
+        "SRV" => 33,
+        "ANY" => 255,
+        other => other
+            .trim_start_matches("TYPE")
+            .parse()
+            .map_err(|_| anyhow::anyhow!("unknown qtype: {}", s))?,
+    };
+    Ok(qtype)
+}
+
+/// Parses `min-max` or a single value, in milliseconds.
+fn parse_delay_range(s: &str) -> anyhow::Result<(u64, u64)> {
+    let (min, max) = s.split_once('-').unwrap_or((s, s));
+    let (min, max) = (min.trim().parse()?, max.trim().parse()?);
+    anyhow::ensure!(min <= max, "{} is an empty range", s);
+    anyhow::ensure!(max <= 10_000, "{} ms is longer than any client waits", max);
+    Ok((min, max))
+}
+
+fn env_parse<T>(key: &str, default: T) -> anyhow::Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match env::var(key) {
+        Ok(val) => val
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid value for {}: {}", key, e)),
+        Err(_) => Ok(default),
+    }
+}
@@ -0,0 +1,222 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{sync::Mutex, time::timeout};
+use tracing::debug;
+
+use crate::{
+    backend,
+    cache::{Cache, CacheKey},
+    connect_upstream,
+    control::Overrides,
+    packet::{self, QuestionEntry, RData},
+    pipeline::{BlockReasons, Pipeline, Response},
+    responses,
+    upstream::{Resolver, Upstream},
+    Config, BUF_SIZE,
+};
+
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    A,
+    Aaaa,
+}
+
+impl RecordType {
+    fn code(self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Aaaa => 28,
+        }
+    }
+}
+
+/// The relay's resolution (local stages, then upstream) as a library API,
+/// independent of [`crate::run`]. It has its own upstream socket, so
+/// `remote_addr` must not clash with a relay running in the same process.
+pub struct Relay {
+    upstream: Upstream,
+    pipeline: Pipeline,
+    cache: Option<Arc<Cache>>,
+    // one upstream exchange at a time, so responses cannot be mixed up
+    exchange: Mutex<()>,
+}
+
+impl Relay {
+    pub async fn new(config: &Config) -> anyhow::Result<Self> {
+        let upstream = connect_upstream(config, &config.upstream_addr).await?;
+        let (hosts, _) = backend::open(
+            config.hosts_backend,
+            &config.hosts_path,
+            config.hosts_cache_path.as_deref(),
+            config.json_records_path.as_deref(),
+            config.dedup_records,
+        )
+        .await?;
+        let cache = config
+            .cache
+            .then(|| Arc::new(Cache::new(config.cache_size)));
+        let responses = responses::load(&config.static_responses)?;
+        let pipeline = Pipeline::new(
+            &config.pipeline,
+            responses,
+            &config.blackhole_qtypes,
+            hosts,
+            Overrides::default(),
+            Arc::default(),
+            &config.schedules,
+            &config.name_patterns,
+            BlockReasons::default(),
+            &[
+                config.authoritative_zones.clone(),
+                config.reverse_zones.clone(),
+            ]
+            .concat(),
+            config.soa_zones,
+            false,
+            cache.clone(),
+            None,
+        );
+
+        Ok(Self {
+            upstream,
+            pipeline,
+            cache,
+            exchange: Mutex::new(()),
+        })
+    }
+
+    /// Resolves `name` to its addresses. Names that do not exist resolve to
+    /// no addresses; any other failure rcode is an error.
+    pub async fn lookup(&self, name: &str, rtype: RecordType) -> anyhow::Result<Vec<IpAddr>> {
+        let mut query = packet::build_query(rand::random(), name, rtype.code());
+        let len = query.len();
+        let msg = packet::Message::new(&mut query, len);
+        let questions = msg
+            .question
+            .entries(msg.header.get_qdcount())
+            .map_err(|e| anyhow::anyhow!("invalid name {}: {}", name, e))?;
+
+        let resp = match self.pipeline.resolve(&questions, None) {
+            Some((stage, Response::Records(rrs))) => {
+                debug!("{} answered by {}", name, stage);
+                return Ok(rrs
+                    .into_iter()
+                    .filter(|rr| rr.rtype == rtype.code())
+                    .filter_map(|rr| match rr.rdata {
+                        RData::V4(octets) => Some(IpAddr::V4(Ipv4Addr::from(octets))),
+                        RData::V6(octets) => Some(IpAddr::V6(Ipv6Addr::from(octets))),
+                        RData::Opaque(_)
+                        | RData::Dname(_)
+                        | RData::Raw { .. }
+                        | RData::Tlsa { .. }
+                        | RData::Naptr { .. }
+                        | RData::Svcb { .. } => None,
+                    })
+                    .collect());
+            }
+            Some((stage, Response::NoData(_) | Response::Partial { .. })) => {
+                debug!("{} answered by {} with no data", name, stage);
+                return Ok(Vec::new());
+            }
+            // the aliases lead to the target's addresses, all that is returned
+            Some((stage, Response::Chase { target, .. })) => {
+                let target = packet::decode_name(&target)
+                    .ok_or(anyhow::anyhow!("invalid alias target for {}", name))?;
+                debug!(
+                    "{} is an alias to {} by {}, asking upstream",
+                    name, target, stage
+                );
+                let mut query = packet::build_query(rand::random(), &target, rtype.code());
+                let len = query.len();
+                let msg = packet::Message::new(&mut query, len);
+                let questions = msg
+                    .question
+                    .entries(msg.header.get_qdcount())
+                    .map_err(|e| anyhow::anyhow!("invalid name {}: {}", target, e))?;
+                self.forward(&query[..len], &questions).await?
+            }
+            Some((stage, Response::Rcode(rcode))) => {
+                debug!("{} answered by {} with rcode {}", name, stage, rcode);
+                return check_rcode(name, rcode).map(|_| Vec::new());
+            }
+            Some((stage, Response::Blocked(_))) => {
+                debug!("{} is blocked by {}", name, stage);
+                return Ok(Vec::new());
+            }
+            Some((stage, Response::Message(resp))) => {
+                debug!("{} answered by {}", name, stage);
+                resp
+            }
+            None => self.forward(&query[..len], &questions).await?,
+        };
+
+        check_rcode(name, resp[3] & 0b0000_1111)?;
+        addrs(&resp, rtype)
+    }
+
+    /// Upstream's response to `query`, cached under its question unless
+    /// truncated.
+    async fn forward(&self, query: &[u8], questions: &[QuestionEntry]) -> anyhow::Result<Vec<u8>> {
+        let resp = self.exchange(query).await?;
+        if let (Some(cache), [q]) = (&self.cache, questions) {
+            if resp[2] & 0b0000_0010 == 0 {
+                cache.insert(CacheKey::from(q), &resp);
+            }
+        }
+        Ok(resp)
+    }
+
+    async fn exchange(&self, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let _guard = self.exchange.lock().await;
+        self.upstream.send(query).await?;
+
+        let mut buf = [0u8; BUF_SIZE];
+        timeout(LOOKUP_TIMEOUT, async {
+            loop {
+                let len = self.upstream.recv(&mut buf).await?;
+                if len >= 12 && buf[0..2] == query[0..2] {
+                    return Ok(buf[..len].to_vec());
+                }
+                debug!("dropping a response to another query");
+            }
+        })
+        .await
+        .map_err(|_| anyhow::anyhow!("no response from upstream {}", self.upstream.addr()))?
+    }
+}
+
+fn check_rcode(name: &str, rcode: u8) -> anyhow::Result<()> {
+    match rcode {
+        0b0000 | 0b0011 => Ok(()),
+        _ => Err(anyhow::anyhow!(
+            "lookup of {} failed with rcode {}",
+            name,
+            rcode
+        )),
+    }
+}
+
+/// Addresses of the given type in the answer section of `resp`.
+fn addrs(resp: &[u8], rtype: RecordType) -> anyhow::Result<Vec<IpAddr>> {
+    let ancount = u16::from_be_bytes([resp[6], resp[7]]) as usize;
+    let records = packet::records(resp).ok_or(anyhow::anyhow!("malformed response"))?;
+
+    Ok(records
+        .iter()
+        .take(ancount)
+        .filter(|rr| rr.rtype == rtype.code())
+        .filter_map(|rr| {
+            let rdata = &resp[rr.rdata..rr.end];
+            match rtype {
+                RecordType::A => <[u8; 4]>::try_from(rdata).ok().map(IpAddr::from),
+                RecordType::Aaaa => <[u8; 16]>::try_from(rdata).ok().map(IpAddr::from),
+            }
+        })
+        .collect())
+}
@@ -5,6 +5,9 @@ use tracing::info;
 struct Cli {
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
+    /// Validate the hosts file and JSON records and exit, without starting the relay
+    #[arg(long)]
+    check_hosts: bool,
 }
 
 #[tokio::main]
@@ -19,8 +22,27 @@ async fn main() -> anyhow::Result<()> {
 
     tracing_subscriber::fmt().with_max_level(level).init();
 
-    let config = mini_dns_relay::Config::from_env();
+    let config = mini_dns_relay::Config::from_env()?;
     info!("config: {:?}", config);
 
+    if cli.check_hosts {
+        let (hosts, report) = mini_dns_relay::check_hosts(&config)?;
+        for error in &report.errors {
+            eprintln!("error: {}", error);
+        }
+        for warning in &report.warnings {
+            eprintln!("warning: {}", warning);
+        }
+        println!(
+            "{}: {} entries, {} errors, {} warnings",
+            config.hosts_path,
+            hosts.entries(),
+            report.errors.len(),
+            report.warnings.len()
+        );
+        anyhow::ensure!(report.errors.is_empty(), "the hosts are invalid");
+        return Ok(());
+    }
+
     mini_dns_relay::run(config).await
 }
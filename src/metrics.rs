@@ -0,0 +1,383 @@
+//! Prometheus metrics, served as plain text over a bare-bones HTTP endpoint,
+//! which also serves the status as JSON on `/status`.
+//!
+//! With exemplars on, scrapers asking for OpenMetrics get the format instead,
+//! each upstream latency bucket with the trace id of the last query that fell
+//! in it: the `trace_id` field of that query's `forward` and `reply` spans.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use tracing::{debug, info};
+
+use crate::{cache::Cache, health::Health, status::Status};
+
+// upper bounds of the answer count buckets, the last one catches the rest
+const ANSWER_BUCKETS: [u64; 3] = [0, 1, 4];
+// upper bounds of the upstream latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+#[derive(Debug, Clone, Copy)]
+pub enum Source {
+    Local,
+    Forwarded,
+}
+
+impl Source {
+    fn label(self) -> &'static str {
+        match self {
+            Source::Local => "local",
+            Source::Forwarded => "forwarded",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    // non-cumulative, one per bucket plus +Inf
+    buckets: [AtomicU64; ANSWER_BUCKETS.len() + 1],
+    sum: AtomicU64,
+}
+
+/// (trace id, latency in seconds, unix time) of the last query in a bucket.
+type Exemplar = (u64, f64, f64);
+
+#[derive(Default)]
+struct LatencyHistogram {
+    // non-cumulative, one per bucket plus +Inf
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+    exemplars: Mutex<[Option<Exemplar>; LATENCY_BUCKETS.len() + 1]>,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    pub rebind_filtered: AtomicU64,
+    pub question_mismatches: AtomicU64,
+    pub responses_dropped: AtomicU64,
+    local_answers: Histogram,
+    forwarded_answers: Histogram,
+    upstream_latency: Mutex<BTreeMap<Arc<str>, LatencyHistogram>>,
+    upstream_queries: Mutex<BTreeMap<Arc<str>, u64>>,
+    exemplars: bool,
+}
+
+impl Metrics {
+    pub fn new(exemplars: bool) -> Self {
+        Self {
+            exemplars,
+            ..Self::default()
+        }
+    }
+
+    /// Records how long `upstream` took to answer the query traced as
+    /// `trace_id`.
+    pub fn observe_latency(&self, upstream: Arc<str>, latency: Duration, trace_id: u64) {
+        let mut histograms = self.upstream_latency.lock().unwrap();
+        let histogram = histograms.entry(upstream).or_default();
+        let secs = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&le| secs <= le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        histogram.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        histogram
+            .sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+
+        if self.exemplars {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            histogram.exemplars.lock().unwrap()[bucket] = Some((trace_id, secs, now));
+        }
+    }
+
+    /// Records a query sent to `upstream`.
+    pub fn count_upstream_query(&self, upstream: Arc<str>) {
+        *self
+            .upstream_queries
+            .lock()
+            .unwrap()
+            .entry(upstream)
+            .or_default() += 1;
+    }
+
+    /// Whether a scraper sending `accept` gets OpenMetrics.
+    pub fn openmetrics(&self, accept: Option<&str>) -> bool {
+        self.exemplars
+            && accept.is_some_and(|accept| accept.contains("application/openmetrics-text"))
+    }
+
+    /// Records how many answer records a response sent to a client holds.
+    pub fn observe_answers(&self, source: Source, count: u16) {
+        let histogram = self.histogram(source);
+        let count = count as u64;
+        let bucket = ANSWER_BUCKETS
+            .iter()
+            .position(|&le| count <= le)
+            .unwrap_or(ANSWER_BUCKETS.len());
+        histogram.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        histogram.sum.fetch_add(count, Ordering::Relaxed);
+    }
+
+    fn histogram(&self, source: Source) -> &Histogram {
+        match source {
+            Source::Local => &self.local_answers,
+            Source::Forwarded => &self.forwarded_answers,
+        }
+    }
+
+    /// The metrics as Prometheus text, or as OpenMetrics with exemplars.
+    pub fn render(&self, health: &Health, cache: Option<&Cache>, openmetrics: bool) -> String {
+        let mut out = String::new();
+        // OpenMetrics names a counter family without the suffix of its sample
+        let total = if openmetrics { "" } else { "_total" };
+
+        let _ = writeln!(
+            out,
+            "# HELP dns_relay_rebind_filtered{} Upstream responses replaced for pointing at private addresses.",
+            total
+        );
+        let _ = writeln!(out, "# TYPE dns_relay_rebind_filtered{} counter", total);
+        let _ = writeln!(
+            out,
+            "dns_relay_rebind_filtered_total {}",
+            self.rebind_filtered.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP dns_relay_question_mismatches{} Upstream responses dropped for not echoing the question asked.",
+            total
+        );
+        let _ = writeln!(out, "# TYPE dns_relay_question_mismatches{} counter", total);
+        let _ = writeln!(
+            out,
+            "dns_relay_question_mismatches_total {}",
+            self.question_mismatches.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP dns_relay_responses_dropped{} Responses, not queries, received from clients and dropped.",
+            total
+        );
+        let _ = writeln!(out, "# TYPE dns_relay_responses_dropped{} counter", total);
+        let _ = writeln!(
+            out,
+            "dns_relay_responses_dropped_total {}",
+            self.responses_dropped.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP dns_relay_answer_records Answer records per response sent to clients."
+        );
+        let _ = writeln!(out, "# TYPE dns_relay_answer_records histogram");
+        for source in [Source::Local, Source::Forwarded] {
+            let histogram = self.histogram(source);
+            let mut cumulative = 0;
+            for (i, bucket) in histogram.buckets.iter().enumerate() {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let le = ANSWER_BUCKETS
+                    .get(i)
+                    .map_or("+Inf".to_owned(), |le| le.to_string());
+                let _ = writeln!(
+                    out,
+                    "dns_relay_answer_records_bucket{{source=\"{}\",le=\"{}\"}} {}",
+                    source.label(),
+                    le,
+                    cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "dns_relay_answer_records_sum{{source=\"{}\"}} {}",
+                source.label(),
+                histogram.sum.load(Ordering::Relaxed)
+            );
+            let _ = writeln!(
+                out,
+                "dns_relay_answer_records_count{{source=\"{}\"}} {}",
+                source.label(),
+                cumulative
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP dns_relay_upstream_latency_seconds Time each upstream took to answer forwarded queries."
+        );
+        let _ = writeln!(out, "# TYPE dns_relay_upstream_latency_seconds histogram");
+        for (addr, histogram) in self.upstream_latency.lock().unwrap().iter() {
+            let exemplars = *histogram.exemplars.lock().unwrap();
+            let mut cumulative = 0;
+            for (i, bucket) in histogram.buckets.iter().enumerate() {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let le = LATENCY_BUCKETS
+                    .get(i)
+                    .map_or("+Inf".to_owned(), |le| le.to_string());
+                let _ = write!(
+                    out,
+                    "dns_relay_upstream_latency_seconds_bucket{{upstream=\"{}\",le=\"{}\"}} {}",
+                    addr, le, cumulative
+                );
+                if let Some((trace_id, secs, at)) = exemplars[i].filter(|_| openmetrics) {
+                    let _ = write!(
+                        out,
+                        " # {{trace_id=\"{:016x}\"}} {} {:.3}",
+                        trace_id, secs, at
+                    );
+                }
+                let _ = writeln!(out);
+            }
+            let _ = writeln!(
+                out,
+                "dns_relay_upstream_latency_seconds_sum{{upstream=\"{}\"}} {}",
+                addr,
+                histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+            );
+            let _ = writeln!(
+                out,
+                "dns_relay_upstream_latency_seconds_count{{upstream=\"{}\"}} {}",
+                addr, cumulative
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP dns_relay_upstream_healthy Whether the upstream passes its health checks."
+        );
+        let _ = writeln!(out, "# TYPE dns_relay_upstream_healthy gauge");
+        for (addr, state) in health.states() {
+            let _ = writeln!(
+                out,
+                "dns_relay_upstream_healthy{{upstream=\"{}\"}} {}",
+                addr, state.healthy as u8
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP dns_relay_upstream_queries{} Queries sent to each upstream, retries included.",
+            total
+        );
+        let _ = writeln!(out, "# TYPE dns_relay_upstream_queries{} counter", total);
+        for (addr, count) in self.upstream_queries.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "dns_relay_upstream_queries_total{{upstream=\"{}\"}} {}",
+                addr, count
+            );
+        }
+
+        if let Some(cache) = cache {
+            let _ = writeln!(
+                out,
+                "# HELP dns_relay_cache_entries Responses in the cache, expired ones not yet dropped included."
+            );
+            let _ = writeln!(out, "# TYPE dns_relay_cache_entries gauge");
+            let _ = writeln!(out, "dns_relay_cache_entries {}", cache.len());
+            let _ = writeln!(
+                out,
+                "# HELP dns_relay_cache_bytes Approximate memory used by cached responses and their keys, in bytes."
+            );
+            let _ = writeln!(out, "# TYPE dns_relay_cache_bytes gauge");
+            let _ = writeln!(out, "dns_relay_cache_bytes {}", cache.bytes());
+            let _ = writeln!(
+                out,
+                "# HELP dns_relay_cache_evictions{} Cached responses evicted to make room for new ones.",
+                total
+            );
+            let _ = writeln!(out, "# TYPE dns_relay_cache_evictions{} counter", total);
+            let _ = writeln!(out, "dns_relay_cache_evictions_total {}", cache.evictions());
+        }
+
+        if openmetrics {
+            let _ = writeln!(out, "# EOF");
+        }
+        out
+    }
+}
+
+/// Answers HTTP requests for `/status` with the status, `/healthz` and
+/// `/readyz` with 200 or 503 for orchestrators, every other one with the
+/// current metrics.
+///
+/// The relay is live as long as this answers at all: the hosts are loaded
+/// and the event loops started before it is, and the process exits if one
+/// fails. It is ready unless every upstream fails its health checks.
+pub async fn serve(
+    addr: &str,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    status: Arc<Status>,
+    cache: Option<Arc<Cache>>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("metrics are served on http://{}/metrics", addr);
+
+    loop {
+        let (mut stream, peer) = listener.accept().await?;
+        let metrics = metrics.clone();
+        let health = health.clone();
+        let status = status.clone();
+        let cache = cache.clone();
+
+        tokio::spawn(async move {
+            // only the path of the request line and the Accept header matter
+            let mut request = [0u8; 1024];
+            let len = stream.read(&mut request).await.unwrap_or(0);
+            let request = std::str::from_utf8(&request[..len]).unwrap_or_default();
+            let path = request.split_whitespace().nth(1).unwrap_or("/");
+            let accept = request.lines().find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("accept").then_some(value)
+            });
+
+            let (code, content_type, body) = match path {
+                "/status" => ("200 OK", "application/json", status.render()),
+                "/healthz" => ("200 OK", "text/plain", "ok\n".to_owned()),
+                "/readyz" if health.any_healthy() => ("200 OK", "text/plain", "ok\n".to_owned()),
+                "/readyz" => (
+                    "503 Service Unavailable",
+                    "text/plain",
+                    "no healthy upstream\n".to_owned(),
+                ),
+                _ if metrics.openmetrics(accept) => (
+                    "200 OK",
+                    "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                    metrics.render(&health, cache.as_deref(), true),
+                ),
+                _ => (
+                    "200 OK",
+                    "text/plain; version=0.0.4",
+                    metrics.render(&health, cache.as_deref(), false),
+                ),
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                code,
+                content_type,
+                body.len(),
+                body
+            );
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                debug!("failed to send metrics to {}: {}", peer, e);
+            }
+        });
+    }
+}
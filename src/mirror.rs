@@ -0,0 +1,55 @@
+//! Copies of every query received, sent to a collector for monitoring.
+//!
+//! Each datagram is the client address followed by the query exactly as
+//! received: the address family (4 or 6), the address, the port in network
+//! order, then the query bytes.
+
+use std::net::SocketAddr;
+
+use tokio::net::UdpSocket;
+use tracing::{info, trace};
+
+pub struct Mirror {
+    sock: UdpSocket,
+    collector: SocketAddr,
+}
+
+impl Mirror {
+    pub async fn new(addr: &str) -> anyhow::Result<Self> {
+        let collector = tokio::net::lookup_host(addr)
+            .await?
+            .next()
+            .ok_or(anyhow::anyhow!("no address for mirror {}", addr))?;
+        let local = if collector.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let sock = UdpSocket::bind(local).await?;
+        info!("mirroring queries to {}", collector);
+
+        Ok(Self { sock, collector })
+    }
+
+    /// Never waits and never fails: a copy that cannot be sent right away is
+    /// lost, without affecting the query.
+    pub fn send(&self, client: SocketAddr, query: &[u8]) {
+        let mut datagram = Vec::with_capacity(19 + query.len());
+        match client {
+            SocketAddr::V4(v4) => {
+                datagram.push(4);
+                datagram.extend_from_slice(&v4.ip().octets());
+            }
+            SocketAddr::V6(v6) => {
+                datagram.push(6);
+                datagram.extend_from_slice(&v6.ip().octets());
+            }
+        }
+        datagram.extend_from_slice(&client.port().to_be_bytes());
+        datagram.extend_from_slice(query);
+
+        if let Err(e) = self.sock.try_send_to(&datagram, self.collector) {
+            trace!("query from {} not mirrored: {}", client, e);
+        }
+    }
+}
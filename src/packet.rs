@@ -1,13 +1,53 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    ops::Range,
+    sync::Arc,
+};
+
+use crate::intern::Interner;
+
+pub const OPT: u16 = 41;
+pub const EDNS_CLIENT_SUBNET: u16 = 8;
+pub const PADDING: u16 = 12;
+pub const EXTENDED_DNS_ERROR: u16 = 15;
+pub const EDE_PROHIBITED: u16 = 18;
+/// extended rcode for an unsupported EDNS version (RFC 6891 9)
+pub const BADVERS: u16 = 16;
+/// extended rcode for a missing or invalid server cookie (RFC 7873 8)
+pub const BADCOOKIE: u16 = 23;
+/// EDNS option, from the local/experimental range (RFC 6891 9), with no data:
+/// the response was served from the relay's cache.
+pub const CACHED: u16 = 65001;
+
 pub struct Message<'a> {
     pub header: Header<'a>,
     pub question: Question<'a>,
     pub answer: Answer<'a>,
 }
 
+#[allow(clippy::len_without_is_empty)]
 impl<'a> Message<'a> {
+    /// Splits the first `len` bytes of `buf` into header, question section
+    /// and whatever follows it. A question section that cannot be walked
+    /// takes up the rest.
     pub fn new(buf: &'a mut [u8], len: usize) -> Self {
+        let qdcount = u16::from_be_bytes([buf[4], buf[5]]);
         let (header, buf) = buf.split_at_mut(12);
-        let (question, answer) = buf.split_at_mut(len - 12);
+
+        let mut question_len = 0;
+        for _ in 0..qdcount {
+            match skip_name(&buf[..len - 12], question_len) {
+                Some(end) if end + 4 <= len - 12 => question_len = end + 4,
+                _ => {
+                    question_len = len - 12;
+                    break;
+                }
+            }
+        }
+        let (question, answer) = buf.split_at_mut(question_len);
+        let question: &[u8] = question;
 
         Self {
             header: Header {
@@ -16,11 +56,12 @@ impl<'a> Message<'a> {
             },
             question: Question {
                 buf: question,
-                len: len - 12,
+                len: question_len,
             },
             answer: Answer {
                 buf: answer,
-                len: 0,
+                len: len - 12 - question_len,
+                question,
             },
         }
     }
@@ -28,6 +69,19 @@ impl<'a> Message<'a> {
     pub fn len(&self) -> usize {
         self.header.len + self.question.len + self.answer.len
     }
+
+    /// Turns the message into a response carrying nothing but the question
+    /// and `rcode`, returning its length. Anything that followed the question
+    /// in the query (e.g. an OPT record) is left out.
+    pub fn make_empty_response(&mut self, rcode: u8) -> usize {
+        self.header.set_qr(0b1);
+        self.header.set_rcode(rcode);
+        self.header.set_ancount(0);
+        self.header.set_nscount(0);
+        self.header.set_arcount(0);
+
+        self.header.len + self.question.section_len(self.header.get_qdcount())
+    }
 }
 
 pub struct Header<'a> {
@@ -44,18 +98,78 @@ impl Header<'_> {
         u16::from_be_bytes([self.buf[4], self.buf[5]])
     }
 
+    pub fn set_qdcount(&mut self, qdcount: u16) {
+        self.buf[4..6].copy_from_slice(&qdcount.to_be_bytes());
+    }
+
     pub fn set_id(&mut self, id: u16) {
         self.buf[0..2].copy_from_slice(&id.to_be_bytes());
     }
 
+    pub fn get_qr(&self) -> u8 {
+        self.buf[2] >> 7
+    }
+
     pub fn set_qr(&mut self, qr: u8) {
         self.buf[2] = (self.buf[2] & 0b0111_1111) | (qr << 7);
     }
 
+    pub fn get_aa(&self) -> u8 {
+        (self.buf[2] >> 2) & 0b0000_0001
+    }
+
+    pub fn set_aa(&mut self, aa: u8) {
+        self.buf[2] = (self.buf[2] & 0b1111_1011) | (aa << 2);
+    }
+
+    pub fn get_tc(&self) -> u8 {
+        (self.buf[2] >> 1) & 0b0000_0001
+    }
+
+    pub fn set_tc(&mut self, tc: u8) {
+        self.buf[2] = (self.buf[2] & 0b1111_1101) | (tc << 1);
+    }
+
+    pub fn get_rd(&self) -> u8 {
+        self.buf[2] & 0b0000_0001
+    }
+
+    pub fn get_ra(&self) -> u8 {
+        self.buf[3] >> 7
+    }
+
+    pub fn set_ra(&mut self, ra: u8) {
+        self.buf[3] = (self.buf[3] & 0b0111_1111) | (ra << 7);
+    }
+
+    pub fn get_ad(&self) -> u8 {
+        (self.buf[3] >> 5) & 0b0000_0001
+    }
+
+    pub fn set_ad(&mut self, ad: u8) {
+        self.buf[3] = (self.buf[3] & 0b1101_1111) | (ad << 5);
+    }
+
+    pub fn get_cd(&self) -> u8 {
+        (self.buf[3] >> 4) & 0b0000_0001
+    }
+
+    pub fn set_cd(&mut self, cd: u8) {
+        self.buf[3] = (self.buf[3] & 0b1110_1111) | (cd << 4);
+    }
+
+    pub fn get_rcode(&self) -> u8 {
+        self.buf[3] & 0b0000_1111
+    }
+
     pub fn set_rcode(&mut self, rcode: u8) {
         self.buf[3] = (self.buf[3] & 0b1111_0000) | rcode;
     }
 
+    pub fn get_ancount(&self) -> u16 {
+        u16::from_be_bytes([self.buf[6], self.buf[7]])
+    }
+
     pub fn set_ancount(&mut self, ancount: u16) {
         self.buf[6..8].copy_from_slice(&ancount.to_be_bytes());
     }
@@ -69,57 +183,202 @@ impl Header<'_> {
     }
 }
 
+/// Why and where a question section could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// from the start of the message
+    pub offset: usize,
+    pub reason: &'static str,
+    /// up to 16 bytes from `offset`, for tracing
+    pub bytes: Vec<u8>,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {}", self.reason, self.offset)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
 pub struct Question<'a> {
     buf: &'a [u8],
     len: usize,
 }
 
+#[allow(clippy::len_without_is_empty)]
 impl Question<'_> {
-    pub fn entries(&self, qdcount: u16) -> Vec<QuestionEntry> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Length in bytes of the first `qdcount` entries.
+    pub fn section_len(&self, qdcount: u16) -> usize {
+        let mut i = 0;
+        for _ in 0..qdcount {
+            i = skip_name(self.buf, i).map_or(self.len, |end| end + 4);
+        }
+        i.min(self.len)
+    }
+
+    /// Parses the first `qdcount` entries. Fails if they run past the
+    /// message, use compression or have a name that is too long or not
+    /// UTF-8.
+    pub fn entries(&self, qdcount: u16) -> Result<Vec<QuestionEntry>, ParseError> {
+        self.parse(qdcount, |name| Arc::from(name))
+    }
+
+    /// Like [`Question::entries`], with names shared through `interner`.
+    pub fn interned_entries(
+        &self,
+        qdcount: u16,
+        interner: &Interner,
+    ) -> Result<Vec<QuestionEntry>, ParseError> {
+        self.parse(qdcount, |name| interner.intern(name))
+    }
+
+    fn parse(
+        &self,
+        qdcount: u16,
+        intern: impl Fn(&str) -> Arc<str>,
+    ) -> Result<Vec<QuestionEntry>, ParseError> {
+        let buf = &self.buf[..self.len];
+        let error = |at: usize, reason| ParseError {
+            offset: 12 + at,
+            reason,
+            bytes: buf[at.min(buf.len())..(at + 16).min(buf.len())].to_vec(),
+        };
         let mut entries = Vec::new();
         let mut i = 0;
 
         for _ in 0..qdcount {
             let offset = 12 + i; // offset is calculated for later use, stored in QuestionEntry
 
-            let mut qname = String::new();
+            // labels each followed by a '.', at most 255 bytes on the wire
+            let mut qname = [0u8; 254];
+            let mut end: usize = 0;
             loop {
-                let len = self.buf[i] as usize;
+                let len = *buf
+                    .get(i)
+                    .ok_or_else(|| error(i, "name runs past the end of the message"))?
+                    as usize;
                 if len == 0 {
-                    qname.pop(); // remove the last '.'
+                    // without the last '.'; each label was checked to be UTF-8
+                    let qname = std::str::from_utf8(&qname[..end.saturating_sub(1)])
+                        .map_err(|_| error(i, "name is not UTF-8"))?;
 
                     i += 1; // finish reading qname, start reading qtype and qclass
+                    let fixed = buf.get(i..i + 4).ok_or_else(|| {
+                        error(i, "qtype and qclass run past the end of the message")
+                    })?;
                     entries.push(QuestionEntry {
                         offset,
-                        qname,
-                        qtype: u16::from_be_bytes([self.buf[i], self.buf[i + 1]]),
-                        qclass: u16::from_be_bytes([self.buf[i + 2], self.buf[i + 3]]),
+                        qname: intern(qname),
+                        qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+                        qclass: u16::from_be_bytes([fixed[2], fixed[3]]),
                     });
 
                     i += 4; // enter the next round
                     break;
                 }
-                qname.push_str(std::str::from_utf8(&self.buf[i + 1..=i + len]).unwrap());
-                qname.push('.');
+                if len & 0b1100_0000 == 0b1100_0000 {
+                    return Err(error(i, "compression pointer in the question"));
+                }
+                if len > 63 {
+                    return Err(error(i, "label length over 63"));
+                }
+                if end + len + 1 > qname.len() {
+                    return Err(error(i, "name longer than 255 bytes"));
+                }
+                let label = buf
+                    .get(i + 1..=i + len)
+                    .ok_or_else(|| error(i, "label length exceeds buffer"))?;
+                std::str::from_utf8(label).map_err(|_| error(i + 1, "label is not UTF-8"))?;
+                qname[end..end + len].copy_from_slice(label);
+                qname[end + len] = b'.';
+                end += len + 1;
 
                 i += len + 1;
             }
         }
 
-        entries
+        Ok(entries)
     }
 }
 
 pub struct Answer<'a> {
     buf: &'a mut [u8],
     len: usize,
+    // what precedes the answer, after the header
+    question: &'a [u8],
 }
 
+#[allow(clippy::len_without_is_empty)]
 impl Answer<'_> {
-    pub fn add_entries(&mut self, entries: Vec<ResourceRecord>) {
-        for rr in entries {
-            self.buf[self.len..self.len + 2].copy_from_slice(&rr.name.to_be_bytes());
-            self.len += 2;
+    /// Length of everything after the question, including added records.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Appends as many of the records as fit in the buffer, returning how many
+    /// were written.
+    pub fn add_entries(&mut self, entries: Vec<ResourceRecord>, compression: Compression) -> u16 {
+        let question = self.question;
+        let mut names = (compression == Compression::All).then(|| {
+            let mut names = Names::default();
+            let mut i = 0;
+            while let Some(end) = skip_name(question, i) {
+                names.compress(&question[i..end], 12 + i);
+                i = end + 4;
+            }
+            names
+        });
+
+        let mut written = 0;
+        // where the target of the last CNAME written is, and the target
+        let mut previous: Option<(u16, Vec<u8>)> = None;
+        for mut rr in entries {
+            if rr.name == PREVIOUS_TARGET {
+                let Some((pointer, _)) = &previous else {
+                    continue;
+                };
+                rr.name = 0b1100_0000_0000_0000 | pointer;
+            }
+            // owner names point into the question, spelled out from it on request
+            let owner = match (compression, &previous) {
+                (Compression::None, Some((pointer, target))) if rr.name & 0x3fff == *pointer => {
+                    Some(target.as_slice())
+                }
+                (Compression::None, _) => expand(question, rr.name),
+                _ => None,
+            };
+            let owner_len = owner.map_or(2, <[u8]>::len);
+
+            if let (Some(names), RData::Opaque(data)) = (&mut names, &rr.rdata) {
+                let start = 12 + question.len() + self.len + owner_len + 10;
+                let compressed = match rr.rtype {
+                    2 | 5 | 12 => Some(names.compress(data, start)),
+                    15 if data.len() > 2 => {
+                        let mut rdata = data[..2].to_vec();
+                        rdata.extend(names.compress(&data[2..], start + 2));
+                        Some(rdata)
+                    }
+                    _ => None,
+                };
+                if let Some(compressed) = compressed {
+                    rr.rdlength = compressed.len() as u16;
+                    rr.rdata = RData::Opaque(compressed);
+                }
+            }
+            if self.len + owner_len + 10 + rr.rdata.len() > self.buf.len() {
+                break;
+            }
+
+            match owner {
+                Some(owner) => self.buf[self.len..self.len + owner_len].copy_from_slice(owner),
+                None => self.buf[self.len..self.len + 2].copy_from_slice(&rr.name.to_be_bytes()),
+            }
+            self.len += owner_len;
             self.buf[self.len..self.len + 2].copy_from_slice(&rr.rtype.to_be_bytes());
             self.len += 2;
             self.buf[self.len..self.len + 2].copy_from_slice(&rr.rclass.to_be_bytes());
@@ -128,6 +387,7 @@ impl Answer<'_> {
             self.len += 4;
             self.buf[self.len..self.len + 2].copy_from_slice(&rr.rdlength.to_be_bytes());
             self.len += 2;
+            let rdata_start = self.len;
             match rr.rdata {
                 RData::V4(addr) => {
                     self.buf[self.len..self.len + 4].copy_from_slice(&addr);
@@ -137,15 +397,155 @@ impl Answer<'_> {
                     self.buf[self.len..self.len + 16].copy_from_slice(&addr);
                     self.len += 16;
                 }
+                RData::Opaque(data) | RData::Dname(data) | RData::Raw { data, .. } => {
+                    self.buf[self.len..self.len + data.len()].copy_from_slice(&data);
+                    self.len += data.len();
+                }
+                RData::Tlsa {
+                    usage,
+                    selector,
+                    matching_type,
+                    data,
+                } => {
+                    self.buf[self.len..self.len + 3].copy_from_slice(&[
+                        usage,
+                        selector,
+                        matching_type,
+                    ]);
+                    self.len += 3;
+                    self.buf[self.len..self.len + data.len()].copy_from_slice(&data);
+                    self.len += data.len();
+                }
+                RData::Naptr {
+                    order,
+                    preference,
+                    flags,
+                    services,
+                    regexp,
+                    replacement,
+                } => {
+                    self.buf[self.len..self.len + 2].copy_from_slice(&order.to_be_bytes());
+                    self.len += 2;
+                    self.buf[self.len..self.len + 2].copy_from_slice(&preference.to_be_bytes());
+                    self.len += 2;
+                    for string in [flags, services, regexp] {
+                        self.buf[self.len] = string.len() as u8;
+                        self.len += 1;
+                        self.buf[self.len..self.len + string.len()]
+                            .copy_from_slice(string.as_bytes());
+                        self.len += string.len();
+                    }
+                    self.buf[self.len..self.len + replacement.len()].copy_from_slice(&replacement);
+                    self.len += replacement.len();
+                }
+                RData::Svcb {
+                    priority,
+                    target,
+                    params,
+                } => {
+                    self.buf[self.len..self.len + 2].copy_from_slice(&priority.to_be_bytes());
+                    self.len += 2;
+                    self.buf[self.len..self.len + target.len()].copy_from_slice(&target);
+                    self.len += target.len();
+                    for (key, value) in params {
+                        self.buf[self.len..self.len + 2].copy_from_slice(&key.to_be_bytes());
+                        self.len += 2;
+                        self.buf[self.len..self.len + 2]
+                            .copy_from_slice(&(value.len() as u16).to_be_bytes());
+                        self.len += 2;
+                        self.buf[self.len..self.len + value.len()].copy_from_slice(&value);
+                        self.len += value.len();
+                    }
+                }
+            }
+            if rr.rtype == 5 {
+                previous = u16::try_from(12 + question.len() + rdata_start)
+                    .ok()
+                    .filter(|pointer| *pointer <= 0x3fff)
+                    .map(|pointer| (pointer, self.buf[rdata_start..self.len].to_vec()));
             }
+            written += 1;
+        }
+
+        written
+    }
+}
+
+/// Owner name of a record following a CNAME in an answer, standing for the
+/// CNAME's target, for the next link of a chain of aliases.
+pub const PREVIOUS_TARGET: u16 = 0b1100_0000_0000_0000;
+
+/// How the names of local answers are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// spelled out in full, for clients that mishandle pointers
+    None,
+    /// owner names point to the question
+    Owners,
+    /// names in NS, CNAME, PTR and MX rdata also point back to suffixes
+    /// already in the message instead of repeating them
+    All,
+}
+
+/// The name a pointer into the question section refers to, uncompressed.
+fn expand(question: &[u8], pointer: u16) -> Option<&[u8]> {
+    let start = ((pointer & 0x3fff) as usize).checked_sub(12)?;
+    let mut i = start;
+    loop {
+        match *question.get(i)? as usize {
+            0 => return Some(&question[start..=i]),
+            len if len & 0b1100_0000 != 0 => return None,
+            len => i += 1 + len,
         }
     }
 }
 
+/// Where the suffixes of the names written so far start in the message, by
+/// lowercased wire form (RFC 1035 4.1.4).
+#[derive(Default)]
+struct Names(HashMap<Vec<u8>, u16>);
+
+impl Names {
+    /// Returns the uncompressed `name` at `offset` with its longest suffix
+    /// already seen replaced by a pointer, and remembers the suffixes it
+    /// writes out. Anything but a complete uncompressed name is left as is.
+    fn compress(&mut self, name: &[u8], offset: usize) -> Vec<u8> {
+        let mut labels = Vec::new();
+        let mut i = 0;
+        loop {
+            match name.get(i) {
+                Some(0) if i + 1 == name.len() => break,
+                Some(&len) if len & 0b1100_0000 == 0 && len != 0 => {
+                    labels.push(i);
+                    i += 1 + len as usize;
+                }
+                _ => return name.to_vec(),
+            }
+        }
+
+        for i in labels {
+            let suffix = name[i..].to_ascii_lowercase();
+            match self.0.get(&suffix) {
+                Some(&pointer) => {
+                    let mut compressed = name[..i].to_vec();
+                    compressed.extend((0b1100_0000_0000_0000 | pointer).to_be_bytes());
+                    return compressed;
+                }
+                // pointers only reach the first 16 KiB
+                None if offset + i < 0x4000 => {
+                    self.0.insert(suffix, (offset + i) as u16);
+                }
+                None => {}
+            }
+        }
+        name.to_vec()
+    }
+}
+
 #[derive(Debug)]
 pub struct QuestionEntry {
     pub offset: usize,
-    pub qname: String,
+    pub qname: Arc<str>,
     pub qtype: u16,
     pub qclass: u16,
 }
@@ -160,8 +560,683 @@ pub struct ResourceRecord {
     pub rdata: RData,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RData {
     V4([u8; 4]),
     V6([u8; 16]),
+    /// rdata taken verbatim from the hosts file
+    Opaque(Vec<u8>),
+    /// RFC 6672 target, an uncompressed encoded name
+    Dname(Vec<u8>),
+    /// RFC 6698 DANE association
+    Tlsa {
+        usage: u8,
+        selector: u8,
+        matching_type: u8,
+        data: Vec<u8>,
+    },
+    /// rdata of any type, served verbatim (RFC 3597)
+    Raw {
+        rtype: u16,
+        data: Vec<u8>,
+    },
+    /// RFC 3403; the strings are at most 255 bytes, the replacement is an
+    /// uncompressed encoded name
+    Naptr {
+        order: u16,
+        preference: u16,
+        flags: String,
+        services: String,
+        regexp: String,
+        replacement: Vec<u8>,
+    },
+    /// RFC 9460 SVCB and HTTPS; the target is an uncompressed encoded name,
+    /// the params are in wire format and sorted by key
+    Svcb {
+        priority: u16,
+        target: Vec<u8>,
+        params: Vec<(u16, Vec<u8>)>,
+    },
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl RData {
+    pub fn len(&self) -> usize {
+        match self {
+            RData::V4(_) => 4,
+            RData::V6(_) => 16,
+            RData::Opaque(data) | RData::Dname(data) | RData::Raw { data, .. } => data.len(),
+            RData::Tlsa { data, .. } => 3 + data.len(),
+            RData::Naptr {
+                flags,
+                services,
+                regexp,
+                replacement,
+                ..
+            } => 4 + 3 + flags.len() + services.len() + regexp.len() + replacement.len(),
+            RData::Svcb { target, params, .. } => {
+                2 + target.len()
+                    + params
+                        .iter()
+                        .map(|(_, value)| 4 + value.len())
+                        .sum::<usize>()
+            }
+        }
+    }
+}
+
+/// Location of a resource record inside a complete message.
+#[derive(Debug)]
+pub struct RecordRef {
+    pub rtype: u16,
+    pub ttl: u32,
+    pub rdata: usize,
+    pub end: usize,
+}
+
+impl RecordRef {
+    pub fn ttl_offset(&self) -> usize {
+        self.rdata - 6
+    }
+}
+
+/// Returns the offset right after the (possibly compressed) name at `i`.
+pub fn skip_name(buf: &[u8], mut i: usize) -> Option<usize> {
+    loop {
+        let len = *buf.get(i)? as usize;
+        if len == 0 {
+            return Some(i + 1);
+        }
+        if len & 0b1100_0000 == 0b1100_0000 {
+            return (i + 2 <= buf.len()).then_some(i + 2);
+        }
+        i += len + 1;
+    }
+}
+
+/// Offset right after the question section of a complete message.
+pub fn question_end(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 12 {
+        return None;
+    }
+
+    let mut i = 12;
+    for _ in 0..u16::from_be_bytes([buf[4], buf[5]]) {
+        i = skip_name(buf, i)? + 4;
+    }
+    (i <= buf.len()).then_some(i)
+}
+
+/// Lowercases the names in the question section of a complete message,
+/// returning the section as it was if that changed anything.
+pub fn lowercase_question(buf: &mut [u8]) -> Option<Vec<u8>> {
+    let end = question_end(buf)?;
+    let original = buf[12..end].to_vec();
+
+    let mut i = 12;
+    while i < end {
+        loop {
+            let len = buf[i] as usize;
+            if len == 0 {
+                i += 1;
+                break;
+            }
+            if len & 0b1100_0000 == 0b1100_0000 {
+                i += 2;
+                break;
+            }
+            buf[i + 1..i + 1 + len].make_ascii_lowercase();
+            i += 1 + len;
+        }
+        i += 4;
+    }
+
+    (buf[12..end] != original[..]).then_some(original)
+}
+
+/// Whether the question section of the complete message `resp` is `asked`,
+/// names compared case-insensitively.
+pub fn echoes_question(resp: &[u8], asked: &[u8]) -> bool {
+    let Some(echoed) = question_end(resp).map(|end| &resp[12..end]) else {
+        return false;
+    };
+    if echoed.len() != asked.len() {
+        return false;
+    }
+
+    let mut i = 0;
+    while i < asked.len() {
+        let Some(end) = skip_name(asked, i).filter(|end| end + 4 <= asked.len()) else {
+            return false;
+        };
+        // length octets are below 64, so only letters can differ in case
+        if !echoed[i..end].eq_ignore_ascii_case(&asked[i..end])
+            || echoed[end..end + 4] != asked[end..end + 4]
+        {
+            return false;
+        }
+        i = end + 4;
+    }
+    true
+}
+
+/// Walks every resource record after the question section of a complete
+/// message. Returns `None` if the message is malformed.
+pub fn records(buf: &[u8]) -> Option<Vec<RecordRef>> {
+    let count = |i: usize| u16::from_be_bytes([buf[i], buf[i + 1]]);
+    let mut i = question_end(buf)?;
+
+    let mut records = Vec::new();
+    for _ in 0..(count(6) as usize + count(8) as usize + count(10) as usize) {
+        i = skip_name(buf, i)?;
+        let fixed = buf.get(i..i + 10)?;
+        let rdlength = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        let rdata = i + 10;
+        if rdata + rdlength > buf.len() {
+            return None;
+        }
+
+        records.push(RecordRef {
+            rtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+            ttl: u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]),
+            rdata,
+            end: rdata + rdlength,
+        });
+        i = rdata + rdlength;
+    }
+
+    Some(records)
+}
+
+/// Length of the complete message at the start of `buf`. Returns `None` if
+/// it is malformed.
+pub fn message_len(buf: &[u8]) -> Option<usize> {
+    match records(buf)?.last() {
+        Some(record) => Some(record.end),
+        None => question_end(buf),
+    }
+}
+
+/// Cuts the complete message in `buf` down to at most `limit` bytes, keeping
+/// whole records only, and returns its new length. TC is set if anything had
+/// to go.
+pub fn truncate(buf: &mut [u8], limit: usize) -> usize {
+    if buf.len() <= limit {
+        return buf.len();
+    }
+    let count = |buf: &[u8], i: usize| u16::from_be_bytes([buf[i], buf[i + 1]]);
+
+    let (kept, end) = match (records(buf), question_end(buf)) {
+        (Some(records), Some(question_end)) if question_end <= limit => {
+            let kept = records.iter().take_while(|rr| rr.end <= limit).count();
+            let end = kept.checked_sub(1).map_or(question_end, |i| records[i].end);
+            (kept as u16, end)
+        }
+        // not even the question fits, or the message is malformed
+        _ => {
+            buf[4..6].copy_from_slice(&[0, 0]);
+            (0, 12)
+        }
+    };
+
+    let ancount = count(buf, 6).min(kept);
+    let nscount = count(buf, 8).min(kept - ancount);
+    let arcount = kept - ancount - nscount;
+    buf[6..8].copy_from_slice(&ancount.to_be_bytes());
+    buf[8..10].copy_from_slice(&nscount.to_be_bytes());
+    buf[10..12].copy_from_slice(&arcount.to_be_bytes());
+    buf[2] |= 0b0000_0010;
+
+    end
+}
+
+/// Keeps only the first `max` answers of the message in `buf[..len]` and
+/// returns its new length. TC is set if any had to go. The authority and
+/// additional sections go with the dropped answers, as their names may point
+/// into them, all but the OPT record. Returns `None` if the message is
+/// malformed.
+pub fn cap_answers(buf: &mut [u8], len: usize, max: usize) -> Option<usize> {
+    let ancount = u16::from_be_bytes([buf[6], buf[7]]) as usize;
+    if ancount <= max {
+        return Some(len);
+    }
+    let question_end = question_end(&buf[..len])?;
+    let records = records(&buf[..len])?;
+    let start = |i: usize| i.checked_sub(1).map_or(question_end, |i| records[i].end);
+
+    let end = start(max);
+    let opt = records
+        .iter()
+        .position(|rr| rr.rtype == OPT)
+        .map(|i| buf[start(i)..records[i].end].to_vec());
+    buf[2] |= 0b0000_0010;
+    buf[6..8].copy_from_slice(&(max as u16).to_be_bytes());
+    buf[8..10].fill(0);
+    buf[10..12].fill(0);
+    let len = match opt {
+        Some(opt) => {
+            buf[end..end + opt.len()].copy_from_slice(&opt);
+            buf[10..12].copy_from_slice(&1u16.to_be_bytes());
+            end + opt.len()
+        }
+        None => end,
+    };
+    Some(len)
+}
+
+/// A copy of the message with its header and question only, TC set.
+pub fn truncated(buf: &[u8]) -> Vec<u8> {
+    let mut resp = buf[..question_end(buf).unwrap_or(12)].to_vec();
+    if resp.len() == 12 {
+        resp[4..6].copy_from_slice(&[0, 0]);
+    }
+    resp[6..12].fill(0);
+    resp[2] |= 0b0000_0010;
+    resp
+}
+
+/// Sets the TTL of every record but OPT to `ttl`. Returns `None` if the
+/// message is malformed.
+pub fn set_ttls(buf: &mut [u8], ttl: u32) -> Option<()> {
+    for rr in records(buf)?.iter().filter(|rr| rr.rtype != OPT) {
+        let i = rr.ttl_offset();
+        buf[i..i + 4].copy_from_slice(&ttl.to_be_bytes());
+    }
+    Some(())
+}
+
+/// UDP payload size a query says its sender can receive: the OPT record's
+/// class, or 512 without one (RFC 6891 6.2.5).
+pub fn udp_payload_size(buf: &[u8]) -> usize {
+    records(buf)
+        .and_then(|records| records.into_iter().find(|rr| rr.rtype == OPT))
+        .map_or(512, |opt| {
+            u16::from_be_bytes([buf[opt.rdata - 8], buf[opt.rdata - 7]]) as usize
+        })
+        .max(512)
+}
+
+/// Sets the 12-bit rcode of the message in `buf[..len]`: its low 4 bits in
+/// the header, the upper 8 in the OPT record (RFC 6891 6.1.3), adding one
+/// advertising `udp_size` if the rcode needs it and there is none. Returns
+/// the new length, or `None`, leaving the message as is, if it is malformed,
+/// the rcode is out of range or the OPT record does not fit.
+pub fn set_extended_rcode(buf: &mut [u8], len: usize, rcode: u16, udp_size: u16) -> Option<usize> {
+    let upper = u8::try_from(rcode >> 4).ok()?;
+    let len = match records(&buf[..len])?.into_iter().find(|rr| rr.rtype == OPT) {
+        Some(opt) => {
+            buf[opt.ttl_offset()] = upper;
+            len
+        }
+        None if upper > 0 => {
+            let mut record = opt_record(udp_size);
+            record[5] = upper;
+            buf.get_mut(len..len + record.len())?
+                .copy_from_slice(&record);
+            let arcount = u16::from_be_bytes([buf[10], buf[11]]) + 1;
+            buf[10..12].copy_from_slice(&arcount.to_be_bytes());
+            len + record.len()
+        }
+        None => len,
+    };
+    buf[3] = (buf[3] & 0b1111_0000) | (rcode as u8 & 0b0000_1111);
+    Some(len)
+}
+
+/// The 12-bit rcode of the message, the upper 8 bits from its OPT record, if
+/// it has one.
+pub fn extended_rcode(buf: &[u8]) -> u16 {
+    let upper = records(buf)
+        .and_then(|records| records.into_iter().find(|rr| rr.rtype == OPT))
+        .map_or(0, |opt| buf[opt.ttl_offset()] as u16);
+    upper << 4 | (buf[3] & 0b0000_1111) as u16
+}
+
+/// The EDNS version of the OPT record, the second byte of its TTL, or `None`
+/// without one.
+pub fn edns_version(buf: &[u8]) -> Option<u8> {
+    let opt = records(buf)?.into_iter().find(|rr| rr.rtype == OPT)?;
+    Some(buf[opt.ttl_offset() + 1])
+}
+
+/// Whether the OPT record has the DO bit, the top bit of its flags (RFC 3225).
+pub fn dnssec_ok(buf: &[u8]) -> bool {
+    records(buf)
+        .and_then(|records| records.into_iter().find(|rr| rr.rtype == OPT))
+        .is_some_and(|opt| buf[opt.ttl_offset() + 2] & 0b1000_0000 != 0)
+}
+
+pub fn has_opt(buf: &[u8]) -> bool {
+    records(buf).is_some_and(|records| records.iter().any(|rr| rr.rtype == OPT))
+}
+
+/// A minimal EDNS version 0 OPT record advertising `udp_size`, with no flags
+/// or options.
+pub fn opt_record(udp_size: u16) -> [u8; 11] {
+    let mut record = [0u8; 11];
+    record[1..3].copy_from_slice(&OPT.to_be_bytes());
+    record[3..5].copy_from_slice(&udp_size.to_be_bytes());
+    record
+}
+
+/// An Extended DNS Error option (RFC 8914) for an OPT record's rdata.
+pub fn ede_option(info_code: u16, text: &str) -> Vec<u8> {
+    let mut option = Vec::with_capacity(6 + text.len());
+    option.extend_from_slice(&EXTENDED_DNS_ERROR.to_be_bytes());
+    option.extend_from_slice(&(2 + text.len() as u16).to_be_bytes());
+    option.extend_from_slice(&info_code.to_be_bytes());
+    option.extend_from_slice(text.as_bytes());
+    option
+}
+
+/// Appends `option`, in wire format, to the OPT record of the message, first
+/// adding an OPT record advertising `udp_size` if there is none. Returns
+/// `None`, leaving the message as is, if it is malformed or the OPT record
+/// would grow too long.
+pub fn add_edns_option(buf: &mut Vec<u8>, option: &[u8], udp_size: u16) -> Option<()> {
+    match records(buf)?.into_iter().find(|rr| rr.rtype == OPT) {
+        Some(opt) => {
+            let rdlength = u16::try_from(opt.end - opt.rdata + option.len()).ok()?;
+            buf[opt.rdata - 2..opt.rdata].copy_from_slice(&rdlength.to_be_bytes());
+            buf.splice(opt.end..opt.end, option.iter().copied());
+        }
+        None => {
+            let mut opt = opt_record(udp_size);
+            opt[9..11].copy_from_slice(&u16::try_from(option.len()).ok()?.to_be_bytes());
+            buf.extend_from_slice(&opt);
+            buf.extend_from_slice(option);
+            let arcount = u16::from_be_bytes([buf[10], buf[11]]) + 1;
+            buf[10..12].copy_from_slice(&arcount.to_be_bytes());
+        }
+    }
+    Some(())
+}
+
+/// The message padded with an EDNS Padding option (RFC 7830) to a multiple of
+/// `block` bytes, or only up to `max_size` if that is less. `None`, for it to
+/// be sent as is, if it has no OPT record, is padded already or has no room
+/// for the option.
+pub fn padded(buf: &[u8], block: usize, max_size: usize) -> Option<Vec<u8>> {
+    let opt = records(buf)?.into_iter().find(|rr| rr.rtype == OPT)?;
+    if opt_options(buf)?.iter().any(|(code, _)| *code == PADDING) {
+        return None;
+    }
+    // the option's code and length come first
+    let len = ((buf.len() + 4).div_ceil(block) * block)
+        .min(max_size)
+        .checked_sub(buf.len() + 4)?;
+    let rdlength = u16::try_from(opt.end - opt.rdata + 4 + len).ok()?;
+
+    let mut padded = Vec::with_capacity(buf.len() + 4 + len);
+    padded.extend_from_slice(&buf[..opt.end]);
+    padded[opt.rdata - 2..opt.rdata].copy_from_slice(&rdlength.to_be_bytes());
+    padded.extend_from_slice(&PADDING.to_be_bytes());
+    padded.extend_from_slice(&(len as u16).to_be_bytes());
+    padded.resize(padded.len() + len, 0);
+    padded.extend_from_slice(&buf[opt.end..]);
+    Some(padded)
+}
+
+/// The EDNS options of the OPT record in `buf`, as (code, data) pairs. Empty
+/// if there is no OPT record, `None` if the message is malformed.
+pub fn opt_options(buf: &[u8]) -> Option<Vec<(u16, Range<usize>)>> {
+    let mut options = Vec::new();
+    let Some(opt) = records(buf)?.into_iter().find(|rr| rr.rtype == OPT) else {
+        return Some(options);
+    };
+
+    let mut i = opt.rdata;
+    while i < opt.end {
+        let fixed = buf.get(i..i + 4)?;
+        let code = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let len = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+        if i + 4 + len > opt.end {
+            return None;
+        }
+        options.push((code, i + 4..i + 4 + len));
+        i += 4 + len;
+    }
+
+    Some(options)
+}
+
+/// An EDNS Client Subnet option (RFC 7871).
+#[derive(Debug)]
+pub struct ClientSubnet {
+    pub addr: IpAddr,
+    pub source_prefix: u8,
+}
+
+impl ClientSubnet {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let family = u16::from_be_bytes([*data.first()?, *data.get(1)?]);
+        let source_prefix = *data.get(2)?;
+        // scope prefix at 3, always 0 in queries; the address is truncated to
+        // the bytes the prefix needs
+        let bytes = data.get(4..)?;
+
+        let addr = match family {
+            1 if bytes.len() <= 4 => {
+                let mut octets = [0u8; 4];
+                octets[..bytes.len()].copy_from_slice(bytes);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            2 if bytes.len() <= 16 => {
+                let mut octets = [0u8; 16];
+                octets[..bytes.len()].copy_from_slice(bytes);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+
+        Some(Self {
+            addr,
+            source_prefix,
+        })
+    }
+}
+
+impl fmt::Display for ClientSubnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.source_prefix)
+    }
+}
+
+/// Whether `qname` is one of `domains` or a name under one.
+pub fn in_domains(qname: &str, domains: &[String]) -> bool {
+    let qname = qname.trim_end_matches('.').to_ascii_lowercase();
+    domains.iter().any(|domain| {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        qname == domain || qname.ends_with(&format!(".{}", domain))
+    })
+}
+
+/// The address a reverse name (`4.3.2.1.in-addr.arpa` or the 32 nibbles of
+/// an `ip6.arpa` name) stands for.
+pub fn reverse_addr(name: &str) -> Option<IpAddr> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+    if let Some(labels) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets = labels
+            .split('.')
+            .map(|label| label.parse::<u8>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        octets.reverse();
+        let octets: [u8; 4] = octets.try_into().ok()?;
+        return Some(IpAddr::V4(Ipv4Addr::from(octets)));
+    }
+
+    let labels = name.strip_suffix(".ip6.arpa")?;
+    let nibbles = labels
+        .split('.')
+        .map(|label| match label.len() {
+            1 => u8::from_str_radix(label, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    if nibbles.len() != 32 {
+        return None;
+    }
+    let mut octets = [0u8; 16];
+    for (i, pair) in nibbles.rchunks(2).enumerate() {
+        octets[i] = pair[1] << 4 | pair[0];
+    }
+    Some(IpAddr::V6(Ipv6Addr::from(octets)))
+}
+
+/// TXT rdata holding `text`, split into character-strings of at most 255
+/// bytes.
+pub fn txt_rdata(text: &str) -> Vec<u8> {
+    let mut rdata = Vec::with_capacity(text.len() + text.len() / 255 + 1);
+    for chunk in text.as_bytes().chunks(255) {
+        rdata.push(chunk.len() as u8);
+        rdata.extend_from_slice(chunk);
+    }
+    rdata
+}
+
+/// Encodes a dotted name as uncompressed labels; `.` is the root.
+pub fn encode_name(name: &str) -> anyhow::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(name.len() + 2);
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        anyhow::ensure!(label.len() <= 63, "label too long in {}", name);
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    anyhow::ensure!(buf.len() <= 255, "name too long: {}", name);
+    Ok(buf)
+}
+
+/// The presentation form of an uncompressed encoded name, without the
+/// trailing dot.
+pub fn decode_name(name: &[u8]) -> Option<String> {
+    let mut labels = Vec::new();
+    let mut i = 0;
+    loop {
+        let len = *name.get(i)? as usize;
+        if len == 0 {
+            return Some(labels.join("."));
+        }
+        if len > 63 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(name.get(i + 1..i + 1 + len)?).into_owned());
+        i += 1 + len;
+    }
+}
+
+/// Builds a standalone query with a single IN-class question and RD set.
+pub fn build_query(id: u16, qname: &str, qtype: u16) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(qname.len() + 18);
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in qname.split('.').filter(|label| !label.is_empty()) {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf.extend_from_slice(&qtype.to_be_bytes());
+    buf.extend_from_slice(&1u16.to_be_bytes());
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut buf = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        buf.extend_from_slice(&encode_name(name).unwrap());
+        buf.extend_from_slice(&qtype.to_be_bytes());
+        buf.extend_from_slice(&1u16.to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn message_splits_question_from_answer() {
+        let mut buf = query("split.test", 1);
+        let question_len = buf.len() - 12;
+        buf[2] |= 0b1000_0000;
+        buf[7] = 1;
+        // an A record owned by a compression pointer to the question name
+        buf.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4, 10, 0, 0, 1]);
+        let len = buf.len();
+        buf.resize(512, 0);
+
+        let msg = Message::new(&mut buf, len);
+        assert_eq!(msg.question.len(), question_len);
+        assert_eq!(msg.answer.len(), 16);
+        assert_eq!(msg.len(), len);
+
+        let entries = msg.question.entries(1).unwrap();
+        assert_eq!(&*entries[0].qname, "split.test");
+        assert_eq!(entries[0].qtype, 1);
+    }
+
+    #[test]
+    fn message_splits_after_every_question() {
+        let mut buf = query("one.test", 1);
+        buf[5] = 2;
+        let second = query("two.test", 28);
+        buf.extend_from_slice(&second[12..]);
+        let question_len = buf.len() - 12;
+        buf.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4, 10, 0, 0, 1]);
+        let len = buf.len();
+
+        let msg = Message::new(&mut buf, len);
+        assert_eq!(msg.question.len(), question_len);
+        assert_eq!(msg.answer.len(), 16);
+
+        let entries = msg.question.entries(2).unwrap();
+        assert_eq!(&*entries[1].qname, "two.test");
+        assert_eq!(entries[1].qtype, 28);
+    }
+
+    #[test]
+    fn unwalkable_question_takes_up_the_rest() {
+        let mut buf = query("cut.test", 1);
+        // qtype and qclass of the second question are missing
+        buf[5] = 2;
+        buf.extend_from_slice(&encode_name("short.test").unwrap());
+        let len = buf.len();
+
+        let msg = Message::new(&mut buf, len);
+        assert_eq!(msg.question.len(), len - 12);
+        assert_eq!(msg.answer.len(), 0);
+        assert!(msg.question.entries(2).is_err());
+    }
+
+    #[test]
+    fn extended_rcode_is_split_between_header_and_opt() {
+        let mut buf = query("cookie.test", 1);
+        buf[2] |= 0b1000_0000;
+        let len = buf.len();
+        buf.resize(512, 0);
+
+        // a 4-bit rcode needs no OPT record
+        let len = set_extended_rcode(&mut buf, len, 3, 1232).unwrap();
+        assert_eq!(len, buf.iter().rposition(|&b| b != 0).unwrap() + 1);
+        assert_eq!(u16::from_be_bytes([buf[10], buf[11]]), 0);
+        assert_eq!(extended_rcode(&buf[..len]), 3);
+
+        // BADCOOKIE (23) adds one for its upper bits
+        let with_opt = set_extended_rcode(&mut buf, len, BADCOOKIE, 1232).unwrap();
+        assert_eq!(with_opt, len + 11);
+        assert_eq!(u16::from_be_bytes([buf[10], buf[11]]), 1);
+        assert_eq!(buf[3] & 0b0000_1111, 7);
+        assert_eq!(
+            &buf[len + 3..len + 6],
+            &[0x04, 0xd0, 1],
+            "payload size, upper bits"
+        );
+        assert_eq!(extended_rcode(&buf[..with_opt]), BADCOOKIE);
+
+        // and reuses it for the next one
+        let again = set_extended_rcode(&mut buf, with_opt, BADVERS, 1232).unwrap();
+        assert_eq!(again, with_opt);
+        assert_eq!(extended_rcode(&buf[..again]), BADVERS);
+
+        assert_eq!(set_extended_rcode(&mut buf, again, 0x1000, 1232), None);
+    }
 }
@@ -0,0 +1,59 @@
+//! Names blocked or redirected by regular expression, for what exact names
+//! and wildcards cannot express, e.g. `^ad[0-9]+\.tracker\.com$`.
+//!
+//! Matching every pattern against a name costs far more than the hash
+//! lookups of the hosts, so patterns are only tried for names the hosts,
+//! overrides, schedules and views know nothing about, in order, the first
+//! match winning. Keep the list short and the patterns anchored.
+
+use std::{net::IpAddr, str::FromStr};
+
+use regex::Regex;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PatternAction {
+    /// NXDOMAIN, as for names the hosts map to `0.0.0.0`
+    Block,
+    /// this address for A or AAAA queries, whichever it is
+    Redirect(IpAddr),
+}
+
+/// A `regex=block` or `regex=ip` rule from the config. Names are matched
+/// lowercase and without the trailing dot.
+#[derive(Debug, Clone, Serialize)]
+pub struct NamePattern {
+    pub pattern: String,
+    #[serde(skip)]
+    regex: Regex,
+    pub action: PatternAction,
+}
+
+impl NamePattern {
+    pub fn matches(&self, qname: &str) -> bool {
+        self.regex.is_match(qname)
+    }
+}
+
+impl FromStr for NamePattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // the action comes last, as patterns may have `=` of their own
+        let (pattern, action) = s
+            .rsplit_once('=')
+            .ok_or(anyhow::anyhow!("expected regex=block|ip, got {}", s))?;
+        let action = match action {
+            "block" => PatternAction::Block,
+            ip => PatternAction::Redirect(ip.parse().map_err(|_| {
+                anyhow::anyhow!("expected block or an address, got {} in {}", ip, s)
+            })?),
+        };
+
+        Ok(Self {
+            pattern: pattern.to_owned(),
+            regex: Regex::new(pattern)?,
+            action,
+        })
+    }
+}
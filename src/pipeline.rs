@@ -0,0 +1,696 @@
+use std::{net::IpAddr, str::FromStr, sync::Arc};
+
+use serde::Serialize;
+use tracing::debug;
+
+use crate::{
+    backend::Backend,
+    cache::{Cache, CacheKey},
+    control::Overrides,
+    hosts::{CNAME, SOA},
+    is_localhost, name_compressed,
+    packet::{self, QuestionEntry, RData, ResourceRecord},
+    process,
+    responses::StaticResponses,
+    schedule::Schedule,
+    script::{Decision, Script},
+    views::Views,
+    Blocked, NamePattern, DEFAULT_TTL,
+};
+
+pub enum Outcome {
+    Answered(Response),
+    Passthrough,
+}
+
+pub enum Response {
+    /// NOERROR with these answer records
+    Records(Vec<ResourceRecord>),
+    /// NOERROR without answers, the zone's SOA in the authority section so
+    /// the negative answer can be cached (RFC 2308)
+    NoData(ResourceRecord),
+    /// answers to some of the questions, the ones at `forwarded` left to
+    /// upstream
+    Partial {
+        answers: Vec<ResourceRecord>,
+        forwarded: Vec<usize>,
+    },
+    /// a local alias to a name outside, whose records are left to upstream
+    Chase {
+        answers: Vec<ResourceRecord>,
+        target: Vec<u8>,
+    },
+    /// no records, only this rcode
+    Rcode(u8),
+    /// NXDOMAIN for a name blocked on purpose, with the reason of what
+    /// blocked it, `None` for the relay's own
+    Blocked(Option<Arc<str>>),
+    /// a complete message, sent back as is apart from the id
+    Message(Vec<u8>),
+}
+
+/// One step of local resolution. Queries no stage answers go upstream.
+/// `client` is the address the query came from, if it came from one.
+pub trait Stage: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn resolve(&self, questions: &[QuestionEntry], client: Option<IpAddr>) -> Outcome;
+
+    /// Whether the stage is an authority for `qname`.
+    fn authoritative(&self, _qname: &str) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StageKind {
+    Static,
+    Blackhole,
+    Cache,
+    Hosts,
+    Script,
+}
+
+impl FromStr for StageKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "static" => Ok(StageKind::Static),
+            "blackhole" => Ok(StageKind::Blackhole),
+            "cache" => Ok(StageKind::Cache),
+            "hosts" => Ok(StageKind::Hosts),
+            "script" => Ok(StageKind::Script),
+            _ => Err(anyhow::anyhow!("unknown pipeline stage: {}", s)),
+        }
+    }
+}
+
+pub const DEFAULT_ORDER: [StageKind; 5] = [
+    StageKind::Static,
+    StageKind::Blackhole,
+    StageKind::Cache,
+    StageKind::Hosts,
+    StageKind::Script,
+];
+
+/// The reasons given for names blocked by the hosts and by the name
+/// patterns, `None` for the relay's own.
+#[derive(Debug, Clone, Default)]
+pub struct BlockReasons {
+    pub hosts: Option<Arc<str>>,
+    pub patterns: Option<Arc<str>>,
+}
+
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    /// Builds the stages in the given order. The static, cache and script
+    /// stages are left out when there is nothing to serve from them.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        order: &[StageKind],
+        responses: StaticResponses,
+        blackhole_qtypes: &[u16],
+        hosts: Arc<dyn Backend>,
+        overrides: Overrides,
+        views: Arc<Views>,
+        schedules: &[Schedule],
+        patterns: &[NamePattern],
+        reasons: BlockReasons,
+        zones: &[String],
+        soa_zones: bool,
+        split: bool,
+        cache: Option<Arc<Cache>>,
+        script: Option<Arc<Script>>,
+    ) -> Self {
+        let mut responses = Some(responses).filter(|responses| !responses.is_empty());
+        let mut hosts = Some((hosts, reasons));
+        let mut stages: Vec<Box<dyn Stage>> = Vec::new();
+
+        for kind in order {
+            match kind {
+                StageKind::Static => {
+                    if let Some(responses) = responses.take() {
+                        stages.push(Box::new(StaticStage { responses }));
+                    }
+                }
+                StageKind::Blackhole => stages.push(Box::new(BlackholeStage {
+                    qtypes: blackhole_qtypes.to_vec(),
+                })),
+                StageKind::Cache => {
+                    if let Some(cache) = &cache {
+                        stages.push(Box::new(CacheStage {
+                            cache: cache.clone(),
+                        }));
+                    }
+                }
+                StageKind::Hosts => {
+                    if let Some((hosts, reasons)) = hosts.take() {
+                        stages.push(Box::new(HostsStage {
+                            hosts,
+                            overrides: overrides.clone(),
+                            views: views.clone(),
+                            schedules: schedules.to_vec(),
+                            patterns: patterns.to_vec(),
+                            reasons,
+                            zones: zones.to_vec(),
+                            soa_zones,
+                            split,
+                        }));
+                    }
+                }
+                StageKind::Script => {
+                    if let Some(script) = &script {
+                        stages.push(Box::new(ScriptStage {
+                            script: script.clone(),
+                        }));
+                    }
+                }
+            }
+        }
+
+        Self { stages }
+    }
+
+    /// Whether a stage is an authority for `qname`, i.e. it is in a zone.
+    pub fn authoritative(&self, qname: &str) -> bool {
+        self.stages.iter().any(|stage| stage.authoritative(qname))
+    }
+
+    pub fn stages(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.stages.iter().map(|stage| stage.name())
+    }
+
+    /// Runs the stages in order until one answers.
+    pub fn resolve(
+        &self,
+        questions: &[QuestionEntry],
+        client: Option<IpAddr>,
+    ) -> Option<(&'static str, Response)> {
+        self.stages
+            .iter()
+            .find_map(|stage| match stage.resolve(questions, client) {
+                Outcome::Answered(response) => Some((stage.name(), response)),
+                Outcome::Passthrough => None,
+            })
+    }
+}
+
+/// Serves pre-captured responses to single-question queries.
+pub struct StaticStage {
+    responses: StaticResponses,
+}
+
+impl Stage for StaticStage {
+    fn name(&self) -> &'static str {
+        "static"
+    }
+
+    fn resolve(&self, questions: &[QuestionEntry], _client: Option<IpAddr>) -> Outcome {
+        match questions {
+            [q] => match self.responses.get(&(q.qname.to_ascii_lowercase(), q.qtype)) {
+                Some(bytes) => Outcome::Answered(Response::Message(bytes.clone())),
+                None => Outcome::Passthrough,
+            },
+            _ => Outcome::Passthrough,
+        }
+    }
+}
+
+/// Answers NODATA for the configured qtypes, whatever the name.
+pub struct BlackholeStage {
+    qtypes: Vec<u16>,
+}
+
+impl Stage for BlackholeStage {
+    fn name(&self) -> &'static str {
+        "blackhole"
+    }
+
+    fn resolve(&self, questions: &[QuestionEntry], _client: Option<IpAddr>) -> Outcome {
+        match questions.iter().find(|q| self.qtypes.contains(&q.qtype)) {
+            Some(q) => {
+                debug!("qtype {} is blackholed", q.qtype);
+                Outcome::Answered(Response::Rcode(0b0000))
+            }
+            None => Outcome::Passthrough,
+        }
+    }
+}
+
+/// Serves earlier upstream responses to single-question queries.
+pub struct CacheStage {
+    cache: Arc<Cache>,
+}
+
+impl Stage for CacheStage {
+    fn name(&self) -> &'static str {
+        "cache"
+    }
+
+    fn resolve(&self, questions: &[QuestionEntry], _client: Option<IpAddr>) -> Outcome {
+        match questions {
+            [q] => match self.cache.get(&CacheKey::from(q)) {
+                Some(bytes) => Outcome::Answered(Response::Message(bytes)),
+                None => Outcome::Passthrough,
+            },
+            _ => Outcome::Passthrough,
+        }
+    }
+}
+
+/// Answers from the overrides, the schedules, the views and the hosts backend,
+/// then the name patterns. Every question must be answerable, otherwise the
+/// whole query is passed on, or with `split` only the questions that are not.
+/// Names in an authoritative zone are always answerable: missing ones do not
+/// exist. A reverse name exists when its address is mapped to a name. Answers
+/// without data carry the zone's SOA, if the hosts have one. An alias to a name
+/// the relay knows nothing about is resolved upstream.
+///
+/// The zones are the configured ones, plus with `soa_zones` every name with
+/// an SOA in the hosts. A name belongs to the innermost zone at or above it,
+/// so a zone with its own SOA nested in another one is answered for with
+/// that SOA.
+pub struct HostsStage {
+    hosts: Arc<dyn Backend>,
+    overrides: Overrides,
+    views: Arc<Views>,
+    schedules: Vec<Schedule>,
+    patterns: Vec<NamePattern>,
+    reasons: BlockReasons,
+    zones: Vec<String>,
+    soa_zones: bool,
+    split: bool,
+}
+
+impl Stage for HostsStage {
+    fn name(&self) -> &'static str {
+        "hosts"
+    }
+
+    fn resolve(&self, questions: &[QuestionEntry], client: Option<IpAddr>) -> Outcome {
+        let mut answers = Vec::new();
+        let mut nodata = None;
+        let mut forwarded = Vec::new();
+        for (i, query) in questions.iter().enumerate() {
+            match process(
+                query,
+                client,
+                self.hosts.as_ref(),
+                &self.overrides,
+                &self.views,
+                &self.schedules,
+                &self.patterns,
+            ) {
+                Ok(rrs) if rrs.is_empty() => {
+                    if is_localhost(&query.qname) {
+                        nodata = nodata.or(Some(query));
+                        continue;
+                    }
+                    if self.zone(&query.qname).is_none() {
+                        if !self.split {
+                            return Outcome::Passthrough;
+                        }
+                        forwarded.push(i);
+                        continue;
+                    }
+                    let overridden = self
+                        .overrides
+                        .read()
+                        .unwrap()
+                        .contains_key(&query.qname.to_ascii_lowercase());
+                    let viewed = client
+                        .and_then(|client| self.views.addr(&query.qname, client))
+                        .is_some();
+                    let mapped = packet::reverse_addr(&query.qname)
+                        .is_some_and(|addr| self.hosts.name(addr).is_some());
+                    if !overridden && !viewed && !mapped && !self.hosts.contains(&query.qname) {
+                        debug!("{} does not exist in its zone", query.qname);
+                        return Outcome::Answered(Response::Rcode(0b0011));
+                    }
+                    nodata = nodata.or(Some(query));
+                }
+                Ok(rrs) => {
+                    debug!("local rr(s) created: {:x?}", rrs);
+                    answers.extend(rrs);
+                }
+                Err(e) => {
+                    debug!("{} is {}", query.qname, e);
+                    let reason = match e {
+                        Blocked::Hosts => &self.reasons.hosts,
+                        Blocked::Pattern(_) => &self.reasons.patterns,
+                    };
+                    return Outcome::Answered(Response::Blocked(reason.clone()));
+                }
+            }
+        }
+
+        if forwarded.len() == questions.len() {
+            return Outcome::Passthrough;
+        }
+        if !forwarded.is_empty() {
+            return Outcome::Answered(Response::Partial { answers, forwarded });
+        }
+        if let Some(target) = self.chased(questions, &answers) {
+            return Outcome::Answered(Response::Chase { answers, target });
+        }
+        match nodata
+            .filter(|_| answers.is_empty())
+            .and_then(|q| self.soa(q))
+        {
+            Some(soa) => Outcome::Answered(Response::NoData(soa)),
+            None => Outcome::Answered(Response::Records(answers)),
+        }
+    }
+
+    fn authoritative(&self, qname: &str) -> bool {
+        self.zone(qname).is_some()
+    }
+}
+
+impl HostsStage {
+    /// The SOA of the innermost zone `query` is in, owned by the zone's name
+    /// within the question and with the negative TTL of RFC 2308 5.
+    fn soa(&self, query: &QuestionEntry) -> Option<ResourceRecord> {
+        let qname = query.qname.trim_end_matches('.');
+        let zone = self.zone(qname)?;
+        let (ttl, rdata) = self.hosts.records(zone, SOA).into_iter().next()?;
+
+        let minimum = soa_minimum(&rdata)?;
+        Some(ResourceRecord {
+            name: name_compressed(query) + (qname.len() - zone.len()) as u16,
+            rtype: SOA,
+            rclass: query.qclass,
+            ttl: ttl.min(minimum),
+            rdlength: rdata.len() as u16,
+            rdata,
+        })
+    }
+
+    /// The innermost zone `qname` is in, if any.
+    fn zone<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
+        let qname = qname.trim_end_matches('.');
+        let configured = self
+            .zones
+            .iter()
+            .map(|zone| zone.trim_end_matches('.'))
+            .filter(|zone| packet::in_domains(qname, &[zone.to_string()]))
+            .max_by_key(|zone| zone.len());
+        // walked from qname up, so the first one found is the innermost
+        let with_soa = self
+            .soa_zones
+            .then(|| {
+                std::iter::successors(Some(qname), |name| name.split_once('.').map(|(_, up)| up))
+                    .find(|name| !self.hosts.records(name, SOA).is_empty())
+            })
+            .flatten();
+
+        match (configured, with_soa) {
+            (Some(configured), Some(with_soa)) if configured.len() >= with_soa.len() => {
+                Some(configured)
+            }
+            (_, Some(with_soa)) => Some(with_soa),
+            (configured, None) => configured,
+        }
+    }
+
+    /// The target of the chain of aliases answering a single question, if
+    /// only upstream can resolve it.
+    fn chased(&self, questions: &[QuestionEntry], answers: &[ResourceRecord]) -> Option<Vec<u8>> {
+        let ([query], Some(cname)) = (questions, answers.last()) else {
+            return None;
+        };
+        let RData::Opaque(target) = &cname.rdata else {
+            return None;
+        };
+        if query.qtype == CNAME || answers.iter().any(|rr| rr.rtype != CNAME) {
+            return None;
+        }
+        let name = packet::decode_name(target)?;
+        if self.zone(&name).is_some() || self.hosts.contains(&name) {
+            return None;
+        }
+        Some(target.clone())
+    }
+}
+
+/// Answers single-question queries as the script decides. Being slow, it
+/// belongs after the stages answering from tables.
+pub struct ScriptStage {
+    script: Arc<Script>,
+}
+
+impl Stage for ScriptStage {
+    fn name(&self) -> &'static str {
+        "script"
+    }
+
+    fn resolve(&self, questions: &[QuestionEntry], client: Option<IpAddr>) -> Outcome {
+        let [q] = questions else {
+            return Outcome::Passthrough;
+        };
+        let response = match self.script.decide(&q.qname, q.qtype, client) {
+            Decision::Forward => return Outcome::Passthrough,
+            Decision::Block => Response::Blocked(None),
+            Decision::Refuse => Response::Rcode(0b0101),
+            Decision::Answer(ip) => {
+                let rdata = match ip {
+                    IpAddr::V4(ip) if q.qtype == 1 => RData::V4(ip.octets()),
+                    IpAddr::V6(ip) if q.qtype == 28 => RData::V6(ip.octets()),
+                    _ => return Outcome::Answered(Response::Records(Vec::new())),
+                };
+                Response::Records(vec![ResourceRecord {
+                    name: name_compressed(q),
+                    rtype: q.qtype,
+                    rclass: q.qclass,
+                    ttl: DEFAULT_TTL as u32,
+                    rdlength: rdata.len() as u16,
+                    rdata,
+                }])
+            }
+        };
+        debug!("{} answered by the script", q.qname);
+
+        Outcome::Answered(response)
+    }
+}
+
+/// The MINIMUM field, last in SOA rdata.
+fn soa_minimum(rdata: &RData) -> Option<u32> {
+    match rdata {
+        RData::Opaque(data) if data.len() >= 22 => {
+            let minimum = &data[data.len() - 4..];
+            Some(u32::from_be_bytes([
+                minimum[0], minimum[1], minimum[2], minimum[3],
+            ]))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::hosts::load_hosts;
+
+    fn question(qname: &str, qtype: u16) -> QuestionEntry {
+        QuestionEntry {
+            offset: 12,
+            qname: qname.into(),
+            qtype,
+            qclass: 1,
+        }
+    }
+
+    fn hosts(name: &str, contents: &str) -> Arc<dyn Backend> {
+        let path = std::env::temp_dir().join(format!(
+            "mini-dns-relay-{}-{}.txt",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, contents).unwrap();
+        Arc::new(load_hosts(path.to_str().unwrap(), true).unwrap())
+    }
+
+    fn pipeline(order: &[StageKind], hosts: Arc<dyn Backend>) -> Pipeline {
+        Pipeline::new(
+            order,
+            StaticResponses::new(),
+            &[28],
+            hosts,
+            Overrides::default(),
+            Arc::default(),
+            &[],
+            &[],
+            BlockReasons::default(),
+            &[],
+            false,
+            false,
+            None,
+            None,
+        )
+    }
+
+    #[test]
+    fn blackhole_answers_its_qtypes_only() {
+        let stage = BlackholeStage { qtypes: vec![28] };
+
+        assert!(matches!(
+            stage.resolve(&[question("any.test", 28)], None),
+            Outcome::Answered(Response::Rcode(0))
+        ));
+        assert!(matches!(
+            stage.resolve(&[question("any.test", 1)], None),
+            Outcome::Passthrough
+        ));
+    }
+
+    #[test]
+    fn cache_answers_cached_questions_only() {
+        let cache = Arc::new(Cache::new(8));
+        let q = question("cached.test", 1);
+        let mut resp = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+        resp.extend_from_slice(&packet::encode_name("cached.test").unwrap());
+        resp.extend_from_slice(&[0, 1, 0, 1]);
+        resp.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 1]);
+        cache.insert(CacheKey::from(&q), &resp).unwrap();
+        let stage = CacheStage { cache };
+
+        match stage.resolve(&[question("cached.test", 1)], None) {
+            Outcome::Answered(Response::Message(bytes)) => assert_eq!(bytes, resp),
+            _ => panic!("cached response not served"),
+        }
+        assert!(matches!(
+            stage.resolve(&[question("other.test", 1)], None),
+            Outcome::Passthrough
+        ));
+        assert!(matches!(
+            stage.resolve(&[q, question("other.test", 1)], None),
+            Outcome::Passthrough
+        ));
+    }
+
+    #[test]
+    fn hosts_answer_known_names_and_pass_the_rest() {
+        let stage = HostsStage {
+            hosts: hosts("stage", "10.0.0.1 known.test\n"),
+            overrides: Overrides::default(),
+            views: Arc::default(),
+            schedules: Vec::new(),
+            patterns: Vec::new(),
+            reasons: BlockReasons::default(),
+            zones: Vec::new(),
+            soa_zones: false,
+            split: false,
+        };
+
+        match stage.resolve(&[question("known.test", 1)], None) {
+            Outcome::Answered(Response::Records(records)) => {
+                assert_eq!(records.len(), 1);
+                assert_eq!(records[0].rdata, RData::V4([10, 0, 0, 1]));
+            }
+            _ => panic!("known name not answered"),
+        }
+        assert!(matches!(
+            stage.resolve(&[question("unknown.test", 1)], None),
+            Outcome::Passthrough
+        ));
+    }
+
+    #[test]
+    fn blocked_names_carry_the_reason_of_what_blocked_them() {
+        let stage = HostsStage {
+            hosts: hosts("reasons", "0.0.0.0 ads.test\n"),
+            overrides: Overrides::default(),
+            views: Arc::default(),
+            schedules: Vec::new(),
+            patterns: vec!["^tracker\\.test$=block".parse().unwrap()],
+            reasons: BlockReasons {
+                hosts: Some("blocked by the ad list".into()),
+                patterns: None,
+            },
+            zones: Vec::new(),
+            soa_zones: false,
+            split: false,
+        };
+
+        match stage.resolve(&[question("ads.test", 1)], None) {
+            Outcome::Answered(Response::Blocked(reason)) => {
+                assert_eq!(reason.as_deref(), Some("blocked by the ad list"));
+            }
+            _ => panic!("name in the hosts not blocked"),
+        }
+        assert!(matches!(
+            stage.resolve(&[question("tracker.test", 1)], None),
+            Outcome::Answered(Response::Blocked(None))
+        ));
+    }
+
+    #[test]
+    fn script_answers_what_it_decides() {
+        let path =
+            std::env::temp_dir().join(format!("mini-dns-relay-{}-script.rhai", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            fn decide(qname, qtype, client) {
+                if qname == "mapped.test" { return "10.0.0.9"; }
+                if qname == "refused.test" { return "refuse"; }
+            }
+            "#,
+        )
+        .unwrap();
+        let script = Script::load(path.to_str().unwrap(), Duration::from_millis(50)).unwrap();
+        let stage = ScriptStage {
+            script: Arc::new(script),
+        };
+
+        match stage.resolve(&[question("mapped.test", 1)], None) {
+            Outcome::Answered(Response::Records(records)) => {
+                assert_eq!(records.len(), 1);
+                assert!(matches!(records[0].rdata, RData::V4([10, 0, 0, 9])));
+            }
+            _ => panic!("decided address not answered"),
+        }
+        assert!(matches!(
+            stage.resolve(&[question("mapped.test", 28)], None),
+            Outcome::Answered(Response::Records(records)) if records.is_empty()
+        ));
+        assert!(matches!(
+            stage.resolve(&[question("refused.test", 1)], None),
+            Outcome::Answered(Response::Rcode(0b0101))
+        ));
+        assert!(matches!(
+            stage.resolve(&[question("other.test", 1)], None),
+            Outcome::Passthrough
+        ));
+    }
+
+    #[test]
+    fn stages_run_in_the_configured_order() {
+        let hosts = hosts("order", "::1 known.test\n");
+        let aaaa = [question("known.test", 28)];
+
+        let default = pipeline(&DEFAULT_ORDER, hosts.clone());
+        assert_eq!(default.stages().collect::<Vec<_>>(), ["blackhole", "hosts"]);
+        assert!(matches!(
+            default.resolve(&aaaa, None),
+            Some(("blackhole", Response::Rcode(0)))
+        ));
+
+        let hosts_first = pipeline(&[StageKind::Hosts, StageKind::Blackhole], hosts);
+        assert_eq!(
+            hosts_first.stages().collect::<Vec<_>>(),
+            ["hosts", "blackhole"]
+        );
+        assert!(matches!(
+            hosts_first.resolve(&aaaa, None),
+            Some(("hosts", Response::Records(_)))
+        ));
+    }
+}
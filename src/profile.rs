@@ -0,0 +1,82 @@
+//! Extra listeners with policies of their own, e.g. a filtered port for some
+//! devices next to the unfiltered main one, served by the same process.
+//!
+//! A profile is a relay of its own: its socket, upstream, hosts, cache and
+//! queries in flight are not shared with the main listener or other
+//! profiles, so nothing answered for one can leak into another. Its only
+//! upstream is its own or the main listener's `upstream_addr`: the named,
+//! fallback and weighted upstreams belong to the main listener, lest a
+//! device on a filtered port reach an unfiltered upstream through them.
+//! Everything else is configured as for the main listener, apart from what
+//! there is one of per process: the control and metrics endpoints, the cache
+//! snapshot and the saved copy of hosts fetched from a url belong to the
+//! main listener.
+
+use std::env;
+
+use serde::Serialize;
+
+use crate::Config;
+
+/// A profile named in `PROFILES`, configured by the `PROFILE_<NAME>_*`
+/// variables. Only the listening address is required.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Profile {
+    pub name: String,
+    pub local_addr: String,
+    // the main listener's port would clash, so any free one by default
+    pub remote_addr: String,
+    pub upstream_addr: Option<String>,
+    pub hosts_path: Option<String>,
+    pub blocked_reason: Option<String>,
+    // for names blocked by the profile's own hosts
+    pub hosts_blocked_reason: Option<String>,
+}
+
+impl Profile {
+    pub fn from_env(name: &str) -> anyhow::Result<Self> {
+        let var =
+            |key: &str| env::var(format!("PROFILE_{}_{}", name.to_ascii_uppercase(), key)).ok();
+
+        Ok(Self {
+            name: name.to_owned(),
+            local_addr: var("LOCAL_ADDR").ok_or(anyhow::anyhow!(
+                "profile {} has no PROFILE_{}_LOCAL_ADDR",
+                name,
+                name.to_ascii_uppercase()
+            ))?,
+            remote_addr: var("REMOTE_ADDR").unwrap_or("0.0.0.0:0".to_owned()),
+            upstream_addr: var("UPSTREAM_ADDR"),
+            hosts_path: var("HOSTS_PATH"),
+            blocked_reason: var("BLOCKED_REASON").filter(|reason| !reason.is_empty()),
+            hosts_blocked_reason: var("HOSTS_BLOCKED_REASON").filter(|reason| !reason.is_empty()),
+        })
+    }
+
+    /// The config of the relay serving this profile.
+    pub fn config(&self, main: &Config) -> Config {
+        let mut config = main.clone();
+        config.local_addr = self.local_addr.clone();
+        config.remote_addr = self.remote_addr.clone();
+        if let Some(addr) = &self.upstream_addr {
+            config.upstream_addr = addr.clone();
+        }
+        if let Some(path) = &self.hosts_path {
+            config.hosts_path = path.clone();
+            // the main listener's reason is about its own hosts
+            config.hosts_blocked_reason = self.hosts_blocked_reason.clone();
+        }
+        if let Some(reason) = &self.blocked_reason {
+            config.blocked_reason = Some(reason.clone());
+        }
+        config.named_upstreams = Vec::new();
+        config.fallback_upstreams = Vec::new();
+        config.upstream_weights = Vec::new();
+        config.hosts_cache_path = None;
+        config.control_addr = None;
+        config.metrics_addr = None;
+        config.cache_snapshot_path = None;
+        config.profiles = Vec::new();
+        config
+    }
+}
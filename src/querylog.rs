@@ -0,0 +1,39 @@
+//! One line per question received, at info level with target `query`, e.g.
+//! `client=192.0.2.1 qname=example.com qtype=1`.
+//!
+//! With hashed names, the qname is replaced by a keyed hash of it: the same
+//! name always gives the same hash within a run, so repeats and frequencies
+//! can still be counted, but the key is random and never leaves the process.
+
+use std::{collections::hash_map::RandomState, hash::BuildHasher, net::SocketAddr};
+
+use tracing::info;
+
+use crate::packet::QuestionEntry;
+
+pub struct QueryLog {
+    // a fresh random key per process
+    salt: Option<RandomState>,
+}
+
+impl QueryLog {
+    pub fn new(hashed: bool) -> Self {
+        Self {
+            salt: hashed.then(RandomState::new),
+        }
+    }
+
+    pub fn log(&self, client: SocketAddr, questions: &[QuestionEntry]) {
+        for q in questions {
+            match &self.salt {
+                Some(salt) => {
+                    let hash = salt.hash_one(q.qname.to_ascii_lowercase());
+                    info!(target: "query", client = %client.ip(), qname = %format_args!("{:016x}", hash), qtype = q.qtype);
+                }
+                None => {
+                    info!(target: "query", client = %client.ip(), qname = %q.qname, qtype = q.qtype)
+                }
+            }
+        }
+    }
+}
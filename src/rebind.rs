@@ -0,0 +1,43 @@
+//! DNS rebinding protection: upstream answers pointing names on the internet
+//! at addresses inside the local network are not passed on to clients.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::packet;
+
+/// First private, loopback or link-local address among the A/AAAA records in
+/// the answer section of `buf`.
+pub fn private_addr(buf: &[u8]) -> Option<IpAddr> {
+    let ancount = u16::from_be_bytes([*buf.get(6)?, *buf.get(7)?]) as usize;
+
+    packet::records(buf)?
+        .iter()
+        .take(ancount)
+        .filter_map(|rr| match (rr.rtype, &buf[rr.rdata..rr.end]) {
+            (1, rdata) => <[u8; 4]>::try_from(rdata).ok().map(IpAddr::from),
+            (28, rdata) => <[u8; 16]>::try_from(rdata).ok().map(IpAddr::from),
+            _ => None,
+        })
+        .find(|ip| is_private(*ip))
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private_v4(ip),
+            None => is_private_v6(ip),
+        },
+    }
+}
+
+// RFC 1918, loopback, link-local and 0.0.0.0/8
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.octets()[0] == 0
+}
+
+// loopback, unspecified, RFC 4193 unique local and link-local
+fn is_private_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback() || ip.is_unspecified() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+}
@@ -0,0 +1,85 @@
+//! Pre-captured wire-format responses, served verbatim apart from the id.
+
+use std::{collections::HashMap, fs, str::FromStr};
+
+use serde::Serialize;
+use tracing::info;
+
+use crate::{packet, parse_qtype};
+
+/// Raw responses by lowercased name and qtype.
+pub type StaticResponses = HashMap<(String, u16), Vec<u8>>;
+
+/// A `name:qtype:path` mapping from the config.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StaticResponse {
+    pub qname: String,
+    pub qtype: u16,
+    pub path: String,
+}
+
+impl FromStr for StaticResponse {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(qname), Some(qtype), Some(path)) if !qname.is_empty() && !path.is_empty() => {
+                Ok(Self {
+                    qname: qname.trim_end_matches('.').to_ascii_lowercase(),
+                    qtype: parse_qtype(qtype)?,
+                    path: path.to_owned(),
+                })
+            }
+            _ => Err(anyhow::anyhow!("expected name:qtype:path, got {}", s)),
+        }
+    }
+}
+
+/// Reads every response file, failing on the first one that is not a single
+/// well-formed response to its own question.
+pub fn load(mappings: &[StaticResponse]) -> anyhow::Result<StaticResponses> {
+    let mut responses = StaticResponses::new();
+
+    for mapping in mappings {
+        let bytes = fs::read(&mapping.path)
+            .map_err(|e| anyhow::anyhow!("failed to read {}: {}", mapping.path, e))?;
+        validate(&bytes, mapping)
+            .map_err(|e| anyhow::anyhow!("invalid response in {}: {}", mapping.path, e))?;
+
+        info!(
+            "serving {} type {} from {}",
+            mapping.qname, mapping.qtype, mapping.path
+        );
+        responses.insert((mapping.qname.clone(), mapping.qtype), bytes);
+    }
+
+    Ok(responses)
+}
+
+fn validate(bytes: &[u8], mapping: &StaticResponse) -> anyhow::Result<()> {
+    let len = packet::message_len(bytes).ok_or(anyhow::anyhow!("malformed message"))?;
+    anyhow::ensure!(
+        len == bytes.len(),
+        "{} trailing bytes after the message",
+        bytes.len() - len
+    );
+    anyhow::ensure!(bytes[2] & 0b1000_0000 != 0, "not a response");
+    anyhow::ensure!(
+        u16::from_be_bytes([bytes[4], bytes[5]]) == 1,
+        "expected exactly one question"
+    );
+
+    let qname = packet::encode_name(&mapping.qname)?;
+    let question = &bytes[12..];
+    anyhow::ensure!(
+        question.len() >= qname.len() + 2
+            && question[..qname.len()].eq_ignore_ascii_case(&qname)
+            && question[qname.len()..qname.len() + 2] == mapping.qtype.to_be_bytes(),
+        "the question is not {} type {}",
+        mapping.qname,
+        mapping.qtype
+    );
+
+    Ok(())
+}
@@ -0,0 +1,365 @@
+//! Response rate limiting (RRL) against reflection attacks, after BIND's.
+//!
+//! Identical responses (same question, rcode and whether they hold answers) to
+//! the same client network are counted over a sliding window. Beyond the
+//! configured rate the client by default only gets the header and question
+//! with TC set: a real client retries over TCP, a spoofed victim receives
+//! nothing larger than the query that was sent in its name.
+//!
+//! Clients known to send a lot, e.g. the NAT gateway of an office, can be
+//! given a rate of their own. Each of them is counted on its own address
+//! rather than with its network, so that the neighbours do not share it.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{packet, views::Cidr};
+
+// most responses tracked at once; past it the idle ones are dropped, at most
+// once a window, and responses not tracked yet count as over the rate
+const MAX_ENTRIES: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RrlAction {
+    /// header and question with TC set
+    Truncate,
+    /// nothing at all
+    Drop,
+    /// REFUSED, with an EDE "Prohibited" (RFC 8914) if the client uses EDNS
+    Refuse,
+}
+
+impl FromStr for RrlAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "truncate" => Ok(RrlAction::Truncate),
+            "drop" => Ok(RrlAction::Drop),
+            "refuse" => Ok(RrlAction::Refuse),
+            _ => Err(anyhow::anyhow!("unknown RRL action: {}", s)),
+        }
+    }
+}
+
+/// A `<cidr>=<rate>` mapping from the config: clients in the network get
+/// that rate instead of the default one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RrlClient {
+    pub network: String,
+    pub rate: u32,
+}
+
+impl FromStr for RrlClient {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, rate) = s
+            .split_once('=')
+            .ok_or(anyhow::anyhow!("expected cidr=rate, got {}", s))?;
+        network.parse::<Cidr>()?;
+        let rate: u32 = rate.parse()?;
+        anyhow::ensure!(rate > 0, "the RRL rate of {} must be positive", network);
+
+        Ok(Self {
+            network: network.to_owned(),
+            rate,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct Key {
+    net: IpAddr,
+    // the question section, lowercased
+    question: Vec<u8>,
+    rcode: u8,
+    empty: bool,
+}
+
+/// The windows of the responses tracked, and when the idle ones were last
+/// dropped.
+#[derive(Debug)]
+struct Tracked {
+    windows: HashMap<Key, Window>,
+    swept: Instant,
+}
+
+/// Responses counted in the current window and the one before, which is
+/// weighted by how much of it the sliding window still covers.
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    current: u32,
+    previous: u32,
+}
+
+#[derive(Debug)]
+pub struct Rrl {
+    // responses allowed per window
+    limit: f64,
+    window: Duration,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    action: RrlAction,
+    // networks with a limit of their own
+    clients: Vec<(Cidr, f64)>,
+    max_entries: usize,
+    tracked: Mutex<Tracked>,
+}
+
+impl Rrl {
+    pub fn new(
+        rate: u32,
+        window: u64,
+        ipv4_prefix: u8,
+        ipv6_prefix: u8,
+        action: RrlAction,
+        clients: &[RrlClient],
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(rate > 0, "the RRL rate must be positive");
+        anyhow::ensure!(window > 0, "the RRL window must be positive");
+        anyhow::ensure!(
+            ipv4_prefix <= 32,
+            "invalid RRL ipv4 prefix: {}",
+            ipv4_prefix
+        );
+        anyhow::ensure!(
+            ipv6_prefix <= 128,
+            "invalid RRL ipv6 prefix: {}",
+            ipv6_prefix
+        );
+
+        Ok(Self {
+            limit: rate as f64 * window as f64,
+            window: Duration::from_secs(window),
+            ipv4_prefix,
+            ipv6_prefix,
+            action,
+            clients: clients
+                .iter()
+                .map(|client| Ok((client.network.parse()?, client.rate as f64 * window as f64)))
+                .collect::<anyhow::Result<_>>()?,
+            max_entries: MAX_ENTRIES,
+            tracked: Mutex::new(Tracked {
+                windows: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        })
+    }
+
+    pub fn action(&self) -> RrlAction {
+        self.action
+    }
+
+    /// Counts `resp` as sent to `client` and tells whether it is still within
+    /// the rate. Malformed responses are never limited.
+    ///
+    /// A flood of spoofed sources fills the table with live entries that no
+    /// sweep can drop. Rather than scanning it for every response, sweeps
+    /// run at most once a window, and until one frees room the responses not
+    /// tracked yet are limited.
+    pub fn allows(&self, client: IpAddr, resp: &[u8]) -> bool {
+        let Some(question_end) = packet::question_end(resp) else {
+            return true;
+        };
+        let (net, limit) = match self.clients.iter().find(|(cidr, _)| cidr.contains(client)) {
+            Some((_, limit)) => (client, *limit),
+            None => (self.network(client), self.limit),
+        };
+        let key = Key {
+            net,
+            question: resp[12..question_end].to_ascii_lowercase(),
+            rcode: resp[3] & 0b0000_1111,
+            empty: resp[6..8] == [0, 0],
+        };
+
+        let now = Instant::now();
+        let mut tracked = self.tracked.lock().unwrap();
+        if tracked.windows.len() >= self.max_entries
+            && now.duration_since(tracked.swept) >= self.window
+        {
+            tracked
+                .windows
+                .retain(|_, w| now.duration_since(w.start) < self.window * 2);
+            tracked.swept = now;
+        }
+        if tracked.windows.len() >= self.max_entries && !tracked.windows.contains_key(&key) {
+            return false;
+        }
+        let w = tracked.windows.entry(key).or_insert(Window {
+            start: now,
+            current: 0,
+            previous: 0,
+        });
+
+        let passed = (now.duration_since(w.start).as_secs_f64() / self.window.as_secs_f64()) as u32;
+        if passed > 0 {
+            w.previous = if passed == 1 { w.current } else { 0 };
+            w.current = 0;
+            w.start += self.window * passed;
+        }
+        let covered = 1.0 - now.duration_since(w.start).as_secs_f64() / self.window.as_secs_f64();
+        let estimate = w.previous as f64 * covered + w.current as f64;
+        w.current = w.current.saturating_add(1);
+
+        estimate < limit
+    }
+
+    fn network(&self, addr: IpAddr) -> IpAddr {
+        match addr {
+            IpAddr::V4(v4) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.ipv4_prefix as u32)
+                    .unwrap_or(0);
+                IpAddr::from((u32::from(v4) & mask).to_be_bytes())
+            }
+            IpAddr::V6(v6) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.ipv6_prefix as u32)
+                    .unwrap_or(0);
+                IpAddr::from((u128::from(v6) & mask).to_be_bytes())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rrl(rate: u32, ipv4_prefix: u8, ipv6_prefix: u8, clients: &[&str]) -> Rrl {
+        let clients: Vec<RrlClient> = clients.iter().map(|c| c.parse().unwrap()).collect();
+        Rrl::new(
+            rate,
+            1,
+            ipv4_prefix,
+            ipv6_prefix,
+            RrlAction::Truncate,
+            &clients,
+        )
+        .unwrap()
+    }
+
+    fn response(name: &str) -> Vec<u8> {
+        let mut resp = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 0, 0, 0, 0, 0];
+        resp.extend_from_slice(&packet::encode_name(name).unwrap());
+        resp.extend_from_slice(&[0, 1, 0, 1]);
+        resp
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    /// Moves every window, and the last sweep, `windows` windows back.
+    fn backdate(rrl: &Rrl, windows: u32) {
+        let mut tracked = rrl.tracked.lock().unwrap();
+        tracked.swept = tracked.swept.checked_sub(rrl.window * windows).unwrap();
+        for w in tracked.windows.values_mut() {
+            w.start = w.start.checked_sub(rrl.window * windows).unwrap();
+        }
+    }
+
+    /// How many of `count` identical responses to `client` are allowed.
+    fn allowed(rrl: &Rrl, client: &str, resp: &[u8], count: usize) -> usize {
+        (0..count).filter(|_| rrl.allows(ip(client), resp)).count()
+    }
+
+    #[test]
+    fn bursts_over_the_rate_are_limited() {
+        let rrl = rrl(5, 24, 56, &[]);
+        let resp = response("rrl.test");
+
+        assert_eq!(allowed(&rrl, "192.0.2.1", &resp, 8), 5);
+        // other responses are counted apart
+        assert_eq!(allowed(&rrl, "192.0.2.1", &response("other.test"), 1), 1);
+        assert_eq!(allowed(&rrl, "192.0.2.1", &response("RRL.test"), 1), 0);
+        assert!(rrl.allows(ip("192.0.2.1"), &[0x12, 0x34]), "malformed");
+    }
+
+    #[test]
+    fn the_rate_recovers_after_the_window() {
+        let rrl = rrl(5, 24, 56, &[]);
+        let resp = response("rrl.test");
+        assert_eq!(allowed(&rrl, "192.0.2.1", &resp, 6), 5);
+
+        backdate(&rrl, 2);
+
+        assert_eq!(allowed(&rrl, "192.0.2.1", &resp, 6), 5);
+    }
+
+    #[test]
+    fn a_network_shares_one_budget() {
+        let rrl = rrl(5, 24, 56, &[]);
+        let resp = response("rrl.test");
+
+        assert_eq!(allowed(&rrl, "192.0.2.1", &resp, 3), 3);
+        assert_eq!(allowed(&rrl, "192.0.2.200", &resp, 3), 2);
+        assert_eq!(allowed(&rrl, "192.0.3.1", &resp, 3), 3);
+        assert_eq!(allowed(&rrl, "2001:db8:0:1::1", &resp, 3), 3);
+        assert_eq!(allowed(&rrl, "2001:db8:0:1::2", &resp, 3), 2);
+    }
+
+    #[test]
+    fn prefixes_mask_the_client_address() {
+        let cases = [
+            (24, 56, "192.0.2.77", "192.0.2.0"),
+            (24, 56, "2001:db8:aa:bbcc::1", "2001:db8:aa:bb00::"),
+            (0, 0, "192.0.2.77", "0.0.0.0"),
+            (0, 0, "2001:db8::1", "::"),
+            (32, 128, "192.0.2.77", "192.0.2.77"),
+            (32, 128, "2001:db8::1", "2001:db8::1"),
+            (17, 33, "192.0.255.1", "192.0.128.0"),
+            (17, 33, "2001:db8:ffff::1", "2001:db8:8000::"),
+        ];
+        for (ipv4_prefix, ipv6_prefix, addr, network) in cases {
+            let rrl = rrl(5, ipv4_prefix, ipv6_prefix, &[]);
+            assert_eq!(rrl.network(ip(addr)), ip(network), "{}", addr);
+        }
+        assert!(Rrl::new(5, 1, 33, 56, RrlAction::Drop, &[]).is_err());
+        assert!(Rrl::new(5, 1, 24, 129, RrlAction::Drop, &[]).is_err());
+    }
+
+    #[test]
+    fn clients_with_a_rate_of_their_own() {
+        let rrl = rrl(2, 24, 56, &["192.0.2.0/28=4"]);
+        let resp = response("rrl.test");
+
+        // counted on their own address, with their own rate
+        assert_eq!(allowed(&rrl, "192.0.2.1", &resp, 6), 4);
+        assert_eq!(allowed(&rrl, "192.0.2.2", &resp, 6), 4);
+        // the rest of the network keeps the default
+        assert_eq!(allowed(&rrl, "192.0.2.100", &resp, 6), 2);
+        assert_eq!(allowed(&rrl, "192.0.2.101", &resp, 6), 0);
+    }
+
+    #[test]
+    fn a_full_table_limits_new_responses_until_swept() {
+        let mut rrl = rrl(5, 24, 56, &[]);
+        rrl.max_entries = 3;
+        let resp = response("rrl.test");
+
+        for client in ["192.0.2.1", "192.0.3.1", "192.0.4.1"] {
+            assert_eq!(allowed(&rrl, client, &resp, 1), 1);
+        }
+        // live entries, so a sweep frees nothing
+        backdate(&rrl, 1);
+        assert_eq!(allowed(&rrl, "192.0.5.1", &resp, 1), 0);
+        assert_eq!(allowed(&rrl, "192.0.2.1", &resp, 1), 1, "tracked already");
+        assert_eq!(rrl.tracked.lock().unwrap().windows.len(), 3);
+
+        // idle ones go, though only once a window has passed since the sweep
+        backdate(&rrl, 2);
+        assert_eq!(allowed(&rrl, "192.0.5.1", &resp, 1), 1);
+        assert_eq!(rrl.tracked.lock().unwrap().windows.len(), 1);
+    }
+}
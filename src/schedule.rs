@@ -0,0 +1,65 @@
+//! Names whose address changes on a schedule, for exercising client failover.
+
+use std::{
+    net::IpAddr,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+/// A `name:period:ip|ip|...` mapping from the config. The addresses take
+/// turns, each active for `period` seconds. The switches are counted from the
+/// Unix epoch, so every relay switches at the same moments.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Schedule {
+    pub qname: String,
+    pub period: u64,
+    pub addrs: Vec<IpAddr>,
+}
+
+impl Schedule {
+    /// The address active at `now`, with the seconds left until the next
+    /// switch as its TTL.
+    pub fn active(&self, now: SystemTime) -> (IpAddr, u32) {
+        let secs = now
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or_default();
+        let slot = secs / self.period % self.addrs.len() as u64;
+        let ttl = self.period - secs % self.period;
+
+        (
+            self.addrs[slot as usize],
+            ttl.try_into().unwrap_or(u32::MAX),
+        )
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("expected name:period:ip|ip|..., got {}", s);
+        // addresses come last, as IPv6 ones have colons of their own
+        let mut parts = s.splitn(3, ':');
+        let (Some(qname), Some(period), Some(addrs)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let period: u64 = period.parse().map_err(|_| invalid())?;
+        let addrs = addrs
+            .split('|')
+            .map(|addr| addr.trim().parse())
+            .collect::<Result<Vec<IpAddr>, _>>()
+            .map_err(|_| invalid())?;
+        anyhow::ensure!(!qname.is_empty(), "no name in {}", s);
+        anyhow::ensure!(period > 0, "the period must be positive in {}", s);
+
+        Ok(Self {
+            qname: qname.trim_end_matches('.').to_ascii_lowercase(),
+            period,
+            addrs,
+        })
+    }
+}
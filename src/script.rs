@@ -0,0 +1,192 @@
+//! Policy decided by a Rhai script, for rules too dynamic for the hosts.
+//!
+//! The script defines `fn decide(qname, qtype, client)`, called with the
+//! lowercased name without its trailing dot, the qtype as a number and the
+//! client's address as a string, empty when unknown. It returns one of:
+//!
+//! - an IPv4 or IPv6 address, to answer A or AAAA queries with it and the
+//!   other types with NODATA
+//! - `"block"`, answered like a name blocked in the hosts
+//! - `"refuse"`, answered with REFUSED
+//! - `"forward"` or `()`, to leave the query to upstream
+//!
+//! ```text
+//! fn decide(qname, qtype, client) {
+//!     if qname.ends_with(".ads.example") { return "block"; }
+//!     if client.starts_with("10.0.9.") && qname == "printer.lan" { return "10.0.9.5"; }
+//! }
+//! ```
+//!
+//! A script running longer than its timeout is stopped and the query
+//! forwarded, as is one that fails or returns anything else.
+
+use std::{cell::Cell, fmt, net::IpAddr, time::Duration, time::Instant};
+
+use rhai::{Dynamic, Engine, Scope, AST};
+use tracing::{info, warn};
+
+thread_local! {
+    // when the call running on this thread is stopped; calls never span threads
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Answer(IpAddr),
+    Block,
+    Refuse,
+    Forward,
+}
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    timeout: Duration,
+}
+
+impl Script {
+    pub fn load(path: &str, timeout: Duration) -> anyhow::Result<Self> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read script {}: {}", path, e))?;
+        let script = Self::compile(&source, timeout)
+            .map_err(|e| anyhow::anyhow!("invalid script {}: {}", path, e))?;
+        info!("loaded script {}", path);
+
+        Ok(script)
+    }
+
+    fn compile(source: &str, timeout: Duration) -> anyhow::Result<Self> {
+        let mut engine = Engine::new();
+        engine.on_progress(|_| {
+            DEADLINE
+                .get()
+                .filter(|deadline| Instant::now() >= *deadline)
+                .map(|_| Dynamic::UNIT)
+        });
+        let ast = engine.compile(source)?;
+        anyhow::ensure!(
+            ast.iter_functions()
+                .any(|f| f.name == "decide" && f.params.len() == 3),
+            "no decide(qname, qtype, client) function"
+        );
+
+        Ok(Self {
+            engine,
+            ast,
+            timeout,
+        })
+    }
+
+    pub fn decide(&self, qname: &str, qtype: u16, client: Option<IpAddr>) -> Decision {
+        let qname = qname.trim_end_matches('.').to_ascii_lowercase();
+        let client = client.map(|client| client.to_string()).unwrap_or_default();
+
+        DEADLINE.set(Some(Instant::now() + self.timeout));
+        let result = self.engine.call_fn::<Dynamic>(
+            &mut Scope::new(),
+            &self.ast,
+            "decide",
+            (qname.clone(), qtype as i64, client),
+        );
+        DEADLINE.set(None);
+
+        let decision = match result {
+            Ok(decision) => decision,
+            Err(e) => {
+                warn!("script failed for {}: {}", qname, e);
+                return Decision::Forward;
+            }
+        };
+        if decision.is_unit() {
+            return Decision::Forward;
+        }
+        match decision.into_string().as_deref() {
+            Ok("block") => Decision::Block,
+            Ok("refuse") => Decision::Refuse,
+            Ok("forward") => Decision::Forward,
+            Ok(other) => match other.parse() {
+                Ok(ip) => Decision::Answer(ip),
+                Err(_) => {
+                    warn!("script decided {:?} for {}, forwarding it", other, qname);
+                    Decision::Forward
+                }
+            },
+            Err(kind) => {
+                warn!("script returned a {} for {}, forwarding it", kind, qname);
+                Decision::Forward
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Script")
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn decisions() {
+        let script = Script::compile(
+            r#"
+            fn decide(qname, qtype, client) {
+                if qname == "blocked.test" { return "block"; }
+                if qname == "refused.test" { return "refuse"; }
+                if qname == "kids.test" && client == "10.0.0.7" { return "10.0.0.1"; }
+                if qname == "v6.test" { return "::1"; }
+                if qname == "typed.test" && qtype == 16 { return "forward"; }
+                if qname == "odd.test" { return 42; }
+            }
+            "#,
+            TIMEOUT,
+        )
+        .unwrap();
+        let kid = Some("10.0.0.7".parse().unwrap());
+
+        assert_eq!(script.decide("Blocked.test.", 1, None), Decision::Block);
+        assert_eq!(script.decide("refused.test", 1, None), Decision::Refuse);
+        assert_eq!(
+            script.decide("kids.test", 1, kid),
+            Decision::Answer("10.0.0.1".parse().unwrap())
+        );
+        assert_eq!(script.decide("kids.test", 1, None), Decision::Forward);
+        assert_eq!(
+            script.decide("v6.test", 28, None),
+            Decision::Answer("::1".parse().unwrap())
+        );
+        assert_eq!(script.decide("typed.test", 16, None), Decision::Forward);
+        assert_eq!(script.decide("odd.test", 1, None), Decision::Forward);
+        assert_eq!(script.decide("other.test", 1, None), Decision::Forward);
+    }
+
+    #[test]
+    fn slow_scripts_are_stopped() {
+        let script = Script::compile(
+            r#"
+            fn decide(qname, qtype, client) {
+                loop {}
+            }
+            "#,
+            TIMEOUT,
+        )
+        .unwrap();
+
+        let start = Instant::now();
+        assert_eq!(script.decide("slow.test", 1, None), Decision::Forward);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn scripts_without_decide_are_rejected() {
+        assert!(Script::compile("fn other(qname) { () }", TIMEOUT).is_err());
+        assert!(Script::compile("fn decide(qname) {", TIMEOUT).is_err());
+    }
+}
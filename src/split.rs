@@ -0,0 +1,469 @@
+//! Queries answered partly locally and partly upstream. The questions of a
+//! multi-question query the relay cannot answer go upstream on their own, as
+//! does the target of a local alias to an outside name. Either way upstream's
+//! response is merged with the local answers before it is sent back.
+//!
+//! The client gets its question section back as it sent it. The answer
+//! section holds the local answers followed by upstream's, so an alias comes
+//! before what it leads to: for a chain of local aliases, each CNAME in the
+//! order they are followed from the queried name, then upstream's answers
+//! for the last target. The authority and additional sections are
+//! upstream's. The rcode is upstream's too, as there is no way to give one
+//! per question.
+//!
+//! Upstream compressed its names against a question section the client never
+//! sees, so every pointer in its records is remapped. Pointers into its
+//! question section move to the same name in the client's, or in the alias'
+//! rdata. Pointers into its records shift by as much as those records moved.
+//! Names in rdata are followed for the types RFC 3597 4 allows to be
+//! compressed, the only ones a conforming upstream compresses.
+
+use crate::packet::{self, Compression, Message, QuestionEntry, ResourceRecord};
+
+// NS, MD, MF, CNAME, SOA, MB, MG, MR, PTR, MINFO, MX
+const COMPRESSIBLE: [u16; 11] = [2, 3, 4, 5, 6, 7, 8, 9, 12, 14, 15];
+
+/// What is needed to merge upstream's response with the local answers.
+#[derive(Debug)]
+pub struct Split {
+    qdcount: u16,
+    // the client's question section
+    question: Vec<u8>,
+    // (start in the forwarded query, start in the response, length) of each
+    // forwarded name
+    forwarded: Vec<(usize, usize, usize)>,
+    ancount: u16,
+    answers: Vec<u8>,
+}
+
+impl Split {
+    /// Rewrites the query in `buf[..len]` to ask only the `forwarded`
+    /// questions, keeping whatever followed the question section. Returns the
+    /// new length, or `None` with `buf` untouched if the query is malformed.
+    pub fn new(
+        buf: &mut [u8],
+        len: usize,
+        questions: &[QuestionEntry],
+        forwarded: &[usize],
+        answers: Vec<ResourceRecord>,
+        compression: Compression,
+    ) -> Option<(usize, Self)> {
+        let question_end = packet::question_end(&buf[..len])?;
+        let (ancount, answers) = local(buf, question_end, answers, compression);
+
+        let mut asked = Vec::new();
+        for &i in forwarded {
+            let start = questions.get(i)?.offset;
+            let end = packet::skip_name(&buf[..question_end], start)?;
+            asked.push((buf.get(start..end + 4)?.to_vec(), start));
+        }
+
+        Self::rewrite(buf, len, question_end, asked, ancount, answers)
+    }
+
+    /// Rewrites the single-question query in `buf[..len]` to ask for
+    /// `target` instead, `answers` ending with the CNAME to it. Returns the
+    /// new length, or `None` with `buf` untouched if the query is malformed.
+    pub fn chase(
+        buf: &mut [u8],
+        len: usize,
+        answers: Vec<ResourceRecord>,
+        target: &[u8],
+        compression: Compression,
+    ) -> Option<(usize, Self)> {
+        let question_end = packet::question_end(&buf[..len])?;
+        // the target is spelled out, for the forwarded question to point to
+        let compression = match compression {
+            Compression::None => Compression::None,
+            _ => Compression::Owners,
+        };
+        let (ancount, answers) = local(buf, question_end, answers, compression);
+        if !answers.ends_with(target) {
+            return None;
+        }
+
+        let mut question = target.to_vec();
+        question.extend_from_slice(&buf[question_end - 4..question_end]);
+        let to = question_end + answers.len() - target.len();
+
+        Self::rewrite(
+            buf,
+            len,
+            question_end,
+            vec![(question, to)],
+            ancount,
+            answers,
+        )
+    }
+
+    /// Replaces the question section with the `asked` questions, each with
+    /// where its name is found in the response.
+    fn rewrite(
+        buf: &mut [u8],
+        len: usize,
+        question_end: usize,
+        asked: Vec<(Vec<u8>, usize)>,
+        ancount: u16,
+        answers: Vec<u8>,
+    ) -> Option<(usize, Self)> {
+        let mut query = buf[..12].to_vec();
+        query[4..6].copy_from_slice(&(asked.len() as u16).to_be_bytes());
+        let mut forwarded = Vec::new();
+        for (question, to) in asked {
+            forwarded.push((query.len(), to, question.len() - 4));
+            query.extend_from_slice(&question);
+        }
+        query.extend_from_slice(&buf[question_end..len]);
+        if query.len() > buf.len() {
+            return None;
+        }
+
+        let split = Self {
+            qdcount: u16::from_be_bytes([buf[4], buf[5]]),
+            question: buf[12..question_end].to_vec(),
+            forwarded,
+            ancount,
+            answers,
+        };
+        buf[..query.len()].copy_from_slice(&query);
+        Some((query.len(), split))
+    }
+
+    /// The response for the client, or `None` if upstream's is malformed or
+    /// does not answer the questions it was asked.
+    pub fn merge(&self, resp: &[u8]) -> Option<Vec<u8>> {
+        let count = |i: usize| u16::from_be_bytes([resp[i], resp[i + 1]]);
+        if count(4) as usize != self.forwarded.len() {
+            return None;
+        }
+        let question_end = packet::question_end(resp)?;
+        let records = packet::records(resp)?;
+
+        let records_start = 12 + self.question.len() + self.answers.len();
+        let remap = |offset: usize| -> Option<usize> {
+            if offset < question_end {
+                let (from, to, _) = self
+                    .forwarded
+                    .iter()
+                    .find(|(from, _, len)| (*from..from + len).contains(&offset))?;
+                Some(to + offset - from)
+            } else {
+                Some(offset - question_end + records_start)
+            }
+        };
+
+        let mut patched = resp.to_vec();
+        let mut start = question_end;
+        for rr in &records {
+            remap_name(&mut patched, start, remap)?;
+            if COMPRESSIBLE.contains(&rr.rtype) {
+                let mut i = match rr.rtype {
+                    15 => rr.rdata + 2,
+                    _ => rr.rdata,
+                };
+                // SOA and MINFO start with two names
+                for _ in 0..if matches!(rr.rtype, 6 | 14) { 2 } else { 1 } {
+                    remap_name(&mut patched, i, remap)?;
+                    i = packet::skip_name(&patched[..rr.end], i)?;
+                }
+            }
+            start = rr.end;
+        }
+
+        let mut merged = patched[..12].to_vec();
+        merged[4..6].copy_from_slice(&self.qdcount.to_be_bytes());
+        merged[6..8].copy_from_slice(&(count(6) + self.ancount).to_be_bytes());
+        merged.extend_from_slice(&self.question);
+        merged.extend_from_slice(&self.answers);
+        merged.extend_from_slice(&patched[question_end..]);
+        Some(merged)
+    }
+
+    /// SERVFAIL for the client's question, for when upstream's response
+    /// cannot be merged.
+    pub fn failure(&self, header: &[u8]) -> Vec<u8> {
+        let mut resp = header[..12].to_vec();
+        resp[3] = (resp[3] & 0b1111_0000) | 0b0010;
+        resp[4..6].copy_from_slice(&self.qdcount.to_be_bytes());
+        resp[6..12].fill(0);
+        resp.extend_from_slice(&self.question);
+        resp
+    }
+}
+
+/// The local answers written right after the question in `buf`, where they
+/// stay in the response, with their count.
+fn local(
+    buf: &[u8],
+    question_end: usize,
+    answers: Vec<ResourceRecord>,
+    compression: Compression,
+) -> (u16, Vec<u8>) {
+    let size: usize = answers.iter().map(|rr| 12 + rr.rdata.len()).sum();
+    let mut local = buf[..question_end].to_vec();
+    local.resize(question_end + size + 255 * answers.len(), 0);
+    let mut msg = Message::new(&mut local, question_end);
+    let ancount = msg.answer.add_entries(answers, compression);
+    let end = question_end + msg.answer.len();
+    (ancount, local[question_end..end].to_vec())
+}
+
+/// Rewrites the pointer ending the name at `i`, if it has one.
+fn remap_name(buf: &mut [u8], mut i: usize, remap: impl Fn(usize) -> Option<usize>) -> Option<()> {
+    loop {
+        let len = *buf.get(i)?;
+        match len {
+            0 => return Some(()),
+            len if len & 0b1100_0000 == 0b1100_0000 => {
+                let pointer = u16::from_be_bytes([len, *buf.get(i + 1)?]) & 0x3fff;
+                let pointer = u16::try_from(remap(pointer as usize)?)
+                    .ok()
+                    .filter(|pointer| *pointer <= 0x3fff)?;
+                buf[i..i + 2].copy_from_slice(&(0b1100_0000_0000_0000 | pointer).to_be_bytes());
+                return Some(());
+            }
+            len => i += 1 + len as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::RData;
+
+    fn name(name: &str) -> Vec<u8> {
+        packet::encode_name(name).unwrap()
+    }
+
+    /// `labels` followed by a pointer to `offset`.
+    fn compressed(labels: &str, offset: usize) -> Vec<u8> {
+        let mut name = name(labels);
+        name.pop();
+        name.extend_from_slice(&(0xc000 | offset as u16).to_be_bytes());
+        name
+    }
+
+    /// Appends a record and returns where its rdata starts.
+    fn push_record(buf: &mut Vec<u8>, owner: &[u8], rtype: u16, rdata: &[u8]) -> usize {
+        buf.extend_from_slice(owner);
+        buf.extend_from_slice(&rtype.to_be_bytes());
+        buf.extend_from_slice(&[0, 1, 0, 0, 0x0e, 0x10]);
+        buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        buf.extend_from_slice(rdata);
+        buf.len() - rdata.len()
+    }
+
+    /// The name at `i`, pointers followed.
+    fn name_at(buf: &[u8], mut i: usize) -> String {
+        let mut labels = Vec::new();
+        for _ in 0..64 {
+            match buf[i] as usize {
+                0 => return labels.join("."),
+                len if len & 0xc0 == 0xc0 => {
+                    i = (u16::from_be_bytes([buf[i], buf[i + 1]]) & 0x3fff) as usize;
+                }
+                len => {
+                    labels.push(String::from_utf8_lossy(&buf[i + 1..i + 1 + len]).into_owned());
+                    i += 1 + len;
+                }
+            }
+        }
+        panic!("pointer loop at {}", i);
+    }
+
+    /// Every record as its owner, type and rdata in presentation form.
+    fn records(buf: &[u8]) -> Vec<String> {
+        let mut start = packet::question_end(buf).unwrap();
+        let mut records = Vec::new();
+        for rr in packet::records(buf).unwrap() {
+            let rdata = match rr.rtype {
+                1 => buf[rr.rdata..rr.end]
+                    .iter()
+                    .map(u8::to_string)
+                    .collect::<Vec<_>>()
+                    .join("."),
+                5 => name_at(buf, rr.rdata),
+                6 => {
+                    let rname = packet::skip_name(buf, rr.rdata).unwrap();
+                    format!("{} {}", name_at(buf, rr.rdata), name_at(buf, rname))
+                }
+                15 => format!(
+                    "{} {}",
+                    u16::from_be_bytes([buf[rr.rdata], buf[rr.rdata + 1]]),
+                    name_at(buf, rr.rdata + 2)
+                ),
+                rtype => panic!("unexpected type {}", rtype),
+            };
+            records.push(format!("{} {} {}", name_at(buf, start), rr.rtype, rdata));
+            start = rr.end;
+        }
+        records
+    }
+
+    fn header(id: u16, flags: u8, counts: [u16; 4]) -> Vec<u8> {
+        let mut buf = id.to_be_bytes().to_vec();
+        buf.extend_from_slice(&[flags, 0]);
+        for count in counts {
+            buf.extend_from_slice(&count.to_be_bytes());
+        }
+        buf
+    }
+
+    fn soa(zone_offset: usize) -> Vec<u8> {
+        let mut rdata = compressed("ns1", zone_offset);
+        rdata.extend(compressed("hostmaster", zone_offset));
+        rdata.extend_from_slice(&[0; 20]);
+        rdata
+    }
+
+    #[test]
+    fn questions_answered_upstream_are_merged_with_local_ones() {
+        let mut buf = header(0x1234, 0x01, [2, 0, 0, 0]);
+        buf.extend(name("local.test"));
+        buf.extend_from_slice(&[0, 1, 0, 1]);
+        let second = buf.len();
+        buf.extend(name("www.remote.example"));
+        buf.extend_from_slice(&[0, 15, 0, 1]);
+        let client_question = buf[12..].to_vec();
+        let questions = [
+            QuestionEntry {
+                offset: 12,
+                qname: "local.test".into(),
+                qtype: 1,
+                qclass: 1,
+            },
+            QuestionEntry {
+                offset: second,
+                qname: "www.remote.example".into(),
+                qtype: 15,
+                qclass: 1,
+            },
+        ];
+        let answers = vec![ResourceRecord {
+            name: 0xc00c,
+            rtype: 1,
+            rclass: 1,
+            ttl: 60,
+            rdlength: 4,
+            rdata: RData::V4([10, 0, 0, 1]),
+        }];
+        let len = buf.len();
+        buf.resize(512, 0);
+
+        let (len, split) = Split::new(
+            &mut buf,
+            len,
+            &questions,
+            &[1],
+            answers,
+            Compression::Owners,
+        )
+        .unwrap();
+        let mut expected = header(0x1234, 0x01, [1, 0, 0, 0]);
+        expected.extend(name("www.remote.example"));
+        expected.extend_from_slice(&[0, 15, 0, 1]);
+        assert_eq!(&buf[..len], expected.as_slice());
+
+        // upstream compresses against the forwarded question, at 12
+        let remote = 12 + 4;
+        let mut resp = header(0x1234, 0x81, [1, 2, 1, 0]);
+        resp.extend_from_slice(&buf[12..len]);
+        let target = push_record(&mut resp, &[0xc0, 12], 5, &compressed("mail", remote));
+        let mut mx = vec![0, 10];
+        mx.extend(compressed("mx1", remote));
+        push_record(&mut resp, &(0xc000 | target as u16).to_be_bytes(), 15, &mx);
+        push_record(
+            &mut resp,
+            &(0xc000 | remote as u16).to_be_bytes(),
+            6,
+            &soa(remote),
+        );
+
+        let merged = split.merge(&resp).unwrap();
+        assert_eq!(&merged[..4], &[0x12, 0x34, 0x81, 0x00]);
+        assert_eq!(&merged[4..12], &[0, 2, 0, 3, 0, 1, 0, 0]);
+        assert_eq!(&merged[12..12 + client_question.len()], client_question);
+        assert_eq!(
+            records(&merged),
+            [
+                "local.test 1 10.0.0.1",
+                "www.remote.example 5 mail.remote.example",
+                "mail.remote.example 15 10 mx1.remote.example",
+                "remote.example 6 ns1.remote.example hostmaster.remote.example",
+            ]
+        );
+
+        // upstream must answer the one question it was asked
+        resp[5] = 2;
+        assert!(split.merge(&resp).is_none());
+    }
+
+    #[test]
+    fn chased_aliases_are_merged_with_upstreams_answers() {
+        let mut buf = header(0x1234, 0x01, [1, 0, 0, 0]);
+        buf.extend(name("alias.test"));
+        buf.extend_from_slice(&[0, 15, 0, 1]);
+        let client_question = buf[12..].to_vec();
+        let target = name("target.example");
+        let answers = vec![ResourceRecord {
+            name: 0xc00c,
+            rtype: 5,
+            rclass: 1,
+            ttl: 60,
+            rdlength: target.len() as u16,
+            rdata: RData::Opaque(target.clone()),
+        }];
+        let len = buf.len();
+        buf.resize(512, 0);
+
+        let (len, split) = Split::chase(&mut buf, len, answers, &target, Compression::All).unwrap();
+        let mut expected = header(0x1234, 0x01, [1, 0, 0, 0]);
+        expected.extend(name("target.example"));
+        expected.extend_from_slice(&[0, 15, 0, 1]);
+        assert_eq!(&buf[..len], expected.as_slice());
+
+        let example = 12 + 7;
+        let mut resp = header(0x1234, 0x81, [1, 2, 1, 0]);
+        resp.extend_from_slice(&buf[12..len]);
+        let cdn = push_record(&mut resp, &[0xc0, 12], 5, &compressed("cdn", 12));
+        let mut mx = vec![0, 5];
+        mx.extend(compressed("mx", cdn));
+        push_record(&mut resp, &(0xc000 | cdn as u16).to_be_bytes(), 15, &mx);
+        push_record(
+            &mut resp,
+            &(0xc000 | example as u16).to_be_bytes(),
+            6,
+            &soa(example),
+        );
+
+        let merged = split.merge(&resp).unwrap();
+        assert_eq!(&merged[4..12], &[0, 1, 0, 3, 0, 1, 0, 0]);
+        assert_eq!(&merged[12..12 + client_question.len()], client_question);
+        assert_eq!(
+            records(&merged),
+            [
+                "alias.test 5 target.example",
+                "target.example 5 cdn.target.example",
+                "cdn.target.example 15 5 mx.cdn.target.example",
+                "example 6 ns1.example hostmaster.example",
+            ]
+        );
+    }
+
+    #[test]
+    fn remap_name_rewrites_only_the_final_pointer() {
+        let mut buf = compressed("www", 12);
+        remap_name(&mut buf, 0, |offset| Some(offset + 100)).unwrap();
+        assert_eq!(buf, compressed("www", 112));
+
+        let mut plain = name("www.test");
+        remap_name(&mut plain, 0, |_| None).unwrap();
+        assert_eq!(plain, name("www.test"));
+
+        // pointers that cannot be remapped or would not fit
+        assert!(remap_name(&mut buf, 0, |_| None).is_none());
+        assert!(remap_name(&mut buf, 0, |_| Some(0x4000)).is_none());
+        assert!(remap_name(&mut buf[..4], 0, Some).is_none());
+    }
+}
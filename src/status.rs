@@ -0,0 +1,44 @@
+//! What the relay is doing right now, as JSON for the status endpoint.
+
+use std::{sync::Arc, time::Instant};
+
+use serde_json::json;
+
+use crate::{backend::Backend, cache::Cache, Config, MsgMap};
+
+pub struct Status {
+    started: Instant,
+    // rendered once, the config does not change at runtime
+    config: serde_json::Value,
+    hosts: Arc<dyn Backend>,
+    cache: Option<Arc<Cache>>,
+    msg_map: MsgMap,
+}
+
+impl Status {
+    pub fn new(
+        config: &Config,
+        hosts: Arc<dyn Backend>,
+        cache: Option<Arc<Cache>>,
+        msg_map: MsgMap,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            started: Instant::now(),
+            config: serde_json::to_value(config)?,
+            hosts,
+            cache,
+            msg_map,
+        })
+    }
+
+    pub fn render(&self) -> String {
+        json!({
+            "uptime_secs": self.started.elapsed().as_secs(),
+            "hosts_entries": self.hosts.entries(),
+            "cache_entries": self.cache.as_ref().map(|cache| cache.len()),
+            "in_flight": self.msg_map.lock().unwrap().len(),
+            "config": self.config,
+        })
+        .to_string()
+    }
+}
@@ -0,0 +1,438 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpSocket, TcpStream, UdpSocket,
+    },
+    sync::{mpsc, Notify, OnceCell},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{dnscrypt::DnsCryptUpstream, packet};
+
+// reconnecting is given up after this long, by then clients have moved on
+const RETRY_BUDGET: Duration = Duration::from_secs(10);
+// queries over tcp unanswered for this long are neither kept nor re-sent
+const INFLIGHT_TTL: Duration = Duration::from_secs(10);
+
+/// Exponential backoff with full jitter: the n-th retry waits a random time
+/// up to `base * 2^n`, but never more than `cap`.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub base: Duration,
+    pub cap: Duration,
+}
+
+impl Backoff {
+    fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.cap);
+        ceiling.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+/// Something that queries can be sent to and responses received from.
+///
+/// Queries and responses are raw DNS messages; matching them up is left to
+/// the caller (by message id), exactly like a plain UDP socket.
+pub trait Resolver {
+    async fn send(&self, buf: &[u8]) -> anyhow::Result<()>;
+    async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<usize>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Udp,
+    Tcp,
+    DnsCrypt,
+}
+
+impl FromStr for Transport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "udp" => Ok(Transport::Udp),
+            "tcp" => Ok(Transport::Tcp),
+            "dnscrypt" => Ok(Transport::DnsCrypt),
+            _ => Err(anyhow::anyhow!("unknown upstream transport: {}", s)),
+        }
+    }
+}
+
+pub enum Upstream {
+    Udp(UdpUpstream),
+    Tcp(TcpUpstream),
+    DnsCrypt(DnsCryptUpstream),
+}
+
+impl Upstream {
+    pub fn addr(&self) -> Arc<str> {
+        match self {
+            Upstream::Udp(u) => u.addr.clone(),
+            Upstream::Tcp(t) => t.inner.addr.clone(),
+            Upstream::DnsCrypt(d) => d.addr(),
+        }
+    }
+
+    /// The socket queries leave through, if there is a single one.
+    pub fn socket(&self) -> Option<&UdpSocket> {
+        match self {
+            Upstream::Udp(u) => u.sock.get(),
+            Upstream::Tcp(_) => None,
+            Upstream::DnsCrypt(d) => Some(d.socket()),
+        }
+    }
+
+    /// Binds a lazy socket, if not bound yet, for queries to be sent.
+    pub async fn ready(&self) -> anyhow::Result<()> {
+        match self {
+            Upstream::Udp(u) => u.sock().await.map(|_| ()),
+            Upstream::Tcp(_) | Upstream::DnsCrypt(_) => Ok(()),
+        }
+    }
+}
+
+impl Resolver for Upstream {
+    async fn send(&self, buf: &[u8]) -> anyhow::Result<()> {
+        match self {
+            Upstream::Udp(u) => u.send(buf).await,
+            Upstream::Tcp(t) => t.send(buf).await,
+            Upstream::DnsCrypt(d) => d.send(buf).await,
+        }
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        match self {
+            Upstream::Udp(u) => u.recv(buf).await,
+            Upstream::Tcp(t) => t.recv(buf).await,
+            Upstream::DnsCrypt(d) => d.recv(buf).await,
+        }
+    }
+}
+
+pub struct UdpUpstream {
+    sock: OnceCell<UdpSocket>,
+    local: String,
+    addr: Arc<str>,
+    // wakes the receiver up once a lazy socket is bound
+    bound: Notify,
+}
+
+impl UdpUpstream {
+    pub async fn bind(local: &str, upstream: &str) -> anyhow::Result<Self> {
+        let this = Self::lazy(local, upstream);
+        this.sock().await?;
+        Ok(this)
+    }
+
+    /// An upstream whose socket is only bound when the first query is sent,
+    /// so that a relay answering everything locally needs no network.
+    pub fn lazy(local: &str, upstream: &str) -> Self {
+        Self {
+            sock: OnceCell::new(),
+            local: local.to_owned(),
+            addr: upstream.into(),
+            bound: Notify::new(),
+        }
+    }
+
+    async fn sock(&self) -> anyhow::Result<&UdpSocket> {
+        if let Some(sock) = self.sock.get() {
+            return Ok(sock);
+        }
+        let sock = self
+            .sock
+            .get_or_try_init(|| async {
+                let sock = UdpSocket::bind(&self.local).await?;
+                info!("remote socket is listening on {}", self.local);
+                Ok::<_, anyhow::Error>(sock)
+            })
+            .await?;
+        self.bound.notify_waiters();
+        Ok(sock)
+    }
+}
+
+impl Resolver for UdpUpstream {
+    async fn send(&self, buf: &[u8]) -> anyhow::Result<()> {
+        self.sock().await?.send_to(buf, &*self.addr).await?;
+        Ok(())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let sock = loop {
+            let bound = self.bound.notified();
+            if let Some(sock) = self.sock.get() {
+                break sock;
+            }
+            bound.await;
+        };
+        let (len, _) = sock.recv_from(buf).await?;
+        Ok(len)
+    }
+}
+
+/// A small pool of persistent TCP connections to the upstream.
+///
+/// Queries are spread over the connections round-robin using the two-byte
+/// length prefix framing of RFC 1035 4.2.2. Every connection has a reader task
+/// pushing responses into a shared channel. When a connection drops, a
+/// reconnect task re-establishes it and sends the queries still in flight on
+/// it again, backing off between failed attempts. Queries unanswered for
+/// [`INFLIGHT_TTL`] are forgotten instead. Responses too long for the
+/// caller's buffer are truncated to whole records, with TC set.
+pub struct TcpUpstream {
+    inner: Arc<TcpInner>,
+    rx: tokio::sync::Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+}
+
+struct TcpInner {
+    addr: Arc<str>,
+    bind_addr: Option<SocketAddr>,
+    slots: Vec<tokio::sync::Mutex<Option<OwnedWriteHalf>>>,
+    next: AtomicUsize,
+    // kept until the response arrives, or for INFLIGHT_TTL, so it can be resent
+    inflight: Mutex<HashMap<u16, Inflight>>,
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    dropped: mpsc::UnboundedSender<usize>,
+    backoff: Backoff,
+}
+
+/// A query sent over tcp and not answered yet.
+struct Inflight {
+    slot: usize,
+    sent: Instant,
+    query: Vec<u8>,
+}
+
+impl TcpUpstream {
+    pub fn new(
+        upstream: &str,
+        pool_size: usize,
+        bind_addr: Option<SocketAddr>,
+        backoff: Backoff,
+    ) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (dropped, dropped_rx) = mpsc::unbounded_channel();
+
+        let inner = Arc::new(TcpInner {
+            addr: upstream.into(),
+            bind_addr,
+            slots: (0..pool_size.max(1))
+                .map(|_| tokio::sync::Mutex::new(None))
+                .collect(),
+            next: AtomicUsize::new(0),
+            inflight: Mutex::new(HashMap::new()),
+            tx,
+            dropped,
+            backoff,
+        });
+        tokio::spawn(TcpInner::reconnect_loop(inner.clone(), dropped_rx));
+
+        Self {
+            inner,
+            rx: tokio::sync::Mutex::new(rx),
+        }
+    }
+}
+
+impl Resolver for TcpUpstream {
+    async fn send(&self, buf: &[u8]) -> anyhow::Result<()> {
+        let slot = self.inner.next.fetch_add(1, Ordering::Relaxed) % self.inner.slots.len();
+        let id = u16::from_be_bytes([buf[0], buf[1]]);
+        {
+            let mut inflight = self.inner.inflight.lock().unwrap();
+            inflight.retain(|_, query| query.sent.elapsed() < INFLIGHT_TTL);
+            inflight.insert(
+                id,
+                Inflight {
+                    slot,
+                    sent: Instant::now(),
+                    query: buf.to_vec(),
+                },
+            );
+        }
+
+        TcpInner::write(&self.inner, slot, &[buf]).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let mut msg = self
+            .rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(anyhow::anyhow!("tcp upstream closed"))?;
+
+        if msg.len() > buf.len() {
+            debug!(
+                "response of {} bytes from {} exceeds {}, truncating",
+                msg.len(),
+                self.inner.addr,
+                buf.len()
+            );
+        }
+        let len = packet::truncate(&mut msg, buf.len());
+        buf[..len].copy_from_slice(&msg[..len]);
+        Ok(len)
+    }
+}
+
+impl TcpInner {
+    /// Writes the queries on the connection in `slot`, connecting first if
+    /// needed. A failed write is retried once on a fresh connection.
+    async fn write(this: &Arc<Self>, slot: usize, queries: &[&[u8]]) -> anyhow::Result<()> {
+        let mut conn = this.slots[slot].lock().await;
+
+        for attempt in 0..2 {
+            if conn.is_none() {
+                *conn = Some(Self::connect(this, slot).await?);
+            }
+
+            let mut result = Ok(());
+            for query in queries {
+                let mut framed = Vec::with_capacity(query.len() + 2);
+                framed.extend_from_slice(&(query.len() as u16).to_be_bytes());
+                framed.extend_from_slice(query);
+
+                result = conn.as_mut().unwrap().write_all(&framed).await;
+                if result.is_err() {
+                    break;
+                }
+            }
+
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt == 0 => {
+                    warn!("tcp connection #{} to {} broken: {}", slot, this.addr, e);
+                    *conn = None;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn connect(this: &Arc<Self>, slot: usize) -> anyhow::Result<OwnedWriteHalf> {
+        let stream = match this.bind_addr {
+            Some(bind_addr) => {
+                let target = tokio::net::lookup_host(&*this.addr)
+                    .await?
+                    .find(|addr| addr.is_ipv4() == bind_addr.is_ipv4())
+                    .ok_or(anyhow::anyhow!(
+                        "no address of {} to connect to from {}",
+                        this.addr,
+                        bind_addr
+                    ))?;
+                let sock = if bind_addr.is_ipv4() {
+                    TcpSocket::new_v4()?
+                } else {
+                    TcpSocket::new_v6()?
+                };
+                sock.bind(bind_addr)?;
+                sock.connect(target).await?
+            }
+            None => TcpStream::connect(&*this.addr).await?,
+        };
+        info!("tcp connection #{} to {} established", slot, this.addr);
+
+        let (reader, writer) = stream.into_split();
+        tokio::spawn(Self::read_loop(this.clone(), slot, reader));
+
+        Ok(writer)
+    }
+
+    async fn read_loop(this: Arc<Self>, slot: usize, mut reader: OwnedReadHalf) {
+        while let Ok(len) = reader.read_u16().await {
+            let len = len as usize;
+            let mut msg = vec![0u8; len];
+            if reader.read_exact(&mut msg).await.is_err() {
+                break;
+            }
+            if len < 2 {
+                continue;
+            }
+
+            let id = u16::from_be_bytes([msg[0], msg[1]]);
+            this.inflight.lock().unwrap().remove(&id);
+            if this.tx.send(msg).is_err() {
+                return;
+            }
+        }
+
+        debug!("tcp connection #{} to {} closed", slot, this.addr);
+        {
+            // the slot may already hold a newer connection made by a writer
+            let mut conn = this.slots[slot].lock().await;
+            if conn.as_ref().map(|w| w.local_addr().ok()) == Some(reader.local_addr().ok()) {
+                conn.take();
+            }
+        }
+        let _ = this.dropped.send(slot);
+    }
+
+    async fn reconnect_loop(this: Arc<Self>, mut dropped: mpsc::UnboundedReceiver<usize>) {
+        while let Some(slot) = dropped.recv().await {
+            let pending: Vec<Vec<u8>> = {
+                let mut inflight = this.inflight.lock().unwrap();
+                inflight.retain(|_, query| query.sent.elapsed() < INFLIGHT_TTL);
+                inflight
+                    .values()
+                    .filter(|query| query.slot == slot)
+                    .map(|query| query.query.clone())
+                    .collect()
+            };
+            if pending.is_empty() {
+                continue;
+            }
+
+            info!(
+                "re-sending {} in-flight quer(ies) on tcp connection #{}",
+                pending.len(),
+                slot
+            );
+            let pending: Vec<&[u8]> = pending.iter().map(|q| q.as_slice()).collect();
+            let started = Instant::now();
+            for attempt in 0.. {
+                let Err(e) = Self::write(&this, slot, &pending).await else {
+                    break;
+                };
+                let delay = this.backoff.delay(attempt);
+                if started.elapsed() + delay > RETRY_BUDGET {
+                    error!(
+                        "failed to reconnect to {}, giving up after {} attempt(s): {}",
+                        this.addr,
+                        attempt + 1,
+                        e
+                    );
+                    break;
+                }
+                warn!(
+                    "failed to reconnect to {}, retrying in {:?}: {}",
+                    this.addr, delay, e
+                );
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Split-horizon answers: a name resolving to different addresses depending
+//! on the network the query comes from.
+
+use std::{collections::HashMap, fs, net::IpAddr, str::FromStr};
+
+use tracing::info;
+
+/// A network in CIDR notation; a bare address is a single host.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub(crate) fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').unwrap_or((s, ""));
+        let addr: IpAddr = addr.parse()?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => max,
+            prefix => prefix.parse()?,
+        };
+        anyhow::ensure!(prefix <= max, "prefix too long in {}", s);
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// `<cidr> <name> <ip>` per line, e.g. `10.0.0.0/8 app.example.com 10.1.2.3`.
+/// Of the networks a client is in, the most specific one wins. Empty lines
+/// and `#` comments are skipped.
+#[derive(Debug, Default)]
+pub struct Views {
+    // by lowercased name, longest prefix first
+    rules: HashMap<String, Vec<(Cidr, IpAddr)>>,
+}
+
+impl Views {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read views {}: {}", path, e))?;
+
+        let mut views = Self::default();
+        let mut count = 0;
+        for line in contents.lines() {
+            let rule = line.split('#').next().unwrap_or_default().trim();
+            if rule.is_empty() {
+                continue;
+            }
+            let invalid = || anyhow::anyhow!("invalid views rule: {}", rule);
+            let [cidr, name, ip] = rule.split_whitespace().collect::<Vec<_>>()[..] else {
+                return Err(invalid());
+            };
+            let cidr: Cidr = cidr.parse().map_err(|_| invalid())?;
+            let ip: IpAddr = ip.parse().map_err(|_| invalid())?;
+
+            views
+                .rules
+                .entry(name.trim_end_matches('.').to_ascii_lowercase())
+                .or_default()
+                .push((cidr, ip));
+            count += 1;
+        }
+        for rules in views.rules.values_mut() {
+            rules.sort_by_key(|(cidr, _)| std::cmp::Reverse(cidr.prefix));
+        }
+
+        info!(
+            "loaded {} views rules for {} names from {}",
+            count,
+            views.rules.len(),
+            path
+        );
+        Ok(views)
+    }
+
+    /// The address `name` has for `client`, if a rule covers it.
+    pub fn addr(&self, name: &str, client: IpAddr) -> Option<IpAddr> {
+        // clients on a dual-stack socket show up as v4-mapped v6 addresses
+        let client = client.to_canonical();
+        self.rules
+            .get(&name.to_ascii_lowercase())?
+            .iter()
+            .find(|(cidr, _)| cidr.contains(client))
+            .map(|(_, ip)| *ip)
+    }
+}
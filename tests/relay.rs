@@ -0,0 +1,859 @@
+use std::{
+    fmt::Write,
+    net::UdpSocket as StdUdpSocket,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use mini_dns_relay::{Config, IdGenerator, Profile, RecordType, Relay};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    time::timeout,
+};
+
+fn free_addr() -> String {
+    let sock = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.local_addr().unwrap().to_string()
+}
+
+fn hosts_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "mini-dns-relay-{}-{}.txt",
+        name,
+        std::process::id()
+    ));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+/// Starts a relay with the given hosts file contents and returns its address.
+async fn spawn_relay(name: &str, hosts: &str, config: Config) -> String {
+    let local_addr = free_addr();
+    let config = Config {
+        local_addr: local_addr.clone(),
+        remote_addr: "127.0.0.1:0".to_owned(),
+        hosts_path: hosts_file(name, hosts).to_string_lossy().into_owned(),
+        ..config
+    };
+
+    tokio::spawn(mini_dns_relay::run(config));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    local_addr
+}
+
+fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in name.split('.') {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf.extend_from_slice(&qtype.to_be_bytes());
+    buf.extend_from_slice(&1u16.to_be_bytes());
+    buf
+}
+
+async fn exchange(addr: &str, query: &[u8]) -> Vec<u8> {
+    let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    sock.send_to(query, addr).await.unwrap();
+
+    let mut buf = [0u8; 4096];
+    let len = timeout(Duration::from_secs(2), sock.recv(&mut buf))
+        .await
+        .expect("no response from relay")
+        .unwrap();
+    buf[..len].to_vec()
+}
+
+/// Sends a query through a relay whose upstream answers by echoing the query
+/// with QR set and `flags` or'ed into the fourth header byte.
+async fn forward_once(name: &str, config: Config, flags: u8) -> Vec<u8> {
+    let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let config = Config {
+        upstream_addr: upstream.local_addr().unwrap().to_string(),
+        ..config
+    };
+    let addr = spawn_relay(name, "", config).await;
+
+    let client =
+        tokio::spawn(async move { exchange(&addr, &query(0x1234, "remote.test", 1)).await });
+
+    let mut buf = [0u8; 512];
+    let (len, from) = timeout(Duration::from_secs(2), upstream.recv_from(&mut buf))
+        .await
+        .expect("query not forwarded")
+        .unwrap();
+    buf[2] |= 0b1000_0000;
+    buf[3] |= flags;
+    upstream.send_to(&buf[..len], from).await.unwrap();
+
+    client.await.unwrap()
+}
+
+/// Answers the next query `upstream` receives by echoing it with QR set and
+/// `rcode`, and returns the query.
+async fn answer_next(upstream: &UdpSocket, rcode: u8) -> Vec<u8> {
+    let mut buf = [0u8; 512];
+    let (len, from) = timeout(Duration::from_secs(2), upstream.recv_from(&mut buf))
+        .await
+        .expect("query not forwarded")
+        .unwrap();
+    let query = buf[..len].to_vec();
+    buf[2] |= 0b1000_0000;
+    buf[3] = (buf[3] & 0b1111_0000) | rcode;
+    upstream.send_to(&buf[..len], from).await.unwrap();
+    query
+}
+
+/// Answers the next query `upstream` receives like a server handing out
+/// cookies: echoes it with QR set and `rcode`, the relay's client cookie
+/// followed by `server_cookie`. Returns the query, which must have an OPT
+/// record with only a cookie, as the relay adds to queries without one.
+async fn answer_with_cookie(upstream: &UdpSocket, rcode: u16, server_cookie: [u8; 8]) -> Vec<u8> {
+    let mut buf = [0u8; 512];
+    let (len, from) = timeout(Duration::from_secs(2), upstream.recv_from(&mut buf))
+        .await
+        .expect("query not forwarded")
+        .unwrap();
+    let query = buf[..len].to_vec();
+
+    // the cookie option, the client cookie alone or with a server cookie
+    let option = match &query[len - 12..len - 8] {
+        [0, 10, 0, 8] => len - 12,
+        _ => len - 20,
+    };
+    assert_eq!(&query[option..option + 2], &[0, 10], "a cookie");
+    assert_eq!(
+        u16::from_be_bytes([query[option - 2], query[option - 1]]) as usize,
+        len - option,
+        "only a cookie in the OPT record"
+    );
+    let mut resp = query[..option + 4 + 8].to_vec();
+    resp.extend_from_slice(&server_cookie);
+    resp[option - 2..option].copy_from_slice(&20u16.to_be_bytes());
+    resp[option + 2..option + 4].copy_from_slice(&16u16.to_be_bytes());
+    resp[2] |= 0b1000_0000;
+    resp[3] = (resp[3] & 0b1111_0000) | (rcode as u8 & 0b0000_1111);
+    // the upper bits of the rcode in the TTL of the OPT record
+    resp[option - 6] = (rcode >> 4) as u8;
+    upstream.send_to(&resp, from).await.unwrap();
+    query
+}
+
+/// The server cookie in a query from `answer_with_cookie`, if it has one.
+fn server_cookie(query: &[u8]) -> Option<&[u8]> {
+    let len = query.len();
+    (query[len - 20..len - 16] == [0, 10, 0, 16]).then(|| &query[len - 8..])
+}
+
+/// Serves hosts over http, `first` for the first request and `rest` for all
+/// later ones, and returns their url.
+async fn serve_hosts(first: String, rest: String) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hosts", listener.local_addr().unwrap());
+
+    tokio::spawn(async move {
+        let mut body = first;
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let len = stream.read(&mut buf).await.unwrap();
+                if len == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..len]);
+            }
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            let _ = stream.write_all(head.as_bytes()).await;
+            let _ = stream.write_all(body.as_bytes()).await;
+            body = rest.clone();
+        }
+    });
+
+    url
+}
+
+#[tokio::test]
+async fn local_answer_sets_ra() {
+    let addr = spawn_relay("ra", "10.0.0.1 ra.test\n", Config::default()).await;
+
+    let resp = exchange(&addr, &query(0x1234, "ra.test", 1)).await;
+
+    assert_eq!(&resp[0..2], &[0x12, 0x34]);
+    assert_eq!(resp[2] >> 7, 1, "QR should be set");
+    assert_eq!(resp[3] >> 7, 1, "RA should be set");
+    assert_eq!(u16::from_be_bytes([resp[6], resp[7]]), 1);
+    assert_eq!(&resp[resp.len() - 4..], &[10, 0, 0, 1]);
+}
+
+#[tokio::test]
+async fn local_answer_clears_ra_when_disabled() {
+    let config = Config {
+        recursion_available: false,
+        ..Config::default()
+    };
+    let addr = spawn_relay("no-ra", "10.0.0.1 ra.test\n", config).await;
+
+    let resp = exchange(&addr, &query(0x1234, "ra.test", 1)).await;
+
+    assert_eq!(resp[3] >> 7, 0, "RA should be clear");
+}
+
+#[tokio::test]
+async fn forwarded_query_uses_generated_id() {
+    let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let config = Config {
+        upstream_addr: upstream.local_addr().unwrap().to_string(),
+        id_generator: IdGenerator::sequential(0xbeef),
+        ..Config::default()
+    };
+    let addr = spawn_relay("id", "", config).await;
+
+    let client = tokio::spawn({
+        let addr = addr.clone();
+        async move { exchange(&addr, &query(0x1234, "remote.test", 1)).await }
+    });
+
+    let mut buf = [0u8; 512];
+    let (len, from) = timeout(Duration::from_secs(2), upstream.recv_from(&mut buf))
+        .await
+        .expect("query not forwarded")
+        .unwrap();
+    assert_eq!(&buf[0..2], &[0xbe, 0xef]);
+
+    buf[2] |= 0b1000_0000;
+    upstream.send_to(&buf[..len], from).await.unwrap();
+
+    let resp = client.await.unwrap();
+    assert_eq!(&resp[0..2], &[0x12, 0x34]);
+}
+
+#[tokio::test]
+async fn identical_queries_in_flight_are_coalesced() {
+    let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let config = Config {
+        upstream_addr: upstream.local_addr().unwrap().to_string(),
+        coalesce: true,
+        ..Config::default()
+    };
+    let addr = spawn_relay("coalesce", "", config).await;
+
+    let first = tokio::spawn({
+        let addr = addr.clone();
+        async move { exchange(&addr, &query(0x1111, "remote.test", 1)).await }
+    });
+    let mut buf = [0u8; 512];
+    let (len, from) = timeout(Duration::from_secs(2), upstream.recv_from(&mut buf))
+        .await
+        .expect("query not forwarded")
+        .unwrap();
+
+    let second = tokio::spawn({
+        let addr = addr.clone();
+        async move { exchange(&addr, &query(0x2222, "Remote.TEST", 1)).await }
+    });
+    // the same question with DO set needs its own upstream query
+    let mut edns = query(0x3333, "remote.test", 1);
+    edns[11] = 1;
+    edns.extend_from_slice(&[0x00, 0x00, 0x29, 0x10, 0x00, 0, 0, 0x80, 0, 0x00, 0x00]);
+    let dnssec = tokio::spawn({
+        let addr = addr.clone();
+        async move { exchange(&addr, &edns).await }
+    });
+
+    let mut other = [0u8; 512];
+    let (other_len, _) = timeout(Duration::from_secs(2), upstream.recv_from(&mut other))
+        .await
+        .expect("query with DO not forwarded")
+        .unwrap();
+    assert_eq!(other[11], 1, "the forwarded query is the one with an OPT");
+    assert!(
+        timeout(
+            Duration::from_millis(200),
+            upstream.recv_from(&mut [0u8; 512])
+        )
+        .await
+        .is_err(),
+        "the identical query was forwarded again"
+    );
+
+    buf[2] |= 0b1000_0000;
+    upstream.send_to(&buf[..len], from).await.unwrap();
+    other[2] |= 0b1000_0000;
+    upstream.send_to(&other[..other_len], from).await.unwrap();
+
+    let first = first.await.unwrap();
+    assert_eq!(&first[0..2], &[0x11, 0x11]);
+    assert_eq!(&first[12..], &query(0x1111, "remote.test", 1)[12..]);
+    let second = second.await.unwrap();
+    assert_eq!(&second[0..2], &[0x22, 0x22]);
+    assert_eq!(&second[12..], &query(0x2222, "Remote.TEST", 1)[12..]);
+    let dnssec = dnssec.await.unwrap();
+    assert_eq!(&dnssec[0..2], &[0x33, 0x33]);
+}
+
+#[tokio::test]
+async fn blocked_response_is_header_and_question_only() {
+    let addr = spawn_relay("len", "0.0.0.0 blocked.test\n", Config::default()).await;
+
+    let plain = query(0x1234, "blocked.test", 1);
+    // the same query with an EDNS OPT record in the additional section
+    let mut edns = plain.clone();
+    edns[11] = 1;
+    edns.extend_from_slice(&[0x00, 0x00, 0x29, 0x10, 0x00, 0, 0, 0, 0, 0x00, 0x00]);
+
+    let resp = exchange(&addr, &plain).await;
+    assert_eq!(resp.len(), plain.len(), "header + question bytes");
+    assert_eq!(resp[3] & 0x0f, 3, "NXDOMAIN");
+    assert_eq!(&resp[6..12], &[0, 0, 0, 0, 0, 0]);
+
+    // only the relay's own OPT record follows the question
+    let resp = exchange(&addr, &edns).await;
+    assert_eq!(
+        resp.len(),
+        plain.len() + 11,
+        "header + question + OPT bytes"
+    );
+    assert_eq!(resp[3] & 0x0f, 3, "NXDOMAIN");
+    assert_eq!(&resp[6..12], &[0, 0, 0, 0, 0, 1]);
+    assert_eq!(&resp[plain.len()..plain.len() + 3], &[0x00, 0x00, 0x29]);
+}
+
+#[tokio::test]
+async fn blocked_name_gets_nxdomain() {
+    let addr = spawn_relay("nxdomain", "0.0.0.0 blocked.example\n", Config::default()).await;
+
+    let query = query(0xabcd, "blocked.example", 1);
+    let resp = exchange(&addr, &query).await;
+
+    assert_eq!(&resp[0..2], &[0xab, 0xcd]);
+    assert_eq!(resp[2] >> 7, 1, "QR should be set");
+    assert_eq!(resp[2] & 1, 1, "RD should be echoed");
+    assert_eq!(resp[3] & 0x0f, 3, "NXDOMAIN");
+    assert_eq!(u16::from_be_bytes([resp[4], resp[5]]), 1, "one question");
+    assert_eq!(u16::from_be_bytes([resp[6], resp[7]]), 0, "no answers");
+    assert_eq!(&resp[12..], &query[12..], "question echoed");
+}
+
+#[tokio::test]
+async fn blocked_names_get_the_reason_of_what_blocked_them() {
+    let config = Config {
+        blocked_reason: Some("blocked".to_owned()),
+        hosts_blocked_reason: Some("blocked by the ad list".to_owned()),
+        name_patterns: vec!["^tracker\\.test$=block".parse().unwrap()],
+        ..Config::default()
+    };
+    let addr = spawn_relay("reasons", "0.0.0.0 ads.test\n", config).await;
+
+    let resp = exchange(&addr, &query(0x1234, "ads.test", 1)).await;
+    assert_eq!(resp[3] & 0x0f, 3, "NXDOMAIN");
+    assert_eq!(&resp[10..12], &[0, 1], "one additional record");
+    assert!(resp.ends_with(b"\x16blocked by the ad list"));
+
+    // patterns have no reason of their own, so they get the relay's
+    let resp = exchange(&addr, &query(0x5678, "tracker.test", 1)).await;
+    assert_eq!(resp[3] & 0x0f, 3, "NXDOMAIN");
+    assert!(resp.ends_with(b"\x07blocked"));
+}
+
+#[tokio::test]
+async fn local_answer_to_edns_query_has_opt() {
+    let addr = spawn_relay("edns", "10.0.0.1 edns.test\n", Config::default()).await;
+
+    let mut edns = query(0x1234, "edns.test", 1);
+    let question_end = edns.len();
+    edns[11] = 1;
+    edns.extend_from_slice(&[0x00, 0x00, 0x29, 0x10, 0x00, 0, 0, 0, 0, 0x00, 0x00]);
+
+    let resp = exchange(&addr, &edns).await;
+
+    assert_eq!(u16::from_be_bytes([resp[6], resp[7]]), 1, "one answer");
+    assert_eq!(
+        u16::from_be_bytes([resp[10], resp[11]]),
+        1,
+        "one additional"
+    );
+    // the answer follows the question directly, then the OPT record
+    let answer = &resp[question_end..question_end + 16];
+    assert_eq!(&answer[12..], &[10, 0, 0, 1]);
+    let opt = &resp[question_end + 16..];
+    assert_eq!(opt.len(), 11);
+    assert_eq!(&opt[..3], &[0x00, 0x00, 0x29], "root name, type OPT");
+    assert_eq!(opt[6], 0, "EDNS version 0");
+}
+
+#[tokio::test]
+async fn unsupported_edns_version_gets_badvers() {
+    let addr = spawn_relay("badvers", "10.0.0.1 badvers.test\n", Config::default()).await;
+
+    let mut edns = query(0x1234, "badvers.test", 1);
+    let question_end = edns.len();
+    edns[11] = 1;
+    // EDNS version 1
+    edns.extend_from_slice(&[0x00, 0x00, 0x29, 0x10, 0x00, 0, 1, 0, 0, 0x00, 0x00]);
+
+    let resp = exchange(&addr, &edns).await;
+
+    assert_eq!(u16::from_be_bytes([resp[6], resp[7]]), 0, "no answers");
+    assert_eq!(resp[3] & 0b0000_1111, 0, "low 4 bits of BADVERS");
+    let opt = &resp[question_end..];
+    assert_eq!(opt.len(), 11);
+    assert_eq!(opt[5], 1, "upper 8 bits of BADVERS");
+    assert_eq!(opt[6], 0, "EDNS version 0");
+}
+
+#[tokio::test]
+async fn runt_upstream_datagram_is_dropped() {
+    let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let config = Config {
+        upstream_addr: upstream.local_addr().unwrap().to_string(),
+        ..Config::default()
+    };
+    let addr = spawn_relay("runt", "10.0.0.1 local.test\n", config).await;
+
+    for id in [0x1234, 0x5678] {
+        let relay = addr.clone();
+        let client =
+            tokio::spawn(async move { exchange(&relay, &query(id, "remote.test", 1)).await });
+
+        let mut buf = [0u8; 512];
+        let (len, from) = timeout(Duration::from_secs(2), upstream.recv_from(&mut buf))
+            .await
+            .expect("query not forwarded")
+            .unwrap();
+        upstream.send_to(&[0xab, 0xcd, 0x81], from).await.unwrap();
+        buf[2] |= 0b1000_0000;
+        upstream.send_to(&buf[..len], from).await.unwrap();
+
+        let resp = client.await.unwrap();
+        assert_eq!(&resp[0..2], &id.to_be_bytes());
+    }
+}
+
+#[tokio::test]
+async fn servfail_is_retried_on_the_fallback() {
+    let primary = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let fallback = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let config = Config {
+        upstream_addr: primary.local_addr().unwrap().to_string(),
+        fallback_upstreams: vec![fallback.local_addr().unwrap().to_string()],
+        retry_rcodes: vec![2],
+        ..Config::default()
+    };
+    let addr = spawn_relay("fallback", "", config).await;
+
+    let client =
+        tokio::spawn(async move { exchange(&addr, &query(0x1234, "remote.test", 1)).await });
+
+    let first = answer_next(&primary, 2).await;
+    let second = answer_next(&fallback, 0).await;
+    assert_eq!(first, second, "the same query is sent to the fallback");
+
+    let resp = client.await.unwrap();
+    assert_eq!(&resp[0..2], &[0x12, 0x34]);
+    assert_eq!(resp[3] & 0b0000_1111, 0, "the fallback's NOERROR");
+}
+
+#[tokio::test]
+async fn retries_skip_upstreams_already_asked() {
+    let primary = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let fallbacks = [
+        UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+    ];
+    let fallback_addrs: Vec<String> = fallbacks
+        .iter()
+        .map(|sock| sock.local_addr().unwrap().to_string())
+        .collect();
+    let config = Config {
+        upstream_addr: primary.local_addr().unwrap().to_string(),
+        // untagged queries all go to the first fallback
+        upstream_weights: vec![
+            (primary.local_addr().unwrap().to_string(), 0),
+            (fallback_addrs[0].clone(), 1),
+        ],
+        fallback_upstreams: fallback_addrs,
+        retry_rcodes: vec![2],
+        ..Config::default()
+    };
+    let addr = spawn_relay("retries", "", config).await;
+
+    let client =
+        tokio::spawn(async move { exchange(&addr, &query(0x1234, "remote.test", 1)).await });
+
+    answer_next(&fallbacks[0], 2).await;
+    answer_next(&primary, 2).await;
+    answer_next(&fallbacks[1], 0).await;
+
+    let resp = client.await.unwrap();
+    assert_eq!(&resp[0..2], &[0x12, 0x34]);
+    assert_eq!(resp[3] & 0b0000_1111, 0);
+    assert!(
+        timeout(
+            Duration::from_millis(200),
+            fallbacks[0].recv_from(&mut [0u8; 512])
+        )
+        .await
+        .is_err(),
+        "the first fallback was asked twice"
+    );
+}
+
+#[tokio::test]
+async fn cookies_do_not_leave_an_opt_the_client_did_not_send() {
+    let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let config = Config {
+        upstream_addr: upstream.local_addr().unwrap().to_string(),
+        upstream_cookies: true,
+        ..Config::default()
+    };
+    let addr = spawn_relay("cookie-opt", "", config).await;
+
+    let client =
+        tokio::spawn(async move { exchange(&addr, &query(0x1234, "remote.test", 1)).await });
+
+    let forwarded = answer_with_cookie(&upstream, 0, [9; 8]).await;
+    assert_eq!(&forwarded[10..12], &[0, 1], "an OPT record for the cookie");
+
+    let resp = client.await.unwrap();
+    assert_eq!(&resp[0..2], &[0x12, 0x34]);
+    assert_eq!(&resp[10..12], &[0, 0], "no additional records");
+    assert_eq!(resp.len(), query(0x1234, "remote.test", 1).len());
+}
+
+#[tokio::test]
+async fn badcookie_is_answered_by_sending_the_query_again() {
+    let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let config = Config {
+        upstream_addr: upstream.local_addr().unwrap().to_string(),
+        upstream_cookies: true,
+        ..Config::default()
+    };
+    let addr = spawn_relay("badcookie", "", config).await;
+
+    let client = tokio::spawn({
+        let addr = addr.clone();
+        async move { exchange(&addr, &query(0x1234, "remote.test", 1)).await }
+    });
+    let first = answer_with_cookie(&upstream, 23, [9; 8]).await;
+    assert_eq!(server_cookie(&first), None);
+    let again = answer_with_cookie(&upstream, 0, [9; 8]).await;
+    assert_eq!(server_cookie(&again), Some(&[9; 8][..]));
+    assert_eq!(&again[..2], &first[..2], "the same query, still in flight");
+
+    let resp = client.await.unwrap();
+    assert_eq!(&resp[0..2], &[0x12, 0x34]);
+    assert_eq!(resp[3] & 0b0000_1111, 0, "NOERROR");
+    assert_eq!(&resp[10..12], &[0, 0], "no OPT record");
+
+    // a second BADCOOKIE in a row is not tried again
+    let client =
+        tokio::spawn(async move { exchange(&addr, &query(0x5678, "remote.test", 1)).await });
+    answer_with_cookie(&upstream, 23, [7; 8]).await;
+    answer_with_cookie(&upstream, 23, [8; 8]).await;
+
+    let resp = client.await.unwrap();
+    assert_eq!(&resp[0..2], &[0x56, 0x78]);
+    assert_eq!(resp[3] & 0b0000_1111, 2, "SERVFAIL");
+    assert_eq!(&resp[10..12], &[0, 0], "no OPT record");
+    assert!(
+        timeout(
+            Duration::from_millis(200),
+            upstream.recv_from(&mut [0u8; 512])
+        )
+        .await
+        .is_err(),
+        "the query was sent a third time"
+    );
+}
+
+#[tokio::test]
+async fn profiles_cannot_reach_the_main_listeners_upstreams() {
+    let main = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let named = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let filtered = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let profile_addr = free_addr();
+    let config = Config {
+        upstream_addr: main.local_addr().unwrap().to_string(),
+        named_upstreams: vec![("open".to_owned(), named.local_addr().unwrap().to_string())],
+        fallback_upstreams: vec![named.local_addr().unwrap().to_string()],
+        retry_rcodes: vec![2],
+        upstream_weights: vec![(named.local_addr().unwrap().to_string(), 100)],
+        profiles: vec![Profile {
+            name: "kids".to_owned(),
+            local_addr: profile_addr.clone(),
+            remote_addr: "127.0.0.1:0".to_owned(),
+            upstream_addr: Some(filtered.local_addr().unwrap().to_string()),
+            hosts_path: None,
+            blocked_reason: None,
+            hosts_blocked_reason: None,
+        }],
+        ..Config::default()
+    };
+    spawn_relay("profile-upstreams", "", config).await;
+
+    for (id, name) in [(0x1234, "remote.test.via-open"), (0x5678, "remote.test")] {
+        let relay = profile_addr.clone();
+        let client = tokio::spawn(async move { exchange(&relay, &query(id, name, 1)).await });
+
+        // a SERVFAIL is not retried on a fallback either
+        let forwarded = answer_next(&filtered, 2).await;
+        assert_eq!(&forwarded[12..], &query(id, name, 1)[12..], "tag kept");
+        let resp = client.await.unwrap();
+        assert_eq!(&resp[0..2], &id.to_be_bytes());
+        assert_eq!(resp[3] & 0b0000_1111, 2);
+    }
+    assert!(
+        timeout(Duration::from_millis(200), named.recv_from(&mut [0u8; 512]))
+            .await
+            .is_err(),
+        "a profile's query reached a main listener's upstream"
+    );
+}
+
+#[tokio::test]
+async fn lookup_follows_a_local_alias_upstream() {
+    let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let config = Config {
+        upstream_addr: upstream.local_addr().unwrap().to_string(),
+        remote_addr: "127.0.0.1:0".to_owned(),
+        hosts_path: hosts_file("lookup-alias", "CNAME shop.lan shop.example.com\n")
+            .to_string_lossy()
+            .into_owned(),
+        ..Config::default()
+    };
+    let relay = Relay::new(&config).await.unwrap();
+
+    let server = tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        let (len, from) = timeout(Duration::from_secs(2), upstream.recv_from(&mut buf))
+            .await
+            .expect("target not asked upstream")
+            .unwrap();
+        let mut resp = buf[..len].to_vec();
+        resp[2] |= 0b1000_0000;
+        resp[7] = 1;
+        resp.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 7]);
+        upstream.send_to(&resp, from).await.unwrap();
+        buf[..len].to_vec()
+    });
+
+    let addrs = relay.lookup("shop.lan", RecordType::A).await.unwrap();
+    assert_eq!(addrs, ["192.0.2.7".parse::<std::net::IpAddr>().unwrap()]);
+    let asked = server.await.unwrap();
+    assert_eq!(&asked[12..], &query(0, "shop.example.com", 1)[12..]);
+}
+
+#[tokio::test]
+async fn startup_check_needs_one_upstream_to_answer() {
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let fallback = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let config = Config {
+        upstream_addr: silent.local_addr().unwrap().to_string(),
+        fallback_upstreams: vec![fallback.local_addr().unwrap().to_string()],
+        retry_rcodes: vec![2],
+        startup_check: true,
+        ..Config::default()
+    };
+    let addr = spawn_relay("startup-check", "10.0.0.1 local.test\n", config).await;
+
+    let canary = answer_next(&fallback, 0).await;
+    assert_eq!(u16::from_be_bytes([canary[4], canary[5]]), 1);
+    // the silent upstream is given up on after its canary times out
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let resp = exchange(&addr, &query(0x1234, "local.test", 1)).await;
+    assert_eq!(&resp[resp.len() - 4..], &[10, 0, 0, 1]);
+}
+
+#[tokio::test]
+async fn startup_check_fails_when_no_upstream_answers() {
+    let silent = [
+        UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+    ];
+    let config = Config {
+        local_addr: free_addr(),
+        remote_addr: "127.0.0.1:0".to_owned(),
+        hosts_path: hosts_file("startup-fail", "")
+            .to_string_lossy()
+            .into_owned(),
+        upstream_addr: silent[0].local_addr().unwrap().to_string(),
+        fallback_upstreams: vec![silent[1].local_addr().unwrap().to_string()],
+        retry_rcodes: vec![2],
+        startup_check: true,
+        ..Config::default()
+    };
+
+    let result = timeout(Duration::from_secs(10), mini_dns_relay::run(config))
+        .await
+        .expect("the startup check did not give up");
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn json_addresses_follow_the_hosts_file() {
+    let json = hosts_file(
+        "json-records",
+        r#"[{"name": "both.test", "type": "A", "data": "10.0.0.2"},
+            {"name": "blocked.test", "type": "A", "data": "0.0.0.0"},
+            {"name": "json.test", "type": "A", "ttl": 300, "data": "10.0.0.3"}]"#,
+    );
+    let config = Config {
+        json_records_path: Some(json.to_string_lossy().into_owned()),
+        ..Config::default()
+    };
+    let addr = spawn_relay("json", "10.0.0.1 both.test\n", config).await;
+
+    let both = query(0x1234, "both.test", 1);
+    let resp = exchange(&addr, &both).await;
+    assert_eq!(u16::from_be_bytes([resp[6], resp[7]]), 1);
+    assert_eq!(
+        &resp[resp.len() - 4..],
+        &[10, 0, 0, 1],
+        "the hosts file wins"
+    );
+
+    let resp = exchange(&addr, &query(0x1235, "blocked.test", 1)).await;
+    assert_eq!(resp[3] & 0b0000_1111, 3, "0.0.0.0 blocks");
+
+    let json = query(0x1236, "json.test", 1);
+    let resp = exchange(&addr, &json).await;
+    let answer = &resp[json.len()..];
+    assert_eq!(&answer[6..10], &300u32.to_be_bytes());
+    assert_eq!(&answer[12..], &[10, 0, 0, 3]);
+}
+
+#[tokio::test]
+async fn localhost_is_never_forwarded() {
+    let addr = spawn_relay("localhost", "10.0.0.1 local.test\n", Config::default()).await;
+
+    let resp = exchange(&addr, &query(0x1234, "mail.localhost", 15)).await;
+    assert_eq!(resp[3] & 0b0000_1111, 0);
+    assert_eq!(u16::from_be_bytes([resp[6], resp[7]]), 0, "NODATA for MX");
+
+    let ptr = query(0x1235, "1.0.0.127.in-addr.arpa", 12);
+    let resp = exchange(&addr, &ptr).await;
+    assert_eq!(u16::from_be_bytes([resp[6], resp[7]]), 1);
+    assert_eq!(&resp[ptr.len() + 12..], b"\x09localhost\x00");
+}
+
+#[tokio::test]
+async fn local_answers_are_capped_with_tc() {
+    let json = hosts_file(
+        "capped-records",
+        r#"[{"name": "many.test", "type": "A", "data": "10.0.0.1"},
+            {"name": "many.test", "type": "A", "data": "10.0.0.2"},
+            {"name": "many.test", "type": "A", "data": "10.0.0.3"}]"#,
+    );
+    let config = Config {
+        json_records_path: Some(json.to_string_lossy().into_owned()),
+        max_answer_records: Some(2),
+        ..Config::default()
+    };
+    let addr = spawn_relay("capped-local", "10.0.0.9 local.test\n", config).await;
+
+    let resp = exchange(&addr, &query(0x1234, "many.test", 1)).await;
+    assert_eq!(u16::from_be_bytes([resp[6], resp[7]]), 2);
+    assert_eq!(resp[2] & 0b0000_0010, 0b0000_0010, "TC should be set");
+}
+
+#[tokio::test]
+async fn forwarded_answers_are_capped_with_tc() {
+    let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let config = Config {
+        upstream_addr: upstream.local_addr().unwrap().to_string(),
+        max_answer_records: Some(2),
+        ..Config::default()
+    };
+    let addr = spawn_relay("capped-forward", "10.0.0.9 local.test\n", config).await;
+
+    let client =
+        tokio::spawn(async move { exchange(&addr, &query(0x1234, "remote.test", 1)).await });
+
+    let mut buf = [0u8; 512];
+    let (len, from) = timeout(Duration::from_secs(2), upstream.recv_from(&mut buf))
+        .await
+        .expect("query not forwarded")
+        .unwrap();
+    let mut resp = buf[..len].to_vec();
+    resp[2] |= 0b1000_0000;
+    resp[6..8].copy_from_slice(&5u16.to_be_bytes());
+    resp[10..12].fill(0);
+    let question_end = 12 + "remote.test".len() + 2 + 4;
+    resp.truncate(question_end);
+    for i in 1..=5 {
+        resp.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, i]);
+    }
+    upstream.send_to(&resp, from).await.unwrap();
+
+    let resp = client.await.unwrap();
+    assert_eq!(u16::from_be_bytes([resp[6], resp[7]]), 2);
+    assert_eq!(resp[2] & 0b0000_0010, 0b0000_0010, "TC should be set");
+}
+
+#[tokio::test]
+async fn upstream_ad_bit_is_passed_through() {
+    let resp = forward_once("ad", Config::default(), 0b0010_0000).await;
+
+    assert_eq!(&resp[0..2], &[0x12, 0x34]);
+    assert_eq!((resp[3] >> 5) & 1, 1, "AD should survive");
+}
+
+#[tokio::test]
+async fn upstream_ad_bit_is_cleared_when_configured() {
+    let config = Config {
+        clear_ad: true,
+        ..Config::default()
+    };
+    let resp = forward_once("no-ad", config, 0b0010_0000).await;
+
+    assert_eq!((resp[3] >> 5) & 1, 0, "AD should be clear");
+}
+
+#[tokio::test]
+async fn queries_are_answered_during_a_slow_hosts_refresh() {
+    // long enough a list to take a while to parse
+    let mut long = String::new();
+    for i in 0..300_000u32 {
+        let _ = writeln!(long, "10.1.{}.{} host{}.test", i / 256 % 256, i % 256, i);
+    }
+    long.push_str("10.0.0.2 slow.test\n");
+    let url = serve_hosts("10.0.0.1 slow.test\n".to_owned(), long).await;
+
+    let addr = free_addr();
+    let config = Config {
+        local_addr: addr.clone(),
+        remote_addr: "127.0.0.1:0".to_owned(),
+        hosts_path: url,
+        hosts_refresh_interval: Some(1),
+        ..Config::default()
+    };
+    tokio::spawn(mini_dns_relay::run(config));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let sent = Instant::now();
+        let resp = exchange(&addr, &query(0x1234, "slow.test", 1)).await;
+        assert!(
+            sent.elapsed() < Duration::from_millis(500),
+            "query held up for {:?}",
+            sent.elapsed()
+        );
+        if resp[resp.len() - 4..] == [10, 0, 0, 2] {
+            break;
+        }
+        assert_eq!(&resp[resp.len() - 4..], &[10, 0, 0, 1]);
+        assert!(Instant::now() < deadline, "hosts never refreshed");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}